@@ -0,0 +1,115 @@
+use futures::channel::oneshot;
+use r53::{Message, MessageRender, Name, RRType};
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use vanguard2::config::VanguardConfig;
+use vanguard2::resolver::Resolver;
+use vanguard2::server::Server;
+
+//run with `cargo bench --bench udp_throughput`, every case answers
+//queries to a local zone from loopback clients for a fixed duration
+const BENCH_DURATION: Duration = Duration::from_secs(5);
+const CLIENT_WINDOW: usize = 64;
+const BASE_PORT: u16 = 53530;
+
+fn server_config(port: u16, workers: usize, batch_size: usize) -> VanguardConfig {
+    let config = format!(
+        r#"
+server:
+  listeners:
+  - address: 127.0.0.1:{}
+    protocols: [udp]
+  udp:
+    workers: {}
+    batch_size: {}
+  limit:
+    max_inflight: 1000000
+    max_inflight_per_prefix: 1000000
+auth:
+  zones:
+  - name: example.org
+    file_path: testdata/example.org.zone
+"#,
+        port, workers, batch_size
+    );
+    serde_yaml::from_str(&config).unwrap()
+}
+
+fn query_wire() -> Vec<u8> {
+    let query = Message::with_query(Name::new("example.org.").unwrap(), RRType::A);
+    let mut render = MessageRender::new();
+    query.to_wire(&mut render);
+    render.take_data()
+}
+
+//keep CLIENT_WINDOW queries in flight, lost ones are refilled
+//after read timeout
+fn run_client(server: SocketAddr, query: &[u8], deadline: Instant) -> u64 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let mut buf = vec![0; 4096];
+    let mut answered = 0;
+    while Instant::now() < deadline {
+        for _ in 0..CLIENT_WINDOW {
+            let _ = socket.send(query);
+        }
+        while socket.recv(&mut buf).is_ok() {
+            answered += 1;
+            if Instant::now() >= deadline {
+                break;
+            }
+            let _ = socket.send(query);
+        }
+    }
+    answered
+}
+
+fn bench(port: u16, workers: usize, batch_size: usize) {
+    let config = server_config(port, workers, batch_size);
+    let addr: SocketAddr = config.server.listeners[0].address.parse().unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server_thread = thread::spawn(move || {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let resolver = Resolver::new(&config).unwrap();
+            let server = Server::new(&config.server);
+            let shutdown = async move {
+                let _ = stopped.await;
+            };
+            server.run(resolver, shutdown).await.unwrap();
+        });
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let query = query_wire();
+    let deadline = Instant::now() + BENCH_DURATION;
+    let clients: Vec<_> = (0..num_cpus::get())
+        .map(|_| {
+            let query = query.clone();
+            thread::spawn(move || run_client(addr, &query, deadline))
+        })
+        .collect();
+    let answered: u64 = clients.into_iter().map(|c| c.join().unwrap()).sum();
+    println!(
+        "workers {:>3} batch size {:>3}: {:>10} qps",
+        workers,
+        batch_size,
+        answered / BENCH_DURATION.as_secs()
+    );
+
+    let _ = stop.send(());
+    server_thread.join().unwrap();
+}
+
+fn main() {
+    let cpus = num_cpus::get();
+    let cases = [(1, 1), (cpus, 1), (cpus, 16)];
+    for (i, &(workers, batch_size)) in cases.iter().enumerate() {
+        bench(BASE_PORT + i as u16, workers, batch_size);
+    }
+}
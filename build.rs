@@ -1,5 +1,6 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("src/controller/proto/rrset.proto")?;
     tonic_build::compile_protos("src/controller/proto/dynamic_update_interface.proto")?;
+    tonic_build::compile_protos("src/dnstap/dnstap.proto")?;
     Ok(())
 }
@@ -1,25 +1,72 @@
+use super::memory_zone::MemoryZone;
+use super::notify::{check_notify, NotifyHook};
+use super::secondary::SecondaryManager;
+use super::transfer::{incremental_transfer_zone, transfer_zone};
+use super::update::update_zone;
+use super::zone_loader::load_zone;
 use super::zones::AuthZone;
-use crate::{config::AuthorityConfig, types::Request};
-use r53::{Message, Name};
+use crate::{
+    config::{AuthZoneConfig, AuthorityConfig, Protocol, ZoneType},
+    types::{Acl, MessageStream, Request},
+};
+use anyhow::{self, Context};
+use r53::{HeaderFlag, Message, MessageBuilder, Name, RRType, Rcode, SectionType};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::fs;
-use std::sync::{Arc, RwLock};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::task::spawn_blocking;
+
+//zones loaded but not applied yet, zone set to none will be deleted
+pub struct ZoneChanges {
+    zones: Vec<(Name, Option<MemoryZone>)>,
+    zone_files: HashMap<Name, u64>,
+    transfer_acls: HashMap<Name, Arc<Acl>>,
+}
 
 #[derive(Clone)]
 pub struct AuthServer {
     zones: Arc<RwLock<AuthZone>>,
+    //hash of zone file content for zones from config, zones added
+    //by controller aren't touched during reload
+    zone_files: Arc<Mutex<HashMap<Name, u64>>>,
+    notify_hooks: Arc<RwLock<Vec<Arc<dyn NotifyHook>>>>,
+    //zones without acl can't be transferred
+    transfer_acls: Arc<RwLock<HashMap<Name, Arc<Acl>>>>,
+    secondaries: Arc<SecondaryManager>,
 }
 
 impl AuthServer {
     pub fn new(conf: &AuthorityConfig) -> Self {
         let mut zones = AuthZone::new();
+        let mut zone_files = HashMap::new();
         for zone_conf in conf.zones.iter() {
+            let name = Name::new(&zone_conf.name).unwrap();
+            if zone_conf.zone_type == ZoneType::Secondary {
+                zones.set_zone(
+                    name.clone(),
+                    Some(load_secondary_zone(name, zone_conf, conf)),
+                );
+                continue;
+            }
             let zone_content = fs::read_to_string(&zone_conf.file_path).unwrap();
-            zones
-                .add_zone(Name::new(&zone_conf.name).unwrap(), &zone_content)
-                .unwrap();
+            let zone = load_zone_file(name.clone(), &zone_content, zone_conf, conf).unwrap();
+            zones.set_zone(name.clone(), Some(zone));
+            zone_files.insert(name, content_hash(&zone_content));
         }
+        let zones = Arc::new(RwLock::new(zones));
+        let secondaries = Arc::new(SecondaryManager::new(zones.clone(), conf).unwrap());
+        let notify_hooks: Vec<Arc<dyn NotifyHook>> = vec![secondaries.clone()];
         AuthServer {
-            zones: Arc::new(RwLock::new(zones)),
+            zones,
+            zone_files: Arc::new(Mutex::new(zone_files)),
+            notify_hooks: Arc::new(RwLock::new(notify_hooks)),
+            transfer_acls: Arc::new(RwLock::new(new_transfer_acls(conf).unwrap())),
+            secondaries,
         }
     }
 
@@ -27,7 +74,224 @@ impl AuthServer {
         self.zones.read().unwrap().resolve(req)
     }
 
+    pub fn add_notify_hook(&self, hook: Arc<dyn NotifyHook>) {
+        self.notify_hooks.write().unwrap().push(hook);
+    }
+
+    //notify is acknowledged once it's handed over to the hooks, zone
+    //maintenance is done by them in background
+    pub fn handle_notify(&self, req: &Request) -> Message {
+        let result = check_notify(&self.zones.read().unwrap(), &req.request);
+        let rcode = match result {
+            Ok((zone, serial)) => {
+                debug!("receive notify for zone {} from {}", zone, req.client);
+                for hook in self.notify_hooks.read().unwrap().iter() {
+                    hook.on_notify(&zone, req.client, serial);
+                }
+                Rcode::NoError
+            }
+            Err(rcode) => rcode,
+        };
+        header_response(&req.request, rcode, true)
+    }
+
+    //secondary zone is only changed by its primaries. journal is written
+    //after zones are unlocked, so queries aren't blocked by disk io
+    pub async fn handle_update(&self, req: &Request) -> Message {
+        let name = &req.question().name;
+        if self.secondaries.is_secondary(name) {
+            return header_response(&req.request, Rcode::Refused, false);
+        }
+        let (rcode, journal) = {
+            let mut zones = self.zones.write().unwrap();
+            let rcode = update_zone(&mut zones, &req.request);
+            let journal = zones
+                .get_exact_zone(name)
+                .and_then(|zone| zone.journal_file());
+            (rcode, journal)
+        };
+        if let Some(journal) = journal {
+            match spawn_blocking(move || journal.flush()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("write journal of zone {} failed: {:?}", name, e),
+                Err(e) => warn!("write journal of zone {} failed: {}", name, e),
+            }
+        }
+        header_response(&req.request, rcode, false)
+    }
+
+    //first message is the response, the rest are sent after it. ixfr
+    //shares the acl of axfr
+    pub fn handle_transfer(&self, req: &Request) -> MessageStream {
+        let zone = &req.question().name;
+        let allowed = self
+            .transfer_acls
+            .read()
+            .unwrap()
+            .get(zone)
+            .map_or(false, |acl| acl.contains(req.client.ip()));
+        if !allowed {
+            return Box::new(iter::once(header_response(
+                &req.request,
+                Rcode::Refused,
+                false,
+            )));
+        }
+        let zones = self.zones.read().unwrap();
+        let result = if req.question().typ == RRType::IXFR {
+            incremental_transfer_zone(&zones, &req.request, req.transport == Protocol::Udp)
+        } else {
+            transfer_zone(&zones, &req.request)
+        };
+        match result {
+            Ok(messages) => {
+                debug!("transfer zone {} to {}", zone, req.client);
+                Box::new(messages)
+            }
+            Err(rcode) => Box::new(iter::once(header_response(&req.request, rcode, false))),
+        }
+    }
+
+    pub fn export_zone(&self, name: &Name) -> anyhow::Result<String> {
+        self.zones.read().unwrap().export_zone(name)
+    }
+
+    pub fn snapshot_zone(&self, name: &Name, path: &Path) -> anyhow::Result<()> {
+        self.zones.read().unwrap().snapshot_zone(name, path)
+    }
+
+    //keep secondary zones refreshed, it never returns
+    pub fn run_secondary_zones(&self) -> impl Future<Output = ()> {
+        self.secondaries.clone().run()
+    }
+
     pub fn zone_data(&self) -> Arc<RwLock<AuthZone>> {
         self.zones.clone()
     }
+
+    //only zones whose file content changed are loaded again, secondary
+    //zones are maintained by their own tasks and changed after restart
+    pub fn prepare_reload(&self, conf: &AuthorityConfig) -> anyhow::Result<ZoneChanges> {
+        self.secondaries.check_reload(conf)?;
+        let old_zone_files = self.zone_files.lock().unwrap().clone();
+        let mut zones = Vec::new();
+        let mut zone_files = HashMap::new();
+        for zone_conf in conf.zones.iter() {
+            if zone_conf.zone_type == ZoneType::Secondary {
+                continue;
+            }
+            let name = Name::new(&zone_conf.name)?;
+            let zone_content = fs::read_to_string(&zone_conf.file_path)
+                .with_context(|| format!("read zone file {} failed", zone_conf.file_path))?;
+            let hash = content_hash(&zone_content);
+            if old_zone_files.get(&name) != Some(&hash) {
+                let zone = load_zone_file(name.clone(), &zone_content, zone_conf, conf)
+                    .with_context(|| format!("load zone {} failed", zone_conf.name))?;
+                zones.push((name.clone(), Some(zone)));
+            }
+            zone_files.insert(name, hash);
+        }
+
+        for name in old_zone_files.keys() {
+            if !zone_files.contains_key(name) {
+                zones.push((name.clone(), None));
+            }
+        }
+        Ok(ZoneChanges {
+            zones,
+            zone_files,
+            transfer_acls: new_transfer_acls(conf)?,
+        })
+    }
+
+    //zones of the server are locked by caller, so changes of several
+    //servers could be applied together
+    pub fn apply_reload(&self, zones: &mut AuthZone, changes: ZoneChanges) {
+        for (name, zone) in changes.zones {
+            info!(
+                "{} zone {}",
+                if zone.is_some() { "reload" } else { "remove" },
+                name.to_string()
+            );
+            zones.set_zone(name, zone);
+        }
+        *self.zone_files.lock().unwrap() = changes.zone_files;
+        *self.transfer_acls.write().unwrap() = changes.transfer_acls;
+    }
+}
+
+//changes recorded in journal are applied on top of zone file
+fn load_zone_file(
+    name: Name,
+    zone_content: &str,
+    zone_conf: &AuthZoneConfig,
+    conf: &AuthorityConfig,
+) -> anyhow::Result<MemoryZone> {
+    let zone_file = Path::new(&zone_conf.file_path);
+    let mut zone = load_zone(name, zone_content, Some(zone_file))?;
+    let journal_file = zone_conf.journal_file.as_ref().map(Path::new);
+    zone.open_journal(journal_file, Some(zone_file), conf.max_journal_len)
+        .context("open journal failed")?;
+    Ok(zone)
+}
+
+//zone saved by last transfer is served until it's refreshed, zone
+//without data answers servfail
+fn load_secondary_zone(
+    name: Name,
+    zone_conf: &AuthZoneConfig,
+    conf: &AuthorityConfig,
+) -> MemoryZone {
+    let path = Path::new(&zone_conf.file_path);
+    if !path.exists() {
+        return MemoryZone::new(name);
+    }
+    match fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|zone_content| load_zone_file(name.clone(), &zone_content, zone_conf, conf))
+    {
+        Ok(zone) => zone,
+        Err(e) => {
+            warn!("load secondary zone {} failed: {:?}", zone_conf.name, e);
+            MemoryZone::new(name)
+        }
+    }
+}
+
+fn new_transfer_acls(conf: &AuthorityConfig) -> anyhow::Result<HashMap<Name, Arc<Acl>>> {
+    let mut acls = HashMap::new();
+    for zone_conf in conf.zones.iter() {
+        if let Some(ref addrs) = zone_conf.allow_transfer {
+            let acl = Acl::new(addrs.iter().map(|addr| addr.as_ref()).collect())
+                .with_context(|| format!("invalid transfer acl of zone {}", zone_conf.name))?;
+            acls.insert(Name::new(&zone_conf.name)?, Arc::new(acl));
+        }
+    }
+    Ok(acls)
+}
+
+//only header and zone section are sent back
+fn header_response(request: &Message, rcode: Rcode, authoritative: bool) -> Message {
+    let mut response = request.clone();
+    for section in &[
+        SectionType::Answer,
+        SectionType::Authority,
+        SectionType::Additional,
+    ] {
+        response.take_section(*section);
+    }
+    let mut builder = MessageBuilder::new(&mut response);
+    builder.make_response().rcode(rcode);
+    if authoritative {
+        builder.set_flag(HeaderFlag::AuthAnswer);
+    }
+    builder.done();
+    response.recalculate_header();
+    response
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
 }
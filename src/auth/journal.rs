@@ -0,0 +1,406 @@
+use super::zone_exporter::{write_file, write_rrsets};
+use super::zone_loader::parse_rrs;
+use anyhow::{bail, ensure, Context, Result};
+use r53::{Name, RData, RRType, RRset};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_MAX_JOURNAL_LEN: usize = 100;
+
+//one change of zone, soa is kept out of deleted and added rrsets
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diff {
+    pub old_soa: RRset,
+    pub new_soa: RRset,
+    pub deleted: Vec<RRset>,
+    pub added: Vec<RRset>,
+}
+
+impl Diff {
+    pub fn old_serial(&self) -> u32 {
+        soa_serial(&self.old_soa).unwrap_or(0)
+    }
+
+    pub fn new_serial(&self) -> u32 {
+        soa_serial(&self.new_soa).unwrap_or(0)
+    }
+}
+
+//diffs are chained by serial, the file uses the same layout as ixfr
+//answer, every diff is old soa, deleted rrs, new soa and added rrs.
+//new diff is appended to the file, which is rewritten with the diffs
+//kept once it holds twice of them
+#[derive(Clone)]
+pub struct Journal {
+    max_len: usize,
+    diffs: VecDeque<Diff>,
+    file: Option<JournalFile>,
+}
+
+//writes are queued while zones are locked and done by the caller after
+//zones are unlocked, so disk io doesn't block queries. the queue is
+//shared by zone copies, and writes are done in order under its lock
+#[derive(Clone)]
+pub struct JournalFile {
+    pending: Arc<Mutex<PendingWrites>>,
+}
+
+struct PendingWrites {
+    path: PathBuf,
+    //diffs in file once the pending ones are written, including the
+    //ones trimmed
+    file_len: usize,
+    rewrite: bool,
+    diffs: Vec<Diff>,
+    //zone file is written before journal drops the diffs it needs
+    zone_file: Option<(PathBuf, String)>,
+}
+
+impl Journal {
+    pub fn new(max_len: usize) -> Self {
+        Journal {
+            max_len,
+            diffs: VecDeque::new(),
+            file: None,
+        }
+    }
+
+    pub fn open(origin: &Name, path: &Path, max_len: usize) -> Result<Self> {
+        let mut journal = Journal::new(max_len);
+        let mut file_len = 0;
+        if path.exists() {
+            let content = fs::read_to_string(path)?;
+            let diffs = parse_rrs(origin, &content, Some(path))
+                .and_then(parse_diffs)
+                .with_context(|| format!("invalid journal {}", path.display()))?;
+            file_len = diffs.len();
+            for diff in diffs {
+                journal.push(diff);
+            }
+        }
+        journal.file = Some(JournalFile::new(path, file_len));
+        Ok(journal)
+    }
+
+    pub fn diffs(&self) -> impl Iterator<Item = &Diff> {
+        self.diffs.iter()
+    }
+
+    pub fn file(&self) -> Option<JournalFile> {
+        self.file.clone()
+    }
+
+    //journal is restarted if the diff doesn't follow the last one
+    pub fn append(&mut self, diff: Diff) {
+        let restarted = self.push(diff.clone());
+        if let Some(ref file) = self.file {
+            let mut pending = file.pending.lock().unwrap();
+            if restarted || pending.file_len >= 2 * self.max_len {
+                pending.rewrite = true;
+                pending.diffs = self.diffs.iter().cloned().collect();
+                pending.file_len = pending.diffs.len();
+            } else {
+                pending.diffs.push(diff);
+                pending.file_len += 1;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.diffs.clear();
+        match self.file {
+            Some(ref file) => {
+                {
+                    let mut pending = file.pending.lock().unwrap();
+                    pending.rewrite = true;
+                    pending.diffs.clear();
+                    pending.file_len = 0;
+                }
+                file.flush()
+            }
+            None => Ok(()),
+        }
+    }
+
+    //the first diff is dropped by next append
+    pub fn is_full(&self) -> bool {
+        self.diffs.len() >= self.max_len
+    }
+
+    pub fn first_serial(&self) -> Option<u32> {
+        self.diffs.front().map(Diff::old_serial)
+    }
+
+    //diffs which bring zone from serial to the latest one
+    pub fn diffs_since(&self, serial: u32) -> Option<Vec<Diff>> {
+        let pos = self.diffs.iter().position(|d| d.old_serial() == serial)?;
+        Some(self.diffs.iter().skip(pos).cloned().collect())
+    }
+
+    //return true if the journal is restarted
+    fn push(&mut self, diff: Diff) -> bool {
+        let restarted = match self.diffs.back() {
+            Some(last) if last.new_serial() != diff.old_serial() => {
+                self.diffs.clear();
+                true
+            }
+            _ => false,
+        };
+        self.diffs.push_back(diff);
+        while self.diffs.len() > self.max_len {
+            self.diffs.pop_front();
+        }
+        restarted
+    }
+}
+
+impl JournalFile {
+    fn new(path: &Path, file_len: usize) -> Self {
+        JournalFile {
+            pending: Arc::new(Mutex::new(PendingWrites {
+                path: path.to_path_buf(),
+                file_len,
+                rewrite: false,
+                diffs: Vec::new(),
+                zone_file: None,
+            })),
+        }
+    }
+
+    pub fn save_zone_file(&self, path: &Path, content: String) {
+        self.pending.lock().unwrap().zone_file = Some((path.to_path_buf(), content));
+    }
+
+    //writes which fail are kept and tried again by next flush. the last
+    //diffs are written at once and synced, so they are either in the
+    //file as a whole or missing
+    pub fn flush(&self) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some((ref path, ref content)) = pending.zone_file {
+            write_file(path, content)
+                .with_context(|| format!("write zone file {} failed", path.display()))?;
+        }
+        pending.zone_file = None;
+        if !pending.rewrite && pending.diffs.is_empty() {
+            return Ok(());
+        }
+
+        let mut content = String::new();
+        for diff in pending.diffs.iter() {
+            write_diff(&mut content, diff);
+        }
+        if pending.rewrite {
+            write_file(&pending.path, &content)?;
+        } else {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&pending.path)
+                .with_context(|| format!("open {} failed", pending.path.display()))?;
+            file.write_all(content.as_bytes())?;
+            file.sync_data()?;
+        }
+        pending.rewrite = false;
+        pending.diffs.clear();
+        Ok(())
+    }
+}
+
+fn write_diff(content: &mut String, diff: &Diff) {
+    write_rrsets(content, &[diff.old_soa.clone()]);
+    write_rrsets(content, &diff.deleted);
+    write_rrsets(content, &[diff.new_soa.clone()]);
+    write_rrsets(content, &diff.added);
+}
+
+//rrs are in the layout of ixfr answer without the leading and trailing
+//soa, a soa begins the deleted or added rrs of a diff
+pub fn parse_diffs(rrs: Vec<RRset>) -> Result<Vec<Diff>> {
+    let mut sections: Vec<(RRset, Vec<RRset>)> = Vec::new();
+    for rrset in rrs {
+        if rrset.typ == RRType::SOA {
+            sections.push((rrset, Vec::new()));
+        } else if let Some((_, rrsets)) = sections.last_mut() {
+            rrsets.push(rrset);
+        } else {
+            bail!("diff doesn't start with soa");
+        }
+    }
+    ensure!(sections.len() % 2 == 0, "diff is truncated");
+
+    let mut diffs = Vec::with_capacity(sections.len() / 2);
+    let mut sections = sections.into_iter();
+    while let (Some((old_soa, deleted)), Some((new_soa, added))) =
+        (sections.next(), sections.next())
+    {
+        diffs.push(Diff {
+            old_soa,
+            new_soa,
+            deleted,
+            added,
+        });
+    }
+    Ok(diffs)
+}
+
+//rrset with ttl changed is replaced as a whole, otherwise only the
+//rdatas differ are recorded
+pub fn compare_rrsets(before: &[RRset], after: &[RRset]) -> (Vec<RRset>, Vec<RRset>) {
+    let mut deleted = Vec::new();
+    let mut added = Vec::new();
+    let find = |rrsets: &[RRset], rrset: &RRset| {
+        rrsets
+            .iter()
+            .find(|r| r.name == rrset.name && r.typ == rrset.typ)
+            .cloned()
+    };
+    for old in before {
+        match find(after, old) {
+            Some(new) if new.ttl == old.ttl => {
+                let removed = rdatas_not_in(&old.rdatas, &new.rdatas);
+                if !removed.is_empty() {
+                    deleted.push(RRset {
+                        rdatas: removed,
+                        ..old.clone()
+                    });
+                }
+                let appended = rdatas_not_in(&new.rdatas, &old.rdatas);
+                if !appended.is_empty() {
+                    added.push(RRset {
+                        rdatas: appended,
+                        ..new
+                    });
+                }
+            }
+            Some(new) => {
+                deleted.push(old.clone());
+                added.push(new);
+            }
+            None => deleted.push(old.clone()),
+        }
+    }
+    for new in after {
+        if find(before, new).is_none() {
+            added.push(new.clone());
+        }
+    }
+    (deleted, added)
+}
+
+fn rdatas_not_in(rdatas: &[RData], other: &[RData]) -> Vec<RData> {
+    rdatas
+        .iter()
+        .filter(|rdata| !other.contains(rdata))
+        .cloned()
+        .collect()
+}
+
+pub fn soa_serial(rrset: &RRset) -> Option<u32> {
+    match rrset.rdatas.first() {
+        Some(RData::SOA(soa)) => Some(soa.serial),
+        _ => None,
+    }
+}
+
+//serial number arithmetic defined in rfc1982
+pub fn is_serial_newer(serial: u32, old_serial: u32) -> bool {
+    (serial.wrapping_sub(old_serial) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::str::FromStr;
+
+    fn rrset(s: &str) -> RRset {
+        RRset::from_str(s).unwrap()
+    }
+
+    fn soa(serial: u32) -> RRset {
+        rrset(&format!(
+            "example.org. 300 IN SOA ns.example.org. root.example.org. {} 1800 900 604800 86400",
+            serial
+        ))
+    }
+
+    fn diff(serial: u32, deleted: Vec<RRset>, added: Vec<RRset>) -> Diff {
+        Diff {
+            old_soa: soa(serial),
+            new_soa: soa(serial + 1),
+            deleted,
+            added,
+        }
+    }
+
+    #[test]
+    fn test_compare_rrsets() {
+        let before = vec![
+            rrset("www.example.org. 300 IN A 192.0.2.1"),
+            rrset("mail.example.org. 300 IN A 192.0.2.2"),
+        ];
+        let mut www = rrset("www.example.org. 300 IN A 192.0.2.1");
+        www.rdatas
+            .push(RData::from_str(RRType::A, "192.0.2.3").unwrap());
+        let after = vec![www, rrset("mail.example.org. 600 IN A 192.0.2.2")];
+        let (deleted, added) = compare_rrsets(&before, &after);
+        assert_eq!(deleted, vec![rrset("mail.example.org. 300 IN A 192.0.2.2")]);
+        assert_eq!(
+            added,
+            vec![
+                rrset("www.example.org. 300 IN A 192.0.2.3"),
+                rrset("mail.example.org. 600 IN A 192.0.2.2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_journal() {
+        let origin = Name::new("example.org.").unwrap();
+        let dir = env::temp_dir().join(format!("vanguard2-journal-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.org.jnl");
+
+        let mut journal = Journal::open(&origin, &path, 2).unwrap();
+        journal.append(diff(
+            100,
+            Vec::new(),
+            vec![rrset("a.example.org. 300 IN A 192.0.2.1")],
+        ));
+        journal.append(diff(
+            101,
+            vec![rrset("a.example.org. 300 IN A 192.0.2.1")],
+            Vec::new(),
+        ));
+        journal.append(diff(
+            102,
+            Vec::new(),
+            vec![rrset("b.example.org. 300 IN A 192.0.2.2")],
+        ));
+        assert!(journal.diffs_since(100).is_none());
+        assert_eq!(journal.diffs_since(101).unwrap().len(), 2);
+        assert_eq!(journal.diffs_since(102).unwrap().len(), 1);
+
+        //diffs are written by flush
+        assert_eq!(Journal::open(&origin, &path, 2).unwrap().diffs().count(), 0);
+        journal.file().unwrap().flush().unwrap();
+        let reopened = Journal::open(&origin, &path, 2).unwrap();
+        assert_eq!(
+            reopened.diffs().collect::<Vec<_>>(),
+            journal.diffs().collect::<Vec<_>>()
+        );
+
+        //serial gap restarts the journal
+        journal.append(diff(200, Vec::new(), Vec::new()));
+        assert!(journal.diffs_since(101).is_none());
+        assert_eq!(journal.diffs_since(200).unwrap().len(), 1);
+        journal.file().unwrap().flush().unwrap();
+        let reopened = Journal::open(&origin, &path, 2).unwrap();
+        assert_eq!(reopened.diffs().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
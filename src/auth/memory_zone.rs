@@ -1,9 +1,15 @@
+use crate::auth::journal::{
+    compare_rrsets, is_serial_newer, soa_serial, Diff, Journal, JournalFile,
+    DEFAULT_MAX_JOURNAL_LEN,
+};
 use crate::auth::rdataset::Rdataset;
 use crate::auth::zone::{FindOption, FindResult, FindResultType, ZoneFinder, ZoneUpdater};
+use crate::auth::zone_exporter::export_zone;
 use anyhow::{bail, ensure, Result};
 use domaintree::{DomainTree, FindResultFlag, NodeChain, NodePtr};
 use r53::{LabelSequence, Name, NameRelation, RData, RRType, RRset};
 use std::mem::swap;
+use std::path::{Path, PathBuf};
 
 type ZoneData = DomainTree<Rdataset>;
 
@@ -13,6 +19,10 @@ pub struct MemoryZone {
     //soa and ns lookup
     root_node: NodePtr<Rdataset>,
     pub data: ZoneData,
+    journal: Journal,
+    //journal is replayed on top of zone file, which has the serial
+    zone_file: Option<PathBuf>,
+    file_serial: Option<u32>,
 }
 
 impl MemoryZone {
@@ -26,9 +36,216 @@ impl MemoryZone {
             origin: name,
             root_node,
             data,
+            journal: Journal::new(DEFAULT_MAX_JOURNAL_LEN),
+            zone_file: None,
+            file_serial: None,
         }
     }
 
+    pub fn get_soa(&self) -> Option<RRset> {
+        self.root_node
+            .get_value()
+            .as_ref()
+            .and_then(|rdataset| rdataset.get_rrset(&self.origin, RRType::SOA))
+    }
+
+    pub fn get_serial(&self) -> Option<u32> {
+        self.get_soa().as_ref().and_then(soa_serial)
+    }
+
+    //changes made by f to the names are recorded in journal, soa
+    //serial is increased unless it's updated to a newer one by f. journal
+    //file is written by flush_journal
+    pub fn apply_change<T, F: FnOnce(&mut MemoryZone) -> T>(&mut self, names: &[Name], f: F) -> T {
+        let mut changed_names = vec![self.origin.clone()];
+        for name in names {
+            if !changed_names.contains(name) {
+                changed_names.push(name.clone());
+            }
+        }
+        let names = changed_names;
+        let old_soa = self.get_soa();
+        let before = self.get_change_rrsets(&names);
+        let result = f(self);
+        let (deleted, added) = compare_rrsets(&before, &self.get_change_rrsets(&names));
+
+        let old_soa = match old_soa {
+            Some(old_soa) => old_soa,
+            None => return result,
+        };
+        let mut new_soa = self.get_soa().unwrap_or_else(|| old_soa.clone());
+        if deleted.is_empty() && added.is_empty() && new_soa == old_soa {
+            return result;
+        }
+        let old_serial = soa_serial(&old_soa).unwrap_or(0);
+        if !soa_serial(&new_soa).map_or(false, |serial| is_serial_newer(serial, old_serial)) {
+            if let Some(RData::SOA(ref mut soa)) = new_soa.rdatas.first_mut() {
+                soa.serial = old_serial.wrapping_add(1);
+            }
+            if let Err(e) = self.add_rrset(new_soa.clone()) {
+                warn!("increase serial of zone {} failed: {}", self.origin, e);
+            }
+        }
+        let diff = Diff {
+            old_soa,
+            new_soa,
+            deleted,
+            added,
+        };
+        if self.journal.is_full() && self.is_file_trimmed() {
+            self.save_zone_file();
+        }
+        self.journal.append(diff);
+        result
+    }
+
+    //zone data is copied, so changes could be made aside and swapped
+    //in at once, journal goes with the copy
+    pub fn duplicate(&self) -> Result<MemoryZone> {
+        let mut zone = MemoryZone::new(self.origin.clone());
+        for rrset in self.get_all_rrsets() {
+            zone.add_rrset(rrset)?;
+        }
+        zone.journal = self.journal.clone();
+        zone.zone_file = self.zone_file.clone();
+        zone.file_serial = self.file_serial;
+        Ok(zone)
+    }
+
+    //zone file can't be brought up to date after the diff following
+    //it is dropped from journal
+    fn is_file_trimmed(&self) -> bool {
+        match (self.file_serial, self.journal.first_serial()) {
+            (Some(file_serial), Some(first_serial)) => !is_serial_newer(file_serial, first_serial),
+            _ => false,
+        }
+    }
+
+    fn save_zone_file(&mut self) {
+        if let (Some(path), Some(file)) = (self.zone_file.as_ref(), self.journal.file()) {
+            file.save_zone_file(path, export_zone(self));
+            self.file_serial = self.get_serial();
+        }
+    }
+
+    pub fn journal_file(&self) -> Option<JournalFile> {
+        self.journal.file()
+    }
+
+    pub fn flush_journal(&self) -> Result<()> {
+        match self.journal.file() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn get_change_rrsets(&self, names: &[Name]) -> Vec<RRset> {
+        names
+            .iter()
+            .flat_map(|name| self.get_rrsets(name))
+            .filter(|rrset| rrset.typ != RRType::SOA)
+            .collect()
+    }
+
+    //diffs in journal newer than the zone are applied, journal which
+    //doesn't match the zone is dropped. zone file is saved before the
+    //diffs needed to replay on it are trimmed from journal
+    pub fn open_journal(
+        &mut self,
+        path: Option<&Path>,
+        zone_file: Option<&Path>,
+        max_len: usize,
+    ) -> Result<()> {
+        self.journal = match path {
+            Some(path) => Journal::open(&self.origin, path, max_len)?,
+            None => Journal::new(max_len),
+        };
+        self.zone_file = zone_file.map(Path::to_path_buf);
+        self.file_serial = self.get_serial();
+        let serial = match self.get_serial() {
+            Some(serial) => serial,
+            None => return Ok(()),
+        };
+        let diffs = match self.journal.diffs_since(serial) {
+            Some(diffs) => diffs,
+            None => {
+                let last_serial = self.journal.diffs().last().map(|diff| diff.new_serial());
+                if last_serial.is_some() && last_serial != Some(serial) {
+                    warn!(
+                        "journal of zone {} doesn't match zone serial {}",
+                        self.origin, serial
+                    );
+                    self.journal.clear()?;
+                }
+                return Ok(());
+            }
+        };
+        for diff in diffs {
+            if let Err(e) = self.apply_diff(&diff) {
+                warn!("apply journal to zone {} failed: {}", self.origin, e);
+                self.journal.clear()?;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_diff(&mut self, diff: &Diff) -> Result<()> {
+        for rrset in diff.deleted.iter() {
+            self.delete_rdata(rrset)?;
+        }
+        for rrset in diff.added.iter() {
+            self.add_rrset(rrset.clone())?;
+        }
+        self.add_rrset(diff.new_soa.clone())
+    }
+
+    //none if serial is too old, then whole zone has to be transferred
+    pub fn get_diffs(&self, serial: u32) -> Option<Vec<Diff>> {
+        let current = self.get_serial()?;
+        if serial == current {
+            return Some(Vec::new());
+        }
+        let diffs = self.journal.diffs_since(serial)?;
+        match diffs.last() {
+            Some(diff) if diff.new_serial() == current => Some(diffs),
+            _ => None,
+        }
+    }
+
+    //rrsets owned by the name, zone cut and wildcard are ignored
+    pub fn get_rrsets(&self, name: &Name) -> Vec<RRset> {
+        let result = self.data.find(name);
+        if result.flag != FindResultFlag::ExacatMatch {
+            return Vec::new();
+        }
+        result
+            .node
+            .get_value()
+            .as_ref()
+            .map_or_else(Vec::new, |rdataset| rdataset.get_rrsets(name))
+    }
+
+    //all rrsets in canonical order, walk starts from apex and soa is
+    //the first rrset
+    pub fn get_all_rrsets(&self) -> Vec<RRset> {
+        let mut rrsets = Vec::new();
+        let mut node_chain = NodeChain::new(&self.data);
+        let mut node = self.data.find_node(&self.origin, &mut node_chain).node;
+        while !node.is_null() {
+            if let Some(rdataset) = node.get_value() {
+                let top = node_chain.pop();
+                let name = node_chain.get_absolute_name(top.get_name());
+                node_chain.push(top);
+                let mut node_rrsets = rdataset.get_rrsets(&name);
+                node_rrsets.sort_by_key(|rrset| (rrset.typ != RRType::SOA, rrset.typ.to_u16()));
+                rrsets.append(&mut node_rrsets);
+            }
+            node = self.data.next_node(&mut node_chain);
+        }
+        rrsets
+    }
+
     fn remove_node(&mut self, name: &Name, node: NodePtr<Rdataset>) {
         if name.is_wildcard() {
             if let Ok(parent) = name.parent(1) {
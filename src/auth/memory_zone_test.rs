@@ -1,5 +1,7 @@
 use crate::auth::memory_zone::MemoryZone;
 use crate::auth::zone::{FindOption, FindResult, FindResultType, ZoneFinder, ZoneUpdater};
+use crate::auth::zone_exporter::snapshot_zone;
+use crate::auth::zone_loader::load_zone;
 use r53::{Name, RRType, RRset};
 use std::str::FromStr;
 
@@ -376,3 +378,70 @@ fn test_empty_node() {
     );
     assert_eq!(result.typ, FindResultType::NXDomain);
 }
+
+#[test]
+fn test_journal_replay() {
+    let dir = std::env::temp_dir().join(format!("vanguard2-replay-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("example.org.jnl");
+    let www = RRset::from_str("www.example.org. 300 IN A 192.0.2.10").unwrap();
+    let mut zone = build_zone("example.org", default_zone());
+    zone.open_journal(Some(&path), None, 10).unwrap();
+    zone.apply_change(&[www.name.clone()], |zone| zone.add_rrset(www.clone()))
+        .unwrap();
+    zone.apply_change(&[www.name.clone()], |zone| {
+        zone.delete_rrset(&www.name, RRType::A)
+    })
+    .unwrap();
+    zone.apply_change(&[www.name.clone()], |zone| zone.add_rrset(www.clone()))
+        .unwrap();
+    assert_eq!(zone.get_serial(), Some(103));
+    assert_eq!(zone.get_diffs(101).unwrap().len(), 2);
+    assert!(zone.get_diffs(99).is_none());
+    zone.flush_journal().unwrap();
+
+    let mut reloaded = build_zone("example.org", default_zone());
+    reloaded.open_journal(Some(&path), None, 10).unwrap();
+    assert_eq!(reloaded.get_serial(), Some(103));
+    assert_eq!(reloaded.get_rrsets(&www.name), vec![www]);
+    assert_eq!(reloaded.get_all_rrsets(), zone.get_all_rrsets());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_journal_trimmed() {
+    let dir = std::env::temp_dir().join(format!("vanguard2-trimmed-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let zone_file = dir.join("example.org.zone");
+    let journal_file = dir.join("example.org.jnl");
+    let mut zone = build_zone("example.org", default_zone());
+    snapshot_zone(&zone, &zone_file).unwrap();
+    zone.open_journal(Some(&journal_file), Some(&zone_file), 2)
+        .unwrap();
+    let www = RRset::from_str("www.example.org. 300 IN A 192.0.2.10").unwrap();
+    for _ in 0..5 {
+        zone.apply_change(&[www.name.clone()], |zone| {
+            zone.delete_rrset(&www.name, RRType::A)
+        })
+        .unwrap();
+        zone.apply_change(&[www.name.clone()], |zone| zone.add_rrset(www.clone()))
+            .unwrap();
+    }
+    assert_eq!(zone.get_serial(), Some(110));
+    assert_eq!(zone.get_diffs(108).unwrap().len(), 2);
+    assert!(zone.get_diffs(107).is_none());
+    zone.flush_journal().unwrap();
+
+    //zone file is saved before the diffs it needs are trimmed
+    let name = Name::new("example.org").unwrap();
+    let content = std::fs::read_to_string(&zone_file).unwrap();
+    let mut reloaded = load_zone(name, &content, Some(&zone_file)).unwrap();
+    reloaded
+        .open_journal(Some(&journal_file), Some(&zone_file), 2)
+        .unwrap();
+    assert_eq!(reloaded.get_serial(), Some(110));
+    assert_eq!(reloaded.get_rrsets(&www.name), vec![www]);
+    assert_eq!(reloaded.get_all_rrsets(), zone.get_all_rrsets());
+    assert_eq!(reloaded.get_diffs(108).unwrap().len(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,15 +2,23 @@ mod rdataset;
 
 mod memory_zone;
 mod zone;
+mod zone_exporter;
 mod zone_loader;
 
 mod auth_server;
+mod journal;
+mod notify;
+mod secondary;
+mod transfer;
 //mod proto;
+mod update;
 mod zones;
 
 #[cfg(test)]
 mod memory_zone_test;
 
 pub use auth_server::AuthServer;
+pub use memory_zone::MemoryZone;
+pub use notify::NotifyHook;
 pub use zone::ZoneUpdater;
 pub use zones::AuthZone;
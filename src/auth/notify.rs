@@ -0,0 +1,86 @@
+use super::zone::ZoneFinder;
+use super::zones::AuthZone;
+use r53::{Message, Name, RData, RRType, Rcode, SectionType};
+use std::net::SocketAddr;
+
+//zone maintenance like refreshing a secondary zone is triggered by
+//notify, serial is the one carried by notify if any
+pub trait NotifyHook: Send + Sync {
+    fn on_notify(&self, zone: &Name, source: SocketAddr, serial: Option<u32>);
+}
+
+//notify defined in rfc1996, only soa is supported
+pub fn check_notify(zones: &AuthZone, request: &Message) -> Result<(Name, Option<u32>), Rcode> {
+    let question = match request.question {
+        Some(ref question) => question,
+        None => return Err(Rcode::FormErr),
+    };
+    if question.typ != RRType::SOA {
+        return Err(Rcode::NotImp);
+    }
+    match zones.get_zone(&question.name) {
+        Some(zone) if *zone.get_origin() == question.name => {}
+        _ => return Err(Rcode::NotAuth),
+    }
+
+    let serial = request
+        .section(SectionType::Answer)
+        .and_then(|answers| {
+            answers
+                .iter()
+                .find(|rrset| rrset.typ == RRType::SOA && rrset.name == question.name)
+        })
+        .and_then(|soa| match soa.rdatas.first() {
+            Some(RData::SOA(soa)) => Some(soa.serial),
+            _ => None,
+        });
+    Ok((question.name.clone(), serial))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::{MessageBuilder, Opcode, RRset};
+    use std::str::FromStr;
+
+    fn notify_message(zone: &str, typ: RRType, soa: Option<&str>) -> Message {
+        let mut message = Message::with_query(Name::new(zone).unwrap(), typ);
+        let mut builder = MessageBuilder::new(&mut message);
+        builder.opcode(Opcode::Notify);
+        if let Some(soa) = soa {
+            builder.add_rrset(SectionType::Answer, RRset::from_str(soa).unwrap());
+        }
+        builder.done();
+        message
+    }
+
+    #[test]
+    fn test_check_notify() {
+        let mut zones = AuthZone::new();
+        zones
+            .add_zone(
+                Name::new("example.org.").unwrap(),
+                "example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400\n\
+                 example.org. 300 IN NS ns.example.org.\n\
+                 ns.example.org. 300 IN A 192.0.2.2",
+            )
+            .unwrap();
+
+        let zone = Name::new("example.org.").unwrap();
+        let request = notify_message("example.org.", RRType::SOA, None);
+        assert_eq!(check_notify(&zones, &request), Ok((zone.clone(), None)));
+        let request = notify_message(
+            "example.org.",
+            RRType::SOA,
+            Some("example.org. 300 IN SOA ns.example.org. root.example.org. 101 1800 900 604800 86400"),
+        );
+        assert_eq!(check_notify(&zones, &request), Ok((zone, Some(101))));
+
+        let request = notify_message("example.org.", RRType::A, None);
+        assert_eq!(check_notify(&zones, &request), Err(Rcode::NotImp));
+        let request = notify_message("www.example.org.", RRType::SOA, None);
+        assert_eq!(check_notify(&zones, &request), Err(Rcode::NotAuth));
+        let request = notify_message("example.com.", RRType::SOA, None);
+        assert_eq!(check_notify(&zones, &request), Err(Rcode::NotAuth));
+    }
+}
@@ -65,16 +65,39 @@ impl Rdataset {
         }
     }
 
+    //any is answered with one representative rrset as rfc8482 suggests
     pub fn get_rrset(&self, name: &Name, typ: RRType) -> Option<RRset> {
-        self.get_rrset_tuple(typ).map(|index| RRset {
+        let index = if typ == RRType::ANY {
+            if self.rrsets.is_empty() {
+                None
+            } else {
+                Some(0)
+            }
+        } else {
+            self.get_rrset_tuple(typ)
+        };
+        index.map(|index| RRset {
             name: name.clone(),
-            typ,
+            typ: self.rrsets[index].0,
             class: RRClass::IN,
             ttl: self.rrsets[index].1,
             rdatas: self.rrsets[index].2.clone(),
         })
     }
 
+    pub fn get_rrsets(&self, name: &Name) -> Vec<RRset> {
+        self.rrsets
+            .iter()
+            .map(|(typ, ttl, rdatas)| RRset {
+                name: name.clone(),
+                typ: *typ,
+                class: RRClass::IN,
+                ttl: *ttl,
+                rdatas: rdatas.clone(),
+            })
+            .collect()
+    }
+
     pub fn delete_rrset(&mut self, typ: RRType) -> Result<()> {
         if let Some(index) = self.get_rrset_tuple(typ) {
             self.rrsets.remove(index);
@@ -164,8 +187,19 @@ mod tests {
         rrset.add_rrset(a_rrset.clone()).unwrap();
         assert_eq!(
             rrset.get_rrset(&Name::new("a.cn").unwrap(), RRType::A),
+            Some(a_rrset.clone())
+        );
+
+        let mx_rrset = RRset::from_str("a.cn 3600 IN MX 10 mail.a.cn").unwrap();
+        rrset.add_rrset(mx_rrset).unwrap();
+        assert_eq!(
+            rrset.get_rrset(&Name::new("a.cn").unwrap(), RRType::ANY),
             Some(a_rrset)
         );
+        assert_eq!(
+            Rdataset::new().get_rrset(&Name::new("a.cn").unwrap(), RRType::ANY),
+            None
+        );
     }
 
     #[test]
@@ -0,0 +1,738 @@
+use super::journal::{is_serial_newer, parse_diffs, soa_serial, Diff};
+use super::memory_zone::MemoryZone;
+use super::notify::NotifyHook;
+use super::zone::{ZoneFinder, ZoneUpdater};
+use super::zones::AuthZone;
+use crate::config::{AuthZoneConfig, AuthorityConfig, ZoneType};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::{future::join_all, StreamExt};
+use r53::{Message, MessageBuilder, MessageRender, Name, RData, RRType, RRset, Rcode, SectionType};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout};
+
+const DEFAULT_PRIMARY_PORT: u16 = 53;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+//used before the zone is transferred, since there is no soa yet
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct SecondaryZone {
+    name: Name,
+    primaries: Vec<SocketAddr>,
+    file_path: PathBuf,
+    journal_file: Option<PathBuf>,
+    max_journal_len: usize,
+    notify_sender: Sender<()>,
+    notify_receiver: Mutex<Option<Receiver<()>>>,
+}
+
+//secondary zones are refreshed by the soa timers and notify from their
+//primaries, each zone is maintained by its own task
+pub struct SecondaryManager {
+    zones: Arc<RwLock<AuthZone>>,
+    secondaries: Vec<Arc<SecondaryZone>>,
+}
+
+impl SecondaryManager {
+    pub fn new(zones: Arc<RwLock<AuthZone>>, conf: &AuthorityConfig) -> Result<Self> {
+        let mut secondaries = Vec::new();
+        for zone_conf in conf.zones.iter() {
+            if zone_conf.zone_type == ZoneType::Secondary {
+                secondaries.push(Arc::new(SecondaryZone::new(zone_conf, conf)?));
+            }
+        }
+        Ok(SecondaryManager { zones, secondaries })
+    }
+
+    pub fn is_secondary(&self, name: &Name) -> bool {
+        self.secondaries.iter().any(|zone| zone.name == *name)
+    }
+
+    //tasks of secondary zones are started with server, so they can't be
+    //added, removed or changed by reload
+    pub fn check_reload(&self, conf: &AuthorityConfig) -> Result<()> {
+        let mut count = 0;
+        for zone_conf in conf.zones.iter() {
+            if zone_conf.zone_type != ZoneType::Secondary {
+                continue;
+            }
+            let zone = SecondaryZone::new(zone_conf, conf)?;
+            ensure!(
+                self.secondaries.iter().any(|s| s.is_same(&zone)),
+                "secondary zone {} changed, restart needed",
+                zone_conf.name
+            );
+            count += 1;
+        }
+        ensure!(
+            count == self.secondaries.len(),
+            "secondary zone removed, restart needed"
+        );
+        Ok(())
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        join_all(
+            self.secondaries
+                .iter()
+                .map(|zone| zone.clone().maintain(self.zones.clone())),
+        )
+        .await;
+    }
+}
+
+impl NotifyHook for SecondaryManager {
+    //notify is only accepted from primaries as rfc1996 suggests
+    fn on_notify(&self, zone: &Name, source: SocketAddr, _serial: Option<u32>) {
+        let secondary = self.secondaries.iter().find(|s| s.name == *zone);
+        if let Some(secondary) = secondary {
+            if secondary
+                .primaries
+                .iter()
+                .any(|primary| primary.ip() == source.ip())
+            {
+                //refresh is already pending if the channel is full
+                let _ = secondary.notify_sender.clone().try_send(());
+            } else {
+                debug!("ignore notify for zone {} from {}", zone, source);
+            }
+        }
+    }
+}
+
+impl SecondaryZone {
+    fn new(zone_conf: &AuthZoneConfig, conf: &AuthorityConfig) -> Result<Self> {
+        let name = Name::new(&zone_conf.name)?;
+        ensure!(
+            !zone_conf.primaries.is_empty(),
+            "secondary zone {} has no primary",
+            zone_conf.name
+        );
+        let mut primaries = Vec::with_capacity(zone_conf.primaries.len());
+        for primary in zone_conf.primaries.iter() {
+            primaries.push(parse_primary(primary)?);
+        }
+        let (notify_sender, notify_receiver) = channel(1);
+        Ok(SecondaryZone {
+            name,
+            primaries,
+            file_path: PathBuf::from(&zone_conf.file_path),
+            journal_file: zone_conf.journal_file.as_ref().map(PathBuf::from),
+            max_journal_len: conf.max_journal_len,
+            notify_sender,
+            notify_receiver: Mutex::new(Some(notify_receiver)),
+        })
+    }
+
+    fn is_same(&self, other: &SecondaryZone) -> bool {
+        self.name == other.name
+            && self.primaries == other.primaries
+            && self.file_path == other.file_path
+            && self.journal_file == other.journal_file
+    }
+
+    //zone loaded from file is treated as refreshed when the file is
+    //saved, it stops answering once it's not refreshed within expire
+    async fn maintain(self: Arc<Self>, zones: Arc<RwLock<AuthZone>>) {
+        let mut notified = match self.notify_receiver.lock().unwrap().take() {
+            Some(receiver) => receiver,
+            None => return,
+        };
+        let mut refreshed_at = self.get_soa(&zones).and_then(|_| self.saved_at());
+        loop {
+            let wait = self.refresh_or_expire(&zones, &mut refreshed_at).await;
+            tokio::select! {
+                _ = delay_for(wait) => {},
+                _ = notified.next() => debug!("refresh zone {} on notify", self.name),
+            }
+        }
+    }
+
+    //return the time to wait before next refresh
+    async fn refresh_or_expire(
+        &self,
+        zones: &RwLock<AuthZone>,
+        refreshed_at: &mut Option<SystemTime>,
+    ) -> Duration {
+        let soa = self.get_soa(zones);
+        match self.refresh(zones).await {
+            Ok(_) => {
+                *refreshed_at = Some(SystemTime::now());
+                to_interval(soa_timers(&self.get_soa(zones)).map(|timers| timers.0))
+            }
+            Err(e) => {
+                warn!("refresh zone {} failed: {:?}", self.name, e);
+                let timers = soa_timers(&soa);
+                let expire = to_interval(timers.map(|timers| timers.2));
+                let expired = refreshed_at.map_or(false, |at| {
+                    at.elapsed().map_or(false, |elapsed| elapsed >= expire)
+                });
+                if expired {
+                    warn!("zone {} expired", self.name);
+                    zones
+                        .write()
+                        .unwrap()
+                        .set_zone(self.name.clone(), Some(MemoryZone::new(self.name.clone())));
+                    *refreshed_at = None;
+                }
+                to_interval(timers.map(|timers| timers.1))
+            }
+        }
+    }
+
+    //zone file is saved on every refresh
+    fn saved_at(&self) -> Option<SystemTime> {
+        fs::metadata(&self.file_path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+
+    fn get_soa(&self, zones: &RwLock<AuthZone>) -> Option<RRset> {
+        zones
+            .read()
+            .unwrap()
+            .get_zone(&self.name)
+            .filter(|zone| *zone.get_origin() == self.name)
+            .and_then(|zone| zone.get_soa())
+    }
+
+    //primaries are tried in order until one of them succeeds
+    async fn refresh(&self, zones: &RwLock<AuthZone>) -> Result<()> {
+        let mut last_error = anyhow!("no primary");
+        for primary in self.primaries.iter() {
+            match self.refresh_from(zones, *primary).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    debug!(
+                        "refresh zone {} from {} failed: {:?}",
+                        self.name, primary, e
+                    );
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    //ixfr is used if zone is loaded already, zone is transferred as a
+    //whole if primary doesn't give the diffs or they can't be applied
+    async fn refresh_from(&self, zones: &RwLock<AuthZone>, primary: SocketAddr) -> Result<()> {
+        let mut stream = timeout(QUERY_TIMEOUT, TcpStream::connect(primary)).await??;
+        let current = self.get_soa(zones);
+        let soa = timeout(QUERY_TIMEOUT, query_soa(&mut stream, &self.name)).await??;
+        let serial = soa_serial(&soa).ok_or_else(|| anyhow!("invalid soa"))?;
+        if let Some(current_serial) = current.as_ref().and_then(soa_serial) {
+            if !is_serial_newer(serial, current_serial) {
+                //file is saved again to keep the refresh time for restart
+                self.save(zones);
+                return Ok(());
+            }
+        }
+
+        let mut rrs = timeout(
+            TRANSFER_TIMEOUT,
+            transfer(&mut stream, &self.name, current.as_ref()),
+        )
+        .await??;
+        if rrs.len() > 2 && rrs[1].typ == RRType::SOA {
+            let last = rrs.len() - 1;
+            let diffs = parse_diffs(rrs.drain(1..last).collect())?;
+            match self.apply_diffs(zones, diffs) {
+                Ok(_) => {
+                    info!(
+                        "zone {} is refreshed to serial {} with ixfr",
+                        self.name, serial
+                    );
+                    self.save(zones);
+                    return Ok(());
+                }
+                Err(e) => warn!("apply ixfr to zone {} failed: {:?}", self.name, e),
+            }
+            rrs.clear();
+        }
+        if rrs.len() < 2 {
+            rrs = timeout(TRANSFER_TIMEOUT, transfer(&mut stream, &self.name, None)).await??;
+        }
+
+        let zone = self.new_zone(rrs)?;
+        zones
+            .write()
+            .unwrap()
+            .set_zone(self.name.clone(), Some(zone));
+        info!(
+            "zone {} is refreshed to serial {} with axfr",
+            self.name, serial
+        );
+        self.save(zones);
+        Ok(())
+    }
+
+    fn apply_diffs(&self, zones: &RwLock<AuthZone>, diffs: Vec<Diff>) -> Result<()> {
+        let mut zone = match zones.read().unwrap().get_zone(&self.name) {
+            Some(zone) => zone.duplicate()?,
+            None => bail!("zone isn't loaded"),
+        };
+        for diff in diffs {
+            ensure!(
+                zone.get_serial() == Some(diff.old_serial()),
+                "diff doesn't match serial"
+            );
+            let names: Vec<Name> = diff
+                .deleted
+                .iter()
+                .chain(diff.added.iter())
+                .map(|rrset| rrset.name.clone())
+                .collect();
+            zone.apply_change(&names, |zone| zone.apply_diff(&diff))?;
+        }
+        if let Err(e) = zone.flush_journal() {
+            warn!("write journal of zone {} failed: {:?}", self.name, e);
+        }
+        zones
+            .write()
+            .unwrap()
+            .set_zone(self.name.clone(), Some(zone));
+        Ok(())
+    }
+
+    //rrs are bracketed by soa
+    fn new_zone(&self, mut rrs: Vec<RRset>) -> Result<MemoryZone> {
+        ensure!(
+            rrs.len() >= 2 && rrs.last() == rrs.first(),
+            "incomplete zone transfer"
+        );
+        rrs.pop();
+        let mut zone = MemoryZone::new(self.name.clone());
+        for rrset in rrs {
+            zone.add_rrset(rrset)?;
+        }
+        //zone file is saved after every refresh
+        zone.open_journal(self.journal_file.as_deref(), None, self.max_journal_len)?;
+        Ok(zone)
+    }
+
+    //zone file is only a backup used by next startup
+    fn save(&self, zones: &RwLock<AuthZone>) {
+        if let Err(e) = zones
+            .read()
+            .unwrap()
+            .snapshot_zone(&self.name, &self.file_path)
+        {
+            warn!("save zone {} failed: {:?}", self.name, e);
+        }
+    }
+}
+
+fn parse_primary(primary: &str) -> Result<SocketAddr> {
+    match primary.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(_) => primary
+            .parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, DEFAULT_PRIMARY_PORT))
+            .with_context(|| format!("invalid primary {}", primary)),
+    }
+}
+
+//refresh, retry and expire of soa
+fn soa_timers(soa: &Option<RRset>) -> Option<(u32, u32, u32)> {
+    match soa.as_ref().and_then(|soa| soa.rdatas.first()) {
+        Some(RData::SOA(soa)) => Some((soa.refresh, soa.retry, soa.expire)),
+        _ => None,
+    }
+}
+
+fn to_interval(timer: Option<u32>) -> Duration {
+    timer.map_or(DEFAULT_RETRY_INTERVAL, |timer| {
+        Duration::from_secs(timer as u64).max(MIN_REFRESH_INTERVAL)
+    })
+}
+
+async fn query_soa(stream: &mut TcpStream, name: &Name) -> Result<RRset> {
+    let request = new_request(name, RRType::SOA, None);
+    send_message(stream, &request).await?;
+    let response = recv_message(stream, &request).await?;
+    response
+        .section(SectionType::Answer)
+        .and_then(|answers| {
+            answers
+                .iter()
+                .find(|rrset| rrset.typ == RRType::SOA && rrset.name == *name)
+        })
+        .cloned()
+        .ok_or_else(|| anyhow!("no soa in response"))
+}
+
+//rrs in all the messages are returned one by one
+async fn transfer(stream: &mut TcpStream, name: &Name, soa: Option<&RRset>) -> Result<Vec<RRset>> {
+    let is_ixfr = soa.is_some();
+    let typ = if is_ixfr { RRType::IXFR } else { RRType::AXFR };
+    let request = new_request(name, typ, soa);
+    send_message(stream, &request).await?;
+    let mut rrs = Vec::new();
+    loop {
+        let response = recv_message(stream, &request).await?;
+        for rrset in response.section(SectionType::Answer).into_iter().flatten() {
+            for rdata in rrset.rdatas.iter() {
+                rrs.push(RRset {
+                    rdatas: vec![rdata.clone()],
+                    ..rrset.clone()
+                });
+            }
+        }
+        if is_transfer_done(&rrs, is_ixfr)? {
+            return Ok(rrs);
+        }
+    }
+}
+
+//transfer ends with the soa it starts with, which also shows up in
+//ixfr answer as the new soa of the last diff
+fn is_transfer_done(rrs: &[RRset], is_ixfr: bool) -> Result<bool> {
+    let first = match rrs.first() {
+        Some(first) if first.typ == RRType::SOA => first,
+        Some(_) => bail!("transfer doesn't start with soa"),
+        None => bail!("empty transfer response"),
+    };
+    if rrs.len() == 1 {
+        //primary has nothing newer or doesn't support ixfr
+        return Ok(is_ixfr);
+    }
+    let soa_count = rrs.iter().filter(|rrset| rrset.typ == RRType::SOA).count();
+    Ok(rrs.last() == Some(first) && soa_count % 2 == 0)
+}
+
+fn new_request(name: &Name, typ: RRType, soa: Option<&RRset>) -> Message {
+    let mut request = Message::with_query(name.clone(), typ);
+    let mut builder = MessageBuilder::new(&mut request);
+    builder.id(rand::random::<u16>());
+    if let Some(soa) = soa {
+        builder.add_rrset(SectionType::Authority, soa.clone());
+    }
+    builder.done();
+    request
+}
+
+async fn send_message(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    let mut render = MessageRender::new();
+    message.to_wire(&mut render)?;
+    let data = render.take_data();
+    stream.write_u16(data.len() as u16).await?;
+    stream.write_all(&data).await?;
+    Ok(())
+}
+
+async fn recv_message(stream: &mut TcpStream, request: &Message) -> Result<Message> {
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    let response = Message::from_wire(&buf)?;
+    ensure!(
+        response.header.id == request.header.id,
+        "response id doesn't match"
+    );
+    ensure!(
+        response.header.rcode == Rcode::NoError,
+        "primary responds with {:?}",
+        response.header.rcode
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthServer;
+    use crate::config::Protocol;
+    use crate::types::Request;
+    use std::env;
+    use std::path::Path;
+    use std::process;
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+
+    const PRIMARY_ZONE: &str =
+        "example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400\n\
+         example.org. 300 IN NS ns.example.org.\n\
+         ns.example.org. 300 IN A 192.0.2.2\n";
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("vanguard2-{}-{}", test, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn authority_config(
+        file_path: &Path,
+        zone_type: ZoneType,
+        primaries: Vec<String>,
+    ) -> AuthorityConfig {
+        AuthorityConfig {
+            zones: vec![AuthZoneConfig {
+                name: "example.org.".to_string(),
+                file_path: file_path.to_str().unwrap().to_string(),
+                allow_transfer: Some(vec!["127.0.0.1".to_string()]),
+                journal_file: None,
+                zone_type,
+                primaries,
+            }],
+            ..AuthorityConfig::default()
+        }
+    }
+
+    fn new_primary(dir: &Path, max_journal_len: usize) -> AuthServer {
+        let primary_file = dir.join("primary.zone");
+        fs::write(&primary_file, PRIMARY_ZONE).unwrap();
+        let mut conf = authority_config(&primary_file, ZoneType::Primary, Vec::new());
+        conf.max_journal_len = max_journal_len;
+        AuthServer::new(&conf)
+    }
+
+    fn add_rrset(primary: &AuthServer, rrset: &str) {
+        let rrset = RRset::from_str(rrset).unwrap();
+        primary
+            .zone_data()
+            .write()
+            .unwrap()
+            .get_exact_zone(&Name::new("example.org.").unwrap())
+            .unwrap()
+            .apply_change(&[rrset.name.clone()], |zone| zone.add_rrset(rrset.clone()))
+            .unwrap();
+    }
+
+    fn zone_serial(zones: &RwLock<AuthZone>) -> Option<u32> {
+        let origin = Name::new("example.org.").unwrap();
+        zones
+            .read()
+            .unwrap()
+            .get_zone(&origin)
+            .and_then(|zone| zone.get_serial())
+    }
+
+    //local primary answers queries and zone transfer over tcp
+    async fn run_primary(primary: AuthServer) -> SocketAddr {
+        let mut listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, client)) = listener.accept().await {
+                let primary = primary.clone();
+                tokio::spawn(async move {
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut buf = vec![0; len as usize];
+                        stream.read_exact(&mut buf).await.unwrap();
+                        let req = Request::new(Message::from_wire(&buf).unwrap(), client)
+                            .with_transport(Protocol::Tcp);
+                        let messages = match req.question().typ {
+                            RRType::AXFR | RRType::IXFR => primary.handle_transfer(&req).collect(),
+                            _ => vec![primary.resolve(&req).unwrap()],
+                        };
+                        for message in messages {
+                            send_message(&mut stream, &message).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_refresh_from_primary() {
+        let dir = temp_dir("refresh");
+        let secondary_file = dir.join("secondary.zone");
+        let primary = new_primary(&dir, 10);
+        let addr = run_primary(primary.clone()).await;
+
+        let conf = authority_config(&secondary_file, ZoneType::Secondary, vec![addr.to_string()]);
+        let secondary = SecondaryZone::new(&conf.zones[0], &conf).unwrap();
+        let zones = RwLock::new(AuthZone::new());
+        let origin = Name::new("example.org.").unwrap();
+        secondary.refresh(&zones).await.unwrap();
+        assert_eq!(
+            zones.read().unwrap().export_zone(&origin).unwrap(),
+            primary.export_zone(&origin).unwrap()
+        );
+
+        //change on primary is transferred with ixfr
+        add_rrset(&primary, "www.example.org. 300 IN A 192.0.2.1");
+        secondary.refresh(&zones).await.unwrap();
+        assert_eq!(zone_serial(&zones), Some(101));
+        assert_eq!(
+            zones.read().unwrap().export_zone(&origin).unwrap(),
+            primary.export_zone(&origin).unwrap()
+        );
+        assert_eq!(
+            fs::read_to_string(&secondary_file).unwrap(),
+            primary.export_zone(&origin).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ixfr_fallback_to_axfr() {
+        let dir = temp_dir("fallback");
+        //primary only keeps the last diff
+        let primary = new_primary(&dir, 1);
+        let addr = run_primary(primary.clone()).await;
+
+        let conf = authority_config(
+            &dir.join("secondary.zone"),
+            ZoneType::Secondary,
+            vec![addr.to_string()],
+        );
+        let secondary = SecondaryZone::new(&conf.zones[0], &conf).unwrap();
+        let zones = RwLock::new(AuthZone::new());
+        let origin = Name::new("example.org.").unwrap();
+        secondary.refresh(&zones).await.unwrap();
+        assert_eq!(zone_serial(&zones), Some(100));
+
+        add_rrset(&primary, "www.example.org. 300 IN A 192.0.2.1");
+        add_rrset(&primary, "mail.example.org. 300 IN A 192.0.2.3");
+        secondary.refresh(&zones).await.unwrap();
+        assert_eq!(zone_serial(&zones), Some(102));
+        assert_eq!(
+            zones.read().unwrap().export_zone(&origin).unwrap(),
+            primary.export_zone(&origin).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_on_notify() {
+        let dir = temp_dir("notify");
+        let primary = new_primary(&dir, 10);
+        let addr = run_primary(primary.clone()).await;
+
+        let conf = authority_config(
+            &dir.join("secondary.zone"),
+            ZoneType::Secondary,
+            vec![addr.to_string()],
+        );
+        let zones = Arc::new(RwLock::new(AuthZone::new()));
+        let manager = Arc::new(SecondaryManager::new(zones.clone(), &conf).unwrap());
+        tokio::spawn(manager.clone().run());
+        let wait_serial = |serial: u32| {
+            let zones = zones.clone();
+            async move {
+                for _ in 0..50 {
+                    if zone_serial(&zones) == Some(serial) {
+                        return true;
+                    }
+                    delay_for(Duration::from_millis(100)).await;
+                }
+                false
+            }
+        };
+        assert!(wait_serial(100).await);
+
+        //refresh interval is far away, only notify triggers the refresh
+        add_rrset(&primary, "www.example.org. 300 IN A 192.0.2.1");
+        let origin = Name::new("example.org.").unwrap();
+        let stranger = "192.0.2.100:53".parse().unwrap();
+        manager.on_notify(&origin, stranger, Some(101));
+        delay_for(Duration::from_millis(500)).await;
+        assert_eq!(zone_serial(&zones), Some(100));
+        manager.on_notify(&origin, addr, Some(101));
+        assert!(wait_serial(101).await);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_and_expire() {
+        let dir = temp_dir("expire");
+        let secondary_file = dir.join("secondary.zone");
+        fs::write(&secondary_file, PRIMARY_ZONE).unwrap();
+        //primary which refuses connection
+        let addr = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let conf = authority_config(&secondary_file, ZoneType::Secondary, vec![addr.to_string()]);
+        let server = AuthServer::new(&conf);
+        let zones = server.zone_data();
+        let secondary = SecondaryZone::new(&conf.zones[0], &conf).unwrap();
+        let origin = Name::new("example.org.").unwrap();
+        let query = || {
+            let req = Request::new(
+                Message::with_query(origin.clone(), RRType::SOA),
+                "127.0.0.1:5353".parse().unwrap(),
+            );
+            server.resolve(&req).unwrap().header.rcode
+        };
+
+        //expire starts from the time zone file is saved
+        let mut refreshed_at = secondary.saved_at();
+        assert!(refreshed_at.is_some());
+        let wait = secondary.refresh_or_expire(&zones, &mut refreshed_at).await;
+        assert_eq!(wait, Duration::from_secs(900));
+        assert_eq!(zone_serial(&zones), Some(100));
+        assert_eq!(query(), Rcode::NoError);
+
+        let mut refreshed_at = SystemTime::now().checked_sub(Duration::from_secs(604800));
+        let wait = secondary.refresh_or_expire(&zones, &mut refreshed_at).await;
+        assert_eq!(wait, Duration::from_secs(900));
+        assert!(refreshed_at.is_none());
+        assert_eq!(zone_serial(&zones), None);
+        assert_eq!(query(), Rcode::ServFail);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_reload() {
+        let dir = temp_dir("reload");
+        let secondary_file = dir.join("secondary.zone");
+        let conf = authority_config(
+            &secondary_file,
+            ZoneType::Secondary,
+            vec!["127.0.0.1:5300".to_string()],
+        );
+        let server = AuthServer::new(&conf);
+        assert!(server.prepare_reload(&conf).is_ok());
+
+        let changed = authority_config(
+            &secondary_file,
+            ZoneType::Secondary,
+            vec!["127.0.0.1:5301".to_string()],
+        );
+        assert!(server.prepare_reload(&changed).is_err());
+        assert!(server.prepare_reload(&AuthorityConfig::default()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_transfer_done() {
+        let soa = RRset::from_str(
+            "example.org. 300 IN SOA ns.example.org. root.example.org. 101 1800 900 604800 86400",
+        )
+        .unwrap();
+        let old_soa = RRset::from_str(
+            "example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400",
+        )
+        .unwrap();
+        let a = RRset::from_str("www.example.org. 300 IN A 192.0.2.1").unwrap();
+
+        assert!(is_transfer_done(&[soa.clone()], true).unwrap());
+        assert!(!is_transfer_done(&[soa.clone()], false).unwrap());
+        assert!(!is_transfer_done(&[soa.clone(), a.clone()], false).unwrap());
+        assert!(is_transfer_done(&[soa.clone(), a.clone(), soa.clone()], false).unwrap());
+        //new soa of the last diff isn't the end
+        let diffs = vec![soa.clone(), old_soa, soa.clone()];
+        assert!(!is_transfer_done(&diffs, true).unwrap());
+        let mut diffs = diffs;
+        diffs.push(a.clone());
+        diffs.push(soa);
+        assert!(is_transfer_done(&diffs, true).unwrap());
+        assert!(is_transfer_done(&[a], false).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use super::journal::{is_serial_newer, soa_serial};
+use super::memory_zone::MemoryZone;
+use super::zone::ZoneFinder;
+use super::zones::AuthZone;
+use r53::{HeaderFlag, Message, MessageBuilder, MessageRender, RRType, RRset, Rcode, SectionType};
+use std::vec;
+
+//below the 64k limit of tcp message, so client gets the zone in
+//pieces it could process while the rest is on the way
+const MAX_TRANSFER_MESSAGE_LEN: usize = 16384;
+
+//axfr defined in rfc5936, the whole zone is sent in several messages
+//and bracketed by soa
+pub fn transfer_zone(zones: &AuthZone, request: &Message) -> Result<TransferMessages, Rcode> {
+    let zone = get_transfer_zone(zones, request)?;
+    let mut rrsets = zone.get_all_rrsets();
+    match rrsets.first() {
+        Some(soa) if soa.typ == RRType::SOA => rrsets.push(soa.clone()),
+        _ => return Err(Rcode::ServFail),
+    }
+    Ok(TransferMessages::new(request, rrsets))
+}
+
+//ixfr defined in rfc1995, client gets the diffs since its serial, and
+//the whole zone if they are no longer kept in journal. udp response
+//only tells client whether it's up to date
+pub fn incremental_transfer_zone(
+    zones: &AuthZone,
+    request: &Message,
+    over_udp: bool,
+) -> Result<TransferMessages, Rcode> {
+    let zone = get_transfer_zone(zones, request)?;
+    let client_serial = request
+        .section(SectionType::Authority)
+        .and_then(|rrsets| rrsets.iter().find(|rrset| rrset.typ == RRType::SOA))
+        .and_then(soa_serial)
+        .ok_or(Rcode::FormErr)?;
+    let soa = zone.get_soa().ok_or(Rcode::ServFail)?;
+    let serial = soa_serial(&soa).ok_or(Rcode::ServFail)?;
+    if over_udp || !is_serial_newer(serial, client_serial) {
+        return Ok(TransferMessages::new(request, vec![soa]));
+    }
+
+    match zone.get_diffs(client_serial) {
+        Some(diffs) => {
+            let mut rrsets = vec![soa.clone()];
+            for diff in diffs {
+                rrsets.push(diff.old_soa);
+                rrsets.extend(diff.deleted);
+                rrsets.push(diff.new_soa);
+                rrsets.extend(diff.added);
+            }
+            rrsets.push(soa);
+            Ok(TransferMessages::new(request, rrsets))
+        }
+        None => transfer_zone(zones, request),
+    }
+}
+
+fn get_transfer_zone<'a>(zones: &'a AuthZone, request: &Message) -> Result<&'a MemoryZone, Rcode> {
+    let question = match request.question {
+        Some(ref question) => question,
+        None => return Err(Rcode::FormErr),
+    };
+    match zones.get_zone(&question.name) {
+        Some(zone) if *zone.get_origin() == question.name => Ok(zone),
+        _ => Err(Rcode::NotAuth),
+    }
+}
+
+//rrsets are copied out of the zone, messages are built one at a time
+//while they are sent, rrset may be split into several messages if it's
+//too large
+pub struct TransferMessages {
+    request: Message,
+    rrsets: vec::IntoIter<RRset>,
+    //rdatas of the current rrset which aren't packed yet, they are
+    //reversed to be popped in order
+    current: Option<RRset>,
+    //rr which doesn't fit into the last message
+    pending: Option<(RRset, usize)>,
+    render: MessageRender,
+    header_len: usize,
+}
+
+impl TransferMessages {
+    fn new(request: &Message, rrsets: Vec<RRset>) -> Self {
+        let mut messages = TransferMessages {
+            request: request.clone(),
+            rrsets: rrsets.into_iter(),
+            current: None,
+            pending: None,
+            render: MessageRender::new(),
+            header_len: 0,
+        };
+        messages.header_len = messages.wire_len(Vec::new());
+        messages
+    }
+
+    fn wire_len(&mut self, answers: Vec<RRset>) -> usize {
+        let _ = transfer_response(&self.request, answers).to_wire(&mut self.render);
+        let len = self.render.data().len();
+        self.render.clear();
+        len
+    }
+
+    //rr is rendered alone to get its length, names may be compressed
+    //more in the real message, so the sum is an upper bound. the flag
+    //tells whether rr starts a new rrset
+    fn next_rr(&mut self) -> Option<(RRset, usize, bool)> {
+        if let Some((rr, len)) = self.pending.take() {
+            return Some((rr, len, true));
+        }
+        let mut is_new = false;
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if let Some(rdata) = current.rdatas.pop() {
+                    let rr = RRset {
+                        name: current.name.clone(),
+                        typ: current.typ,
+                        class: current.class,
+                        ttl: current.ttl,
+                        rdatas: vec![rdata],
+                    };
+                    let len = self.wire_len(vec![rr.clone()]) - self.header_len;
+                    return Some((rr, len, is_new));
+                }
+            }
+            let mut rrset = self.rrsets.next()?;
+            rrset.rdatas.reverse();
+            self.current = Some(rrset);
+            is_new = true;
+        }
+    }
+}
+
+impl Iterator for TransferMessages {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        let mut answers: Vec<RRset> = Vec::new();
+        let mut len = self.header_len;
+        while let Some((rr, rr_len, is_new)) = self.next_rr() {
+            if len + rr_len > MAX_TRANSFER_MESSAGE_LEN && !answers.is_empty() {
+                self.pending = Some((rr, rr_len));
+                break;
+            }
+            len += rr_len;
+            //rrsets aren't merged, since ixfr response has several soa
+            //in sequence
+            match answers.last_mut() {
+                Some(last) if !is_new => last.rdatas.extend(rr.rdatas),
+                _ => answers.push(rr),
+            }
+        }
+        if answers.is_empty() {
+            None
+        } else {
+            Some(transfer_response(&self.request, answers))
+        }
+    }
+}
+
+fn transfer_response(request: &Message, answers: Vec<RRset>) -> Message {
+    let mut response = request.clone();
+    //soa of client is in authority section of ixfr request
+    response.take_section(SectionType::Authority);
+    response.take_section(SectionType::Additional);
+    let mut builder = MessageBuilder::new(&mut response);
+    builder.make_response().set_flag(HeaderFlag::AuthAnswer);
+    for rrset in answers {
+        builder.add_rrset(SectionType::Answer, rrset);
+    }
+    builder.done();
+    response.recalculate_header();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::zone::ZoneUpdater;
+    use r53::Name;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_transfer_zone() {
+        let mut zones = AuthZone::new();
+        zones
+            .add_zone(
+                Name::new("example.org.").unwrap(),
+                "example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400\n\
+                 example.org. 300 IN NS ns.example.org.\n\
+                 ns.example.org. 300 IN A 192.0.2.2\n\
+                 $GENERATE 1-1000 host$ 300 IN A 192.0.2.1",
+            )
+            .unwrap();
+
+        let request = Message::with_query(Name::new("example.org.").unwrap(), RRType::AXFR);
+        let messages: Vec<Message> = transfer_zone(&zones, &request).unwrap().collect();
+        assert!(messages.len() > 1);
+        let answers: Vec<RRset> = messages
+            .iter()
+            .flat_map(|message| message.section(SectionType::Answer).unwrap().clone())
+            .collect();
+        assert_eq!(answers.first().unwrap().typ, RRType::SOA);
+        assert_eq!(answers.last(), answers.first());
+        assert_eq!(
+            answers
+                .iter()
+                .filter(|rrset| rrset.typ == RRType::A)
+                .map(|rrset| rrset.rdatas.len())
+                .sum::<usize>(),
+            1001
+        );
+        for message in messages {
+            assert!(message.header.is_flag_set(HeaderFlag::AuthAnswer));
+            assert_eq!(message.header.rcode, Rcode::NoError);
+            let mut render = MessageRender::new();
+            message.to_wire(&mut render).unwrap();
+            assert!(render.data().len() <= MAX_TRANSFER_MESSAGE_LEN);
+        }
+
+        let request = Message::with_query(Name::new("www.example.org.").unwrap(), RRType::AXFR);
+        assert_eq!(transfer_zone(&zones, &request).err(), Some(Rcode::NotAuth));
+    }
+
+    fn ixfr_request(serial: u32) -> Message {
+        let mut request = Message::with_query(Name::new("example.org.").unwrap(), RRType::IXFR);
+        let soa = RRset::from_str(&format!(
+            "example.org. 300 IN SOA ns.example.org. root.example.org. {} 1800 900 604800 86400",
+            serial
+        ))
+        .unwrap();
+        MessageBuilder::new(&mut request)
+            .add_rrset(SectionType::Authority, soa)
+            .done();
+        request
+    }
+
+    fn answer_serials(messages: TransferMessages) -> Vec<Option<u32>> {
+        messages
+            .flat_map(|message| message.section(SectionType::Answer).unwrap().clone())
+            .map(|rrset| soa_serial(&rrset))
+            .collect()
+    }
+
+    #[test]
+    fn test_incremental_transfer_zone() {
+        let mut zones = AuthZone::new();
+        let origin = Name::new("example.org.").unwrap();
+        zones
+            .add_zone(
+                origin.clone(),
+                "example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400\n\
+                 example.org. 300 IN NS ns.example.org.\n\
+                 ns.example.org. 300 IN A 192.0.2.2",
+            )
+            .unwrap();
+        let www = RRset::from_str("www.example.org. 300 IN A 192.0.2.1").unwrap();
+        let zone = zones.get_exact_zone(&origin).unwrap();
+        zone.apply_change(&[www.name.clone()], |zone| zone.add_rrset(www.clone()))
+            .unwrap();
+
+        let messages = incremental_transfer_zone(&zones, &ixfr_request(100), false).unwrap();
+        assert_eq!(
+            answer_serials(messages),
+            vec![Some(101), Some(100), Some(101), None, Some(101)]
+        );
+
+        //client is up to date or asks over udp
+        let messages = incremental_transfer_zone(&zones, &ixfr_request(101), false).unwrap();
+        assert_eq!(answer_serials(messages), vec![Some(101)]);
+        let messages = incremental_transfer_zone(&zones, &ixfr_request(100), true).unwrap();
+        assert_eq!(answer_serials(messages), vec![Some(101)]);
+
+        //serial unknown to journal falls back to axfr
+        let messages = incremental_transfer_zone(&zones, &ixfr_request(90), false).unwrap();
+        assert_eq!(answer_serials(messages).len(), 5);
+
+        let request = Message::with_query(origin, RRType::IXFR);
+        assert_eq!(
+            incremental_transfer_zone(&zones, &request, false).err(),
+            Some(Rcode::FormErr)
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use super::journal::{is_serial_newer, soa_serial};
+use super::memory_zone::MemoryZone;
+use super::zone::ZoneUpdater;
+use super::zones::AuthZone;
+use r53::{Message, Name, RData, RRClass, RRType, RRset, Rcode, SectionType};
+
+//dynamic update defined in rfc2136, prerequisites are checked and
+//updates are applied while zones are locked, so nobody sees the zone
+//half updated. changes are recorded in zone journal and soa serial is
+//increased unless it's updated explicitly
+pub fn update_zone(zones: &mut AuthZone, request: &Message) -> Rcode {
+    let origin = match request.question {
+        Some(ref question) if question.typ == RRType::SOA => question.name.clone(),
+        _ => return Rcode::FormErr,
+    };
+    let zone = match zones.get_exact_zone(&origin) {
+        Some(zone) => zone,
+        None => return Rcode::NotAuth,
+    };
+
+    let empty = Vec::new();
+    let prerequisites = request.section(SectionType::Answer).unwrap_or(&empty);
+    let updates = request.section(SectionType::Authority).unwrap_or(&empty);
+    if let Err(rcode) = check_prerequisites(zone, &origin, prerequisites) {
+        return rcode;
+    }
+    if let Err(rcode) = prescan(&origin, updates) {
+        return rcode;
+    }
+    let names: Vec<Name> = updates.iter().map(|rrset| rrset.name.clone()).collect();
+    zone.apply_change(&names, |zone| apply_updates(zone, &origin, updates));
+    Rcode::NoError
+}
+
+fn check_prerequisites(
+    zone: &MemoryZone,
+    origin: &Name,
+    prerequisites: &[RRset],
+) -> Result<(), Rcode> {
+    //value dependent prerequisites are compared as whole rrsets
+    let mut expected_rrsets: Vec<RRset> = Vec::new();
+    for rrset in prerequisites {
+        if rrset.ttl.0 != 0 {
+            return Err(Rcode::FormErr);
+        }
+        if !rrset.name.is_subdomain(origin) {
+            return Err(Rcode::NotZone);
+        }
+        let current = zone.get_rrsets(&rrset.name);
+        match rrset.class {
+            RRClass::ANY | RRClass::NONE if !rrset.rdatas.is_empty() => {
+                return Err(Rcode::FormErr);
+            }
+            RRClass::ANY => {
+                if rrset.typ == RRType::ANY {
+                    if current.is_empty() {
+                        return Err(Rcode::NXDomain);
+                    }
+                } else if !current.iter().any(|r| r.typ == rrset.typ) {
+                    return Err(Rcode::NXRRset);
+                }
+            }
+            RRClass::NONE => {
+                if rrset.typ == RRType::ANY {
+                    if !current.is_empty() {
+                        return Err(Rcode::YXDomain);
+                    }
+                } else if current.iter().any(|r| r.typ == rrset.typ) {
+                    return Err(Rcode::YXRRset);
+                }
+            }
+            RRClass::IN => {
+                match expected_rrsets
+                    .iter_mut()
+                    .find(|r| r.name == rrset.name && r.typ == rrset.typ)
+                {
+                    Some(expected) => expected.rdatas.extend(rrset.rdatas.iter().cloned()),
+                    None => expected_rrsets.push(rrset.clone()),
+                }
+            }
+            _ => return Err(Rcode::FormErr),
+        }
+    }
+
+    for expected in expected_rrsets {
+        let matched = zone
+            .get_rrsets(&expected.name)
+            .into_iter()
+            .find(|r| r.typ == expected.typ)
+            .map_or(false, |current| {
+                same_rdatas(&current.rdatas, &expected.rdatas)
+            });
+        if !matched {
+            return Err(Rcode::NXRRset);
+        }
+    }
+    Ok(())
+}
+
+fn same_rdatas(rdatas: &[RData], other: &[RData]) -> bool {
+    rdatas.iter().all(|rdata| other.contains(rdata))
+        && other.iter().all(|rdata| rdatas.contains(rdata))
+}
+
+fn prescan(origin: &Name, updates: &[RRset]) -> Result<(), Rcode> {
+    for rrset in updates {
+        if !rrset.name.is_subdomain(origin) {
+            return Err(Rcode::NotZone);
+        }
+        let valid = match rrset.class {
+            RRClass::IN => !is_meta_type(rrset.typ) && !rrset.rdatas.is_empty(),
+            RRClass::ANY => {
+                rrset.ttl.0 == 0
+                    && rrset.rdatas.is_empty()
+                    && (rrset.typ == RRType::ANY || !is_meta_type(rrset.typ))
+            }
+            RRClass::NONE => {
+                rrset.ttl.0 == 0 && !is_meta_type(rrset.typ) && !rrset.rdatas.is_empty()
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(Rcode::FormErr);
+        }
+    }
+    Ok(())
+}
+
+fn is_meta_type(typ: RRType) -> bool {
+    match typ {
+        RRType::ANY | RRType::AXFR | RRType::IXFR | RRType::OPT => true,
+        _ => false,
+    }
+}
+
+//updates which conflict with the zone are ignored silently as rfc2136
+//requires, soa and ns of zone apex can't be deleted
+fn apply_updates(zone: &mut MemoryZone, origin: &Name, updates: &[RRset]) {
+    for rrset in updates {
+        let is_apex = rrset.name == *origin;
+        let current = zone.get_rrsets(&rrset.name);
+        let current_rrset = current.iter().find(|r| r.typ == rrset.typ);
+        let result = match rrset.class {
+            RRClass::IN if rrset.typ == RRType::SOA => {
+                let is_newer = current_rrset.and_then(soa_serial).map_or(false, |serial| {
+                    soa_serial(rrset).map_or(false, |new| is_serial_newer(new, serial))
+                });
+                if !is_apex || !is_newer {
+                    continue;
+                }
+                zone.add_rrset(rrset.clone())
+            }
+            RRClass::IN => {
+                let has_conflict = if rrset.typ == RRType::CNAME {
+                    current.iter().any(|r| r.typ != RRType::CNAME)
+                } else {
+                    current.iter().any(|r| r.typ == RRType::CNAME)
+                };
+                if has_conflict {
+                    continue;
+                }
+                let mut rrset = rrset.clone();
+                if let Some(current_rrset) = current_rrset {
+                    rrset
+                        .rdatas
+                        .retain(|rdata| !current_rrset.rdatas.contains(rdata));
+                }
+                if rrset.rdatas.is_empty() {
+                    continue;
+                }
+                zone.add_rrset(rrset)
+            }
+            RRClass::ANY => {
+                for typ in current.iter().map(|r| r.typ) {
+                    if (rrset.typ != RRType::ANY && typ != rrset.typ)
+                        || (is_apex && (typ == RRType::SOA || typ == RRType::NS))
+                    {
+                        continue;
+                    }
+                    if let Err(e) = zone.delete_rrset(&rrset.name, typ) {
+                        debug!("ignore update for {}: {}", rrset.name, e);
+                    }
+                }
+                continue;
+            }
+            _ => {
+                let current_rrset = match current_rrset {
+                    Some(current_rrset) if rrset.typ != RRType::SOA => current_rrset,
+                    _ => continue,
+                };
+                let rdatas: Vec<RData> = rrset
+                    .rdatas
+                    .iter()
+                    .filter(|rdata| current_rrset.rdatas.contains(rdata))
+                    .cloned()
+                    .collect();
+                if rdatas.is_empty()
+                    || (is_apex
+                        && rrset.typ == RRType::NS
+                        && rdatas.len() == current_rrset.rdatas.len())
+                {
+                    continue;
+                }
+                zone.delete_rdata(&RRset {
+                    rdatas,
+                    ..current_rrset.clone()
+                })
+            }
+        };
+        if let Err(e) = result {
+            debug!("ignore update for {}: {}", rrset.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::{MessageBuilder, Opcode, RRTtl};
+    use std::str::FromStr;
+
+    fn build_zones() -> AuthZone {
+        let mut zones = AuthZone::new();
+        zones
+            .add_zone(
+                Name::new("example.org.").unwrap(),
+                "example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400\n\
+                 example.org. 300 IN NS ns.example.org.\n\
+                 ns.example.org. 300 IN A 192.0.2.2\n\
+                 www.example.org. 300 IN A 192.0.2.1",
+            )
+            .unwrap();
+        zones
+    }
+
+    fn update_message(zone: &str, prerequisites: Vec<RRset>, updates: Vec<RRset>) -> Message {
+        let mut message = Message::with_query(Name::new(zone).unwrap(), RRType::SOA);
+        let mut builder = MessageBuilder::new(&mut message);
+        builder.opcode(Opcode::Update);
+        for rrset in prerequisites {
+            builder.add_rrset(SectionType::Answer, rrset);
+        }
+        for rrset in updates {
+            builder.add_rrset(SectionType::Authority, rrset);
+        }
+        builder.done();
+        message
+    }
+
+    fn empty_rrset(name: &str, typ: RRType, class: RRClass) -> RRset {
+        RRset {
+            name: Name::new(name).unwrap(),
+            typ,
+            class,
+            ttl: RRTtl(0),
+            rdatas: Vec::new(),
+        }
+    }
+
+    fn get_rrset(zones: &AuthZone, name: &str, typ: RRType) -> Option<RRset> {
+        let name = Name::new(name).unwrap();
+        zones
+            .get_zone(&name)
+            .unwrap()
+            .get_rrsets(&name)
+            .into_iter()
+            .find(|r| r.typ == typ)
+    }
+
+    fn serial(zones: &AuthZone) -> u32 {
+        soa_serial(&get_rrset(zones, "example.org.", RRType::SOA).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_add_and_delete() {
+        let mut zones = build_zones();
+        let add = RRset::from_str("mail.example.org. 300 IN A 192.0.2.10").unwrap();
+        let request = update_message("example.org.", Vec::new(), vec![add.clone()]);
+        assert_eq!(update_zone(&mut zones, &request), Rcode::NoError);
+        assert_eq!(get_rrset(&zones, "mail.example.org.", RRType::A), Some(add));
+        assert_eq!(serial(&zones), 101);
+
+        //name is in use now
+        let request = update_message(
+            "example.org.",
+            vec![empty_rrset("mail.example.org.", RRType::ANY, RRClass::NONE)],
+            vec![RRset::from_str("mail.example.org. 300 IN A 192.0.2.11").unwrap()],
+        );
+        assert_eq!(update_zone(&mut zones, &request), Rcode::YXDomain);
+        assert_eq!(serial(&zones), 101);
+
+        let mut delete = RRset::from_str("www.example.org. 0 IN A 192.0.2.1").unwrap();
+        delete.class = RRClass::NONE;
+        let request = update_message(
+            "example.org.",
+            vec![empty_rrset("www.example.org.", RRType::A, RRClass::ANY)],
+            vec![
+                delete,
+                empty_rrset("example.org.", RRType::NS, RRClass::ANY),
+                empty_rrset("mail.example.org.", RRType::ANY, RRClass::ANY),
+            ],
+        );
+        assert_eq!(update_zone(&mut zones, &request), Rcode::NoError);
+        assert!(get_rrset(&zones, "www.example.org.", RRType::A).is_none());
+        assert!(get_rrset(&zones, "mail.example.org.", RRType::A).is_none());
+        //apex ns is kept
+        assert!(get_rrset(&zones, "example.org.", RRType::NS).is_some());
+        assert_eq!(serial(&zones), 102);
+    }
+
+    #[test]
+    fn test_invalid_update() {
+        let mut zones = build_zones();
+        let request = update_message(
+            "example.com.",
+            Vec::new(),
+            vec![RRset::from_str("www.example.com. 300 IN A 192.0.2.1").unwrap()],
+        );
+        assert_eq!(update_zone(&mut zones, &request), Rcode::NotAuth);
+
+        let request = update_message(
+            "example.org.",
+            Vec::new(),
+            vec![RRset::from_str("www.example.com. 300 IN A 192.0.2.1").unwrap()],
+        );
+        assert_eq!(update_zone(&mut zones, &request), Rcode::NotZone);
+
+        let request = update_message(
+            "example.org.",
+            vec![RRset::from_str("www.example.org. 0 IN A 192.0.2.2").unwrap()],
+            Vec::new(),
+        );
+        assert_eq!(update_zone(&mut zones, &request), Rcode::NXRRset);
+
+        let mut delete = RRset::from_str("www.example.org. 300 IN A 192.0.2.1").unwrap();
+        delete.class = RRClass::NONE;
+        let request = update_message("example.org.", Vec::new(), vec![delete]);
+        assert_eq!(update_zone(&mut zones, &request), Rcode::FormErr);
+        assert_eq!(serial(&zones), 100);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::auth::memory_zone::MemoryZone;
+use anyhow::{Context, Result};
+use r53::RRset;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+//one rr per line with absolute names, so the output could be loaded
+//again without knowing the origin
+pub fn export_zone(zone: &MemoryZone) -> String {
+    let mut content = String::new();
+    write_rrsets(&mut content, &zone.get_all_rrsets());
+    content
+}
+
+pub fn write_rrsets(content: &mut String, rrsets: &[RRset]) {
+    for rrset in rrsets {
+        for rdata in rrset.rdatas.iter() {
+            let _ = writeln!(
+                content,
+                "{}\t{}\t{}\t{}\t{}",
+                rrset.name, rrset.ttl.0, rrset.class, rrset.typ, rdata
+            );
+        }
+    }
+}
+
+pub fn snapshot_zone(zone: &MemoryZone, path: &Path) -> Result<()> {
+    write_file(path, &export_zone(zone))
+}
+
+//content is written to a temporary file first, so the old one is kept
+//intact if anything goes wrong
+pub fn write_file(path: &Path, content: &str) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)
+        .with_context(|| format!("write {} failed", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("rename to {} failed", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::zone_loader::load_zone;
+    use r53::Name;
+
+    #[test]
+    fn test_export_zone() {
+        let origin = Name::new("example.org.").unwrap();
+        let zone = load_zone(
+            origin.clone(),
+            "$TTL 300\n\
+             www A 192.0.2.1\n\
+             @ NS ns\n\
+             \x20 SOA ns root 100 1800 900 604800 86400\n\
+             ns A 192.0.2.2\n\
+             a.www TXT \"vanguard2\"\n\
+             \x20 A 192.0.2.3\n",
+            None,
+        )
+        .unwrap();
+        let content = export_zone(&zone);
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "example.org.\t300\tIN\tSOA\tns.example.org. root.example.org. 100 1800 900 604800 86400",
+                "example.org.\t300\tIN\tNS\tns.example.org.",
+                "ns.example.org.\t300\tIN\tA\t192.0.2.2",
+                "www.example.org.\t300\tIN\tA\t192.0.2.1",
+                "a.www.example.org.\t300\tIN\tA\t192.0.2.3",
+                "a.www.example.org.\t300\tIN\tTXT\t\"vanguard2\"",
+            ]
+        );
+
+        let reloaded = load_zone(origin, &content, None).unwrap();
+        assert_eq!(export_zone(&reloaded), content);
+    }
+}
@@ -1,26 +1,647 @@
 use crate::auth::memory_zone::MemoryZone;
 use crate::auth::zone::ZoneUpdater;
-use anyhow::Result;
-use r53::{Name, RRset};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use r53::{Name, RData, RRClass, RRTtl, RRType, RRset};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-pub fn load_zone(name: Name, content: &str) -> Result<MemoryZone> {
-    let mut zone = MemoryZone::new(name);
-    for line in content.lines() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
-        match RRset::from_str(line) {
-            Ok(rrset) => zone.add_rrset(rrset)?,
-            Err(e) => {
-                if e.to_string().find("support").is_none() {
-                    return Err(e);
+//included file may include others, limit the depth to break loops
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+//master file format defined in rfc1035, relative $INCLUDE is resolved
+//against the directory of the including file, $INCLUDE is refused if
+//content isn't read from file
+pub fn load_zone(name: Name, content: &str, path: Option<&Path>) -> Result<MemoryZone> {
+    let mut zone = MemoryZone::new(name.clone());
+    let mut parser = ZoneParser::new();
+    parser.parse(content, path, name.to_string(), 0, &mut |rrset| {
+        zone.add_rrset(rrset)
+    })?;
+    Ok(zone)
+}
+
+//rrs in content are returned one by one in the original order
+pub fn parse_rrs(origin: &Name, content: &str, path: Option<&Path>) -> Result<Vec<RRset>> {
+    let mut rrs = Vec::new();
+    let mut parser = ZoneParser::new();
+    parser.parse(content, path, origin.to_string(), 0, &mut |rr| {
+        rrs.push(rr);
+        Ok(())
+    })?;
+    Ok(rrs)
+}
+
+type RRsetHandler<'a> = dyn FnMut(RRset) -> Result<()> + 'a;
+
+//rr with unknown type is skipped, any other error fails the loading
+#[derive(Debug)]
+struct UnknownType(String);
+
+impl fmt::Display for UnknownType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown rr type {}", self.0)
+    }
+}
+
+impl Error for UnknownType {}
+
+//one logical record, which may span several lines with parentheses
+struct Entry {
+    line: usize,
+    //owner is omitted if the record starts with blank
+    has_owner: bool,
+    tokens: Vec<String>,
+}
+
+struct ZoneParser {
+    //set by $TTL
+    default_ttl: Option<u32>,
+    //without $TTL, rr omitting ttl uses the last one specified
+    last_ttl: Option<u32>,
+}
+
+impl ZoneParser {
+    fn new() -> Self {
+        ZoneParser {
+            default_ttl: None,
+            last_ttl: None,
+        }
+    }
+
+    //origin and owner inherited are local to each file
+    fn parse(
+        &mut self,
+        content: &str,
+        path: Option<&Path>,
+        mut origin: String,
+        depth: usize,
+        handler: &mut RRsetHandler,
+    ) -> Result<()> {
+        let entries = tokenize(content, path)?;
+
+        let mut last_owner = None;
+        for entry in entries {
+            match self.parse_entry(&entry, path, &mut origin, &mut last_owner, depth, handler) {
+                Ok(_) => {}
+                Err(e) if e.is::<UnknownType>() => {
+                    warn!("{}: {}, rr is skipped", location(path, entry.line), e)
+                }
+                Err(e) => bail!("{}: {:#}", location(path, entry.line), e),
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_entry(
+        &mut self,
+        entry: &Entry,
+        path: Option<&Path>,
+        origin: &mut String,
+        last_owner: &mut Option<String>,
+        depth: usize,
+        handler: &mut RRsetHandler,
+    ) -> Result<()> {
+        let tokens = &entry.tokens;
+        match tokens[0].to_uppercase().as_ref() {
+            "$ORIGIN" => {
+                ensure!(tokens.len() == 2, "$ORIGIN should have one domain name");
+                *origin = absolute_name(&tokens[1], origin)?;
+            }
+            "$TTL" => {
+                ensure!(tokens.len() == 2, "$TTL should have one ttl");
+                self.default_ttl = Some(parse_ttl(&tokens[1])?);
+            }
+            "$INCLUDE" => {
+                ensure!(
+                    tokens.len() == 2 || tokens.len() == 3,
+                    "$INCLUDE should have file name and optional origin"
+                );
+                ensure!(depth < MAX_INCLUDE_DEPTH, "too many nested $INCLUDE");
+                let path = match path {
+                    Some(path) => path,
+                    None => bail!("$INCLUDE is only allowed in zone file"),
+                };
+                let file = include_path(&tokens[1], path);
+                let content = fs::read_to_string(&file)
+                    .with_context(|| format!("read {} failed", file.display()))?;
+                let include_origin = match tokens.get(2) {
+                    Some(name) => absolute_name(name, origin)?,
+                    None => origin.clone(),
+                };
+                self.parse(&content, Some(&file), include_origin, depth + 1, handler)?;
+            }
+            "$GENERATE" => self.generate(&tokens[1..], origin, handler)?,
+            directive if directive.starts_with('$') => bail!("unknown directive {}", tokens[0]),
+            _ => {
+                let (owner, fields) = if entry.has_owner {
+                    let owner = absolute_name(&tokens[0], origin)?;
+                    *last_owner = Some(owner.clone());
+                    (owner, &tokens[1..])
                 } else {
-                    warn!("rr {} isn't support", line);
+                    match last_owner {
+                        Some(owner) => (owner.clone(), &tokens[..]),
+                        None => bail!("owner name is missing"),
+                    }
+                };
+                handler(self.parse_rr(&owner, fields, origin)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    //[ttl] [class] type rdata, ttl and class may be in either order
+    fn parse_rr(&mut self, owner: &str, fields: &[String], origin: &str) -> Result<RRset> {
+        let mut ttl = None;
+        let mut index = 0;
+        while index < fields.len() && index < 2 {
+            let field = &fields[index];
+            if ttl.is_none() && field.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(field)?);
+            } else if is_class(field) {
+                ensure!(
+                    field.eq_ignore_ascii_case("IN"),
+                    "class {} isn't supported",
+                    field
+                );
+            } else {
+                break;
+            }
+            index += 1;
+        }
+
+        let typ = match fields.get(index) {
+            Some(typ) => typ,
+            None => bail!("rr type is missing"),
+        };
+        let typ = match RRType::from_str(&typ.to_uppercase()) {
+            Ok(typ) => typ,
+            Err(_) => return Err(UnknownType(typ.clone()).into()),
+        };
+        if ttl.is_some() {
+            self.last_ttl = ttl;
+        }
+        let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+            Some(ttl) => ttl,
+            None => bail!("ttl is missing"),
+        };
+
+        let rdata = &fields[index + 1..];
+        ensure!(!rdata.is_empty(), "rdata is missing");
+        let rdata = qualify_rdata(typ, rdata, origin)?;
+        let rdata = RData::from_str(typ, &rdata)
+            .with_context(|| format!("invalid {} rdata {}", typ, rdata))?;
+        Ok(RRset {
+            name: Name::new(owner)?,
+            typ,
+            class: RRClass::IN,
+            ttl: RRTtl(ttl),
+            rdatas: vec![rdata],
+        })
+    }
+
+    //$GENERATE start-stop[/step] lhs [ttl] [class] type rhs
+    fn generate(
+        &mut self,
+        fields: &[String],
+        origin: &str,
+        handler: &mut RRsetHandler,
+    ) -> Result<()> {
+        ensure!(
+            fields.len() >= 4,
+            "$GENERATE should have range, owner, type and rdata"
+        );
+        let (start, stop, step) = parse_range(&fields[0])?;
+        let lhs = &fields[1];
+        let rhs = &fields[fields.len() - 1];
+        for value in (start..=stop).step_by(step as usize) {
+            let owner = absolute_name(&substitute(lhs, value)?, origin)?;
+            let mut rr_fields = fields[2..fields.len() - 1].to_vec();
+            rr_fields.push(substitute(rhs, value)?);
+            handler(self.parse_rr(&owner, &rr_fields, origin)?)?;
+        }
+        Ok(())
+    }
+}
+
+fn location(path: Option<&Path>, line: usize) -> String {
+    match path {
+        Some(path) => format!("{}:{}", path.display(), line),
+        None => format!("line {}", line),
+    }
+}
+
+//comments are dropped, quoted string is kept as one token with quotes
+//and escapes, so rdata could be parsed as it is
+fn tokenize(content: &str, path: Option<&Path>) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut pending: Option<Entry> = None;
+    let mut depth = 0;
+    for (i, line) in content.lines().enumerate() {
+        let line_num = i + 1;
+        let mut entry = pending.take().unwrap_or_else(|| Entry {
+            line: line_num,
+            has_owner: !line.starts_with(|c: char| c.is_whitespace()),
+            tokens: Vec::new(),
+        });
+        let mut token = String::new();
+        let mut quoted = false;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    token.push(c);
+                    if let Some(c) = chars.next() {
+                        token.push(c);
+                    }
+                }
+                '"' => {
+                    token.push(c);
+                    quoted = !quoted;
+                }
+                _ if quoted => token.push(c),
+                ';' => break,
+                '(' => {
+                    depth += 1;
+                    push_token(&mut entry.tokens, &mut token);
+                }
+                ')' => {
+                    ensure!(
+                        depth > 0,
+                        "{}: unbalanced parentheses",
+                        location(path, line_num)
+                    );
+                    depth -= 1;
+                    push_token(&mut entry.tokens, &mut token);
                 }
+                _ if c.is_whitespace() => push_token(&mut entry.tokens, &mut token),
+                _ => token.push(c),
             }
         }
+        ensure!(
+            !quoted,
+            "{}: unterminated quoted string",
+            location(path, line_num)
+        );
+        push_token(&mut entry.tokens, &mut token);
+
+        if depth > 0 {
+            pending = Some(entry);
+        } else if !entry.tokens.is_empty() {
+            entries.push(entry);
+        }
+    }
+    if let Some(entry) = pending {
+        bail!("{}: unbalanced parentheses", location(path, entry.line));
+    }
+    Ok(entries)
+}
+
+fn push_token(tokens: &mut Vec<String>, token: &mut String) {
+    if !token.is_empty() {
+        tokens.push(token.split_off(0));
+    }
+}
+
+fn absolute_name(name: &str, origin: &str) -> Result<String> {
+    let name = if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') && !name.ends_with("\\.") {
+        name.to_string()
+    } else if origin == "." {
+        format!("{}.", name)
+    } else {
+        format!("{}.{}", name, origin)
+    };
+    Name::new(&name)?;
+    Ok(name)
+}
+
+fn include_path(file: &str, path: &Path) -> PathBuf {
+    let file = Path::new(file.trim_matches('"'));
+    match path.parent() {
+        Some(dir) if file.is_relative() => dir.join(file),
+        _ => file.to_path_buf(),
+    }
+}
+
+fn is_class(field: &str) -> bool {
+    let field = field.to_uppercase();
+    ["IN", "CH", "CS", "HS"].contains(&field.as_ref()) || field.starts_with("CLASS")
+}
+
+//ttl is in seconds or with units like 1h30m
+fn parse_ttl(s: &str) -> Result<u32> {
+    ensure!(!s.is_empty(), "ttl is empty");
+    let max = u32::max_value() as u64;
+    let mut ttl = 0u64;
+    let mut value: Option<u64> = None;
+    for c in s.chars() {
+        match c.to_digit(10) {
+            Some(digit) => value = Some(value.unwrap_or(0) * 10 + digit as u64),
+            None => {
+                let unit = match c.to_ascii_lowercase() {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86400,
+                    'w' => 604_800,
+                    _ => bail!("invalid ttl {}", s),
+                };
+                match value.take() {
+                    Some(value) => ttl += value * unit,
+                    None => bail!("invalid ttl {}", s),
+                }
+            }
+        }
+        ensure!(
+            ttl <= max && value.unwrap_or(0) <= max,
+            "ttl {} is too large",
+            s
+        );
+    }
+    let ttl = ttl + value.unwrap_or(0);
+    ensure!(ttl <= max, "ttl {} is too large", s);
+    Ok(ttl as u32)
+}
+
+//names in rdata may be relative too, and soa timers may have units
+fn qualify_rdata(typ: RRType, fields: &[String], origin: &str) -> Result<String> {
+    let name_fields: &[usize] = match typ {
+        RRType::NS | RRType::CNAME | RRType::PTR => &[0],
+        RRType::MX => &[1],
+        RRType::SOA => &[0, 1],
+        RRType::SRV => &[3],
+        RRType::NAPTR => &[5],
+        _ => &[],
+    };
+    let mut qualified = Vec::with_capacity(fields.len());
+    for (i, field) in fields.iter().enumerate() {
+        if name_fields.contains(&i) {
+            qualified.push(absolute_name(field, origin)?);
+        } else if typ == RRType::SOA && i > 2 {
+            qualified.push(parse_ttl(field)?.to_string());
+        } else {
+            qualified.push(field.clone());
+        }
+    }
+    Ok(qualified.join(" "))
+}
+
+fn parse_range(range: &str) -> Result<(u32, u32, u32)> {
+    let invalid = || anyhow!("invalid range {}", range);
+    let (bounds, step) = match range.find('/') {
+        Some(i) => (&range[..i], range[i + 1..].parse().map_err(|_| invalid())?),
+        None => (range, 1),
+    };
+    let i = bounds.find('-').ok_or_else(invalid)?;
+    let start: u32 = bounds[..i].parse().map_err(|_| invalid())?;
+    let stop: u32 = bounds[i + 1..].parse().map_err(|_| invalid())?;
+    ensure!(start <= stop && step > 0, "invalid range {}", range);
+    Ok((start, stop, step))
+}
+
+//$ is replaced by the iterator, ${offset[,width[,base]]} formats it and
+//\$ is a literal $
+fn substitute(template: &str, value: u32) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => {
+                chars.next();
+                result.push('$');
+            }
+            '$' if chars.peek() == Some(&'{') => {
+                chars.next();
+                let mut modifier = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => modifier.push(c),
+                        None => bail!("unterminated modifier in {}", template),
+                    }
+                }
+                result.push_str(&format_value(value, &modifier)?);
+            }
+            '$' => result.push_str(&value.to_string()),
+            _ => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+fn format_value(value: u32, modifier: &str) -> Result<String> {
+    let invalid = || anyhow!("invalid modifier {}", modifier);
+    let fields: Vec<&str> = modifier.split(',').collect();
+    ensure!(fields.len() <= 3, "invalid modifier {}", modifier);
+    let offset: i64 = fields[0].parse().map_err(|_| invalid())?;
+    let width: usize = match fields.get(1) {
+        Some(width) => width.parse().map_err(|_| invalid())?,
+        None => 0,
+    };
+    let value = value as i64 + offset;
+    ensure!(value >= 0, "generated value {} is negative", value);
+    let value = match fields.get(2).cloned().unwrap_or("d") {
+        "d" => format!("{:0width$}", value, width = width),
+        "o" => format!("{:0width$o}", value, width = width),
+        "x" => format!("{:0width$x}", value, width = width),
+        "X" => format!("{:0width$X}", value, width = width),
+        base => bail!("base {} isn't supported", base),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn parse(content: &str, path: Option<&Path>) -> Result<Vec<RRset>> {
+        let mut rrsets = Vec::new();
+        ZoneParser::new().parse(content, path, "example.org.".to_string(), 0, &mut |rrset| {
+            rrsets.push(rrset);
+            Ok(())
+        })?;
+        Ok(rrsets)
+    }
+
+    fn rrset(s: &str) -> RRset {
+        RRset::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("300").unwrap(), 300);
+        assert_eq!(parse_ttl("1h30m").unwrap(), 5400);
+        assert_eq!(parse_ttl("1W2d").unwrap(), 777_600);
+        assert_eq!(parse_ttl("4294967295").unwrap(), u32::max_value());
+        for ttl in &["", "h", "1x", "1hh", "4294967296", "99999999999999999999"] {
+            assert!(parse_ttl(ttl).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let entries = tokenize(
+            "@ IN SOA ns ( ; primary\n\
+             \x20 1 2 ) ; serial\n\
+             \n\
+             ; comment only\n\
+             \x20 TXT \"a;b\" \"c\\\" d\"\n",
+            None,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 1);
+        assert!(entries[0].has_owner);
+        assert_eq!(entries[0].tokens, vec!["@", "IN", "SOA", "ns", "1", "2"]);
+        assert_eq!(entries[1].line, 5);
+        assert!(!entries[1].has_owner);
+        assert_eq!(entries[1].tokens, vec!["TXT", "\"a;b\"", "\"c\\\" d\""]);
+
+        assert!(tokenize("a ( b\nc", None).is_err());
+        assert!(tokenize("a b )", None).is_err());
+        assert!(tokenize("a \"b", None).is_err());
+    }
+
+    #[test]
+    fn test_parse_master_file() {
+        let rrsets = parse(
+            "$TTL 1h\n\
+             @ IN SOA ns admin (\n\
+             \x20   2020010101 ; serial\n\
+             \x20   1h 15m 1w 1d )\n\
+             \x20 NS ns\n\
+             ns 300 A 192.0.2.2\n\
+             \x20 IN 600 AAAA 2001:db8::2\n\
+             $ORIGIN sub\n\
+             www CNAME @\n\
+             mail.example.org. MX 10 www\n",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            rrsets,
+            vec![
+                rrset("example.org. 3600 IN SOA ns.example.org. admin.example.org. 2020010101 3600 900 604800 86400"),
+                rrset("example.org. 3600 IN NS ns.example.org."),
+                rrset("ns.example.org. 300 IN A 192.0.2.2"),
+                rrset("ns.example.org. 600 IN AAAA 2001:db8::2"),
+                rrset("www.sub.example.org. 3600 IN CNAME sub.example.org."),
+                rrset("mail.example.org. 3600 IN MX 10 www.sub.example.org."),
+            ]
+        );
+
+        //without $TTL, the last ttl is used
+        let rrsets = parse("a 300 A 192.0.2.1\nb A 192.0.2.2", None).unwrap();
+        assert_eq!(rrsets[1], rrset("b.example.org. 300 IN A 192.0.2.2"));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        for (content, error) in vec![
+            ("a 300 A 192.0.2.1\n\nb A", "line 3: rdata is missing"),
+            ("a A 192.0.2.1", "line 1: ttl is missing"),
+            ("\x20 300 A 192.0.2.1", "line 1: owner name is missing"),
+            (
+                "$TTL 300\na CH TXT \"x\"",
+                "line 2: class CH isn't supported",
+            ),
+            (
+                "$TTL 300\n$ORIGIN",
+                "line 2: $ORIGIN should have one domain name",
+            ),
+            ("$UNKNOWN x", "line 1: unknown directive $UNKNOWN"),
+            ("$TTL 300\n(\na A 1.1.1.1", "line 2: unbalanced parentheses"),
+            (
+                "$INCLUDE /etc/passwd",
+                "line 1: $INCLUDE is only allowed in zone file",
+            ),
+        ] {
+            assert_eq!(parse(content, None).unwrap_err().to_string(), error);
+        }
+
+        //rr with unknown type is skipped, bad rdata isn't
+        let rrsets = parse("$TTL 300\na UNKNOWN x\nb A 192.0.2.1", None).unwrap();
+        assert_eq!(rrsets, vec![rrset("b.example.org. 300 IN A 192.0.2.1")]);
+        let error = parse("$TTL 300\na A 192.0.2.1\nb A x", None).unwrap_err();
+        assert!(error.to_string().starts_with("line 3: invalid A rdata x"));
+    }
+
+    #[test]
+    fn test_parse_relative_rdata() {
+        //names without trailing dot are relative to origin even if every
+        //owner is absolute
+        let rrsets = parse(
+            "example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400\n\
+             example.org. 300 IN NS ns\n\
+             cname.example.org. 300 IN CNAME canonical.example.org\n",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            rrsets,
+            vec![
+                rrset("example.org. 300 IN SOA ns.example.org. root.example.org. 100 1800 900 604800 86400"),
+                rrset("example.org. 300 IN NS ns.example.org."),
+                rrset("cname.example.org. 300 IN CNAME canonical.example.org.example.org."),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate() {
+        assert_eq!(substitute("host-$", 7).unwrap(), "host-7");
+        assert_eq!(substitute("${10,3}.\\$", 7).unwrap(), "017.$");
+        assert_eq!(substitute("${0,4,x}", 255).unwrap(), "00ff");
+        assert!(substitute("${-8}", 7).is_err());
+        assert!(substitute("${1", 7).is_err());
+        assert_eq!(parse_range("1-10/3").unwrap(), (1, 10, 3));
+        assert!(parse_range("10-1").is_err());
+
+        let rrsets = parse("$GENERATE 1-5/2 host$ 300 A 192.0.2.$", None).unwrap();
+        assert_eq!(
+            rrsets,
+            vec![
+                rrset("host1.example.org. 300 IN A 192.0.2.1"),
+                rrset("host3.example.org. 300 IN A 192.0.2.3"),
+                rrset("host5.example.org. 300 IN A 192.0.2.5"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = env::temp_dir().join(format!("vanguard2-zone-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("hosts.inc"),
+            "www A 192.0.2.1\n\x20 AAAA 2001:db8::1",
+        )
+        .unwrap();
+        fs::write(dir.join("loop.inc"), "$INCLUDE loop.inc").unwrap();
+
+        let zone_file = dir.join("example.org.zone");
+        let rrsets = parse(
+            "$TTL 300\nmail A 192.0.2.2\n$INCLUDE hosts.inc sub\n\x20 AAAA 2001:db8::2",
+            Some(&zone_file),
+        );
+        let error = parse("$INCLUDE loop.inc", Some(&zone_file));
+        fs::remove_dir_all(&dir).unwrap();
+
+        //origin and owner are restored after include
+        assert_eq!(
+            rrsets.unwrap(),
+            vec![
+                rrset("mail.example.org. 300 IN A 192.0.2.2"),
+                rrset("www.sub.example.org. 300 IN A 192.0.2.1"),
+                rrset("www.sub.example.org. 300 IN AAAA 2001:db8::1"),
+                rrset("mail.example.org. 300 IN AAAA 2001:db8::2"),
+            ]
+        );
+        assert!(error.is_err());
     }
-    Ok(zone)
 }
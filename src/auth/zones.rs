@@ -1,10 +1,12 @@
 use crate::auth::memory_zone::MemoryZone;
 use crate::auth::zone::{FindOption, FindResult, FindResultType, ZoneFinder};
+use crate::auth::zone_exporter::{export_zone, snapshot_zone};
 use crate::auth::zone_loader::load_zone;
-use crate::types::Request;
+use crate::types::{error_response, Request};
 use anyhow::{bail, ensure, Result};
 use domaintree::{DomainTree, FindResultFlag};
 use r53::{HeaderFlag, Message, MessageBuilder, Name, RRType, Rcode, SectionType};
+use std::path::Path;
 
 pub struct AuthZone {
     zones: DomainTree<MemoryZone>,
@@ -22,11 +24,21 @@ impl AuthZone {
             bail!("duplicate zone {}", name.to_string());
         }
 
-        let zone = load_zone(name.clone(), zone_content)?;
+        let zone = load_zone(name.clone(), zone_content, None)?;
         self.zones.insert(name, Some(zone));
         Ok(())
     }
 
+    //replace or delete zone, which is already loaded
+    pub fn set_zone(&mut self, name: Name, zone: Option<MemoryZone>) {
+        if self.get_exact_zone(&name).is_some() {
+            self.delete_zone(&name).unwrap();
+        }
+        if zone.is_some() {
+            self.zones.insert(name, zone);
+        }
+    }
+
     pub fn delete_zone(&mut self, name: &Name) -> Result<()> {
         let result = self.zones.find(name);
         ensure!(
@@ -47,6 +59,10 @@ impl AuthZone {
         }
 
         let zone = zone.unwrap();
+        //secondary zone which isn't transferred yet or has expired
+        if zone.get_soa().is_none() {
+            return Some(error_response(&req.request, Rcode::ServFail));
+        }
         let mut result = zone.find(&question.name, question.typ, FindOption::FollowZoneCut);
 
         let query_type = question.typ;
@@ -93,6 +109,20 @@ impl AuthZone {
         Some(response)
     }
 
+    pub fn export_zone(&self, name: &Name) -> Result<String> {
+        match self.get_zone(name) {
+            Some(zone) if zone.get_origin() == name => Ok(export_zone(zone)),
+            _ => bail!("unknown zone {}", name),
+        }
+    }
+
+    pub fn snapshot_zone(&self, name: &Name, path: &Path) -> Result<()> {
+        match self.get_zone(name) {
+            Some(zone) if zone.get_origin() == name => snapshot_zone(zone, path),
+            _ => bail!("unknown zone {}", name),
+        }
+    }
+
     pub fn get_zone<'a>(&'a self, name: &Name) -> Option<&'a MemoryZone> {
         let result = self.zones.find(&name);
         result.get_value()
@@ -1,10 +1,16 @@
-use anyhow;
+use anyhow::{self, bail};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::prelude::*, path::Path};
+use std::{
+    fmt,
+    fs::File,
+    io::prelude::*,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
 
 const DEFAULT_MESSAGE_CACHE_SIZE: usize = 10240;
 
-#[derive(Default, Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct VanguardConfig {
     #[serde(default)]
     pub server: ServerConfig,
@@ -18,6 +24,62 @@ pub struct VanguardConfig {
     pub controller: ControllerConfig,
     #[serde(default)]
     pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub views: Vec<ViewConfig>,
+    #[serde(default)]
+    pub query_log: Option<QueryLogConfig>,
+    #[serde(default)]
+    pub dnstap: Option<DnstapConfig>,
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    //stages query goes through in order
+    #[serde(default = "default_pipeline")]
+    pub pipeline: Vec<Stage>,
+}
+
+impl Default for VanguardConfig {
+    fn default() -> Self {
+        VanguardConfig {
+            server: ServerConfig::default(),
+            auth: AuthorityConfig::default(),
+            recursor: RecursorConfig::default(),
+            forwarder: ForwarderConfig::default(),
+            controller: ControllerConfig::default(),
+            metrics: MetricsConfig::default(),
+            acl: AclConfig::default(),
+            views: Vec::new(),
+            query_log: None,
+            dnstap: None,
+            identity: IdentityConfig::default(),
+            pipeline: default_pipeline(),
+        }
+    }
+}
+
+//forwarding is done by recursion stage, since forward zones are
+//consulted by the iterator before the delegations in cache. rate
+//limiting isn't a stage, it stays in the server layer to cover the
+//responses made by server itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Acl,
+    Chaos,
+    Auth,
+    Cache,
+    Recursion,
+}
+
+fn default_pipeline() -> Vec<Stage> {
+    vec![
+        Stage::Acl,
+        Stage::Chaos,
+        Stage::Auth,
+        Stage::Cache,
+        Stage::Recursion,
+    ]
 }
 
 impl VanguardConfig {
@@ -26,63 +88,472 @@ impl VanguardConfig {
         let mut file = File::open(path)?;
         let mut config_string = String::new();
         file.read_to_string(&mut config_string)?;
-        let config = serde_yaml::from_str(&config_string)?;
+        VanguardConfig::from_yaml(&config_string)
+    }
+
+    fn from_yaml(content: &str) -> anyhow::Result<Self> {
+        let mut config: VanguardConfig = serde_yaml::from_str(content)?;
+        config.server.convert_address()?;
+        config.validate()?;
         Ok(config)
     }
+
+    //errors which can't be expressed by serde are checked here, so bad
+    //config is refused by both startup and reload
+    fn validate(&self) -> anyhow::Result<()> {
+        for address in self.recursor.tcp_only_servers.iter() {
+            if address.parse::<IpAddr>().is_err() {
+                bail!("invalid tcp only server {}", address);
+            }
+        }
+        for listener in self.server.listeners.iter() {
+            if listener.cert_path.is_some() != listener.key_path.is_some() {
+                bail!("listener on {} needs both cert and key", listener.address);
+            }
+        }
+        //local address of udp socket bound to wildcard address isn't the
+        //one query is sent to, so destinations can't be matched
+        if self
+            .views
+            .iter()
+            .any(|view| view.match_destinations.is_some())
+        {
+            for listener in self.server.listeners.iter() {
+                let is_wildcard = listener
+                    .address
+                    .parse::<SocketAddr>()
+                    .map_or(false, |addr| addr.ip().is_unspecified());
+                if is_wildcard && listener.protocols.contains(&Protocol::Udp) {
+                    bail!(
+                        "match_destinations isn't supported with udp listener on {}",
+                        listener.address
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
-    pub address: String,
+    //deprecated, kept for old config, it's converted to a listener
+    //with udp and tcp
     #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default = "default_listeners")]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub limit: QueryLimitConfig,
+    #[serde(default)]
+    pub rrl: Option<RrlConfig>,
+    //turn off plain tcp on all the listeners
+    #[serde(default = "default_enable_tcp")]
     pub enable_tcp: bool,
+    #[serde(default)]
+    pub tcp: TcpConfig,
+    #[serde(default)]
+    pub udp: UdpConfig,
+    //seconds to wait for queries in process during shutdown
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         ServerConfig {
-            address: "0.0.0.0:53".to_string(),
-            enable_tcp: false,
+            address: None,
+            listeners: default_listeners(),
+            limit: QueryLimitConfig::default(),
+            rrl: None,
+            enable_tcp: default_enable_tcp(),
+            tcp: TcpConfig::default(),
+            udp: UdpConfig::default(),
+            shutdown_timeout: default_shutdown_timeout(),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn convert_address(&mut self) -> anyhow::Result<()> {
+        if let Some(address) = self.address.take() {
+            if self.listeners != default_listeners() {
+                bail!("server address can't be used with listeners");
+            }
+            self.listeners = vec![ListenerConfig {
+                address,
+                ..default_listeners().remove(0)
+            }];
+        }
+        Ok(())
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    5
+}
+
+fn default_enable_tcp() -> bool {
+    true
+}
+
+//every udp listener binds workers sockets with SO_REUSEPORT, 0 means one
+//socket per cpu. batch_size larger than 1 receives and sends packets with
+//recvmmsg/sendmmsg, which is only supported on linux
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UdpConfig {
+    #[serde(default)]
+    pub workers: usize,
+    #[serde(default = "default_udp_batch_size")]
+    pub batch_size: usize,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig {
+            workers: 0,
+            batch_size: default_udp_batch_size(),
+        }
+    }
+}
+
+fn default_udp_batch_size() -> usize {
+    1
+}
+
+//timeouts are in seconds, connections and pipelined queries limits
+//are shared by tcp and dot
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TcpConfig {
+    #[serde(default = "default_max_tcp_connections")]
+    pub max_connections: usize,
+    #[serde(default = "default_tcp_idle_timeout")]
+    pub idle_timeout: u64,
+    #[serde(default = "default_tcp_send_timeout")]
+    pub send_timeout: u64,
+    //zone transfer may take long, it's limited as a whole
+    #[serde(default = "default_tcp_transfer_timeout")]
+    pub transfer_timeout: u64,
+    #[serde(default = "default_max_pipelined_queries")]
+    pub max_pipelined_queries: usize,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            max_connections: default_max_tcp_connections(),
+            idle_timeout: default_tcp_idle_timeout(),
+            send_timeout: default_tcp_send_timeout(),
+            transfer_timeout: default_tcp_transfer_timeout(),
+            max_pipelined_queries: default_max_pipelined_queries(),
+        }
+    }
+}
+
+fn default_max_tcp_connections() -> usize {
+    1000
+}
+
+fn default_tcp_idle_timeout() -> u64 {
+    10
+}
+
+fn default_tcp_send_timeout() -> u64 {
+    3
+}
+
+fn default_tcp_transfer_timeout() -> u64 {
+    120
+}
+
+fn default_max_pipelined_queries() -> usize {
+    32
+}
+
+fn default_listeners() -> Vec<ListenerConfig> {
+    vec![ListenerConfig {
+        address: "0.0.0.0:53".to_string(),
+        protocols: default_protocols(),
+        cert_path: None,
+        key_path: None,
+        doh_path: default_doh_path(),
+    }]
+}
+
+//what to do with the query when limit is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverloadPolicy {
+    Drop,
+    ServFail,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QueryLimitConfig {
+    #[serde(default = "default_max_inflight")]
+    pub max_inflight: usize,
+    #[serde(default = "default_max_inflight_per_prefix")]
+    pub max_inflight_per_prefix: usize,
+    #[serde(default = "default_v4_prefix_len")]
+    pub v4_prefix_len: u8,
+    #[serde(default = "default_v6_prefix_len")]
+    pub v6_prefix_len: u8,
+    #[serde(default = "default_overload_policy")]
+    pub overload_policy: OverloadPolicy,
+}
+
+impl Default for QueryLimitConfig {
+    fn default() -> Self {
+        QueryLimitConfig {
+            max_inflight: default_max_inflight(),
+            max_inflight_per_prefix: default_max_inflight_per_prefix(),
+            v4_prefix_len: default_v4_prefix_len(),
+            v6_prefix_len: default_v6_prefix_len(),
+            overload_policy: default_overload_policy(),
         }
     }
 }
 
+fn default_max_inflight() -> usize {
+    10000
+}
+
+fn default_max_inflight_per_prefix() -> usize {
+    100
+}
+
+fn default_v4_prefix_len() -> u8 {
+    24
+}
+
+fn default_v6_prefix_len() -> u8 {
+    56
+}
+
+fn default_overload_policy() -> OverloadPolicy {
+    OverloadPolicy::Drop
+}
+
+//nxdomains and errors per second default to responses per second,
+//errors from recursion aren't limited. slip 0 means never send
+//truncated response
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RrlConfig {
+    #[serde(default = "default_rrl_responses_per_second")]
+    pub responses_per_second: u32,
+    #[serde(default)]
+    pub nxdomains_per_second: Option<u32>,
+    #[serde(default)]
+    pub errors_per_second: Option<u32>,
+    #[serde(default = "default_rrl_window")]
+    pub window: u32,
+    #[serde(default = "default_rrl_slip")]
+    pub slip: u32,
+    #[serde(default = "default_v4_prefix_len")]
+    pub v4_prefix_len: u8,
+    #[serde(default = "default_v6_prefix_len")]
+    pub v6_prefix_len: u8,
+    #[serde(default = "default_rrl_max_table_size")]
+    pub max_table_size: usize,
+    #[serde(default)]
+    pub exempt_clients: Vec<String>,
+}
+
+fn default_rrl_responses_per_second() -> u32 {
+    5
+}
+
+fn default_rrl_window() -> u32 {
+    15
+}
+
+fn default_rrl_slip() -> u32 {
+    2
+}
+
+fn default_rrl_max_table_size() -> usize {
+    100000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Dot,
+    Doh,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Protocol::Udp => "udp",
+            Protocol::Tcp => "tcp",
+            Protocol::Dot => "dot",
+            Protocol::Doh => "doh",
+        };
+        f.write_str(name)
+    }
+}
+
+//dot requires cert and key, doh without them is served over plain
+//http, which is useful when tls is terminated by a proxy in front of us
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ListenerConfig {
+    pub address: String,
+    #[serde(default = "default_protocols")]
+    pub protocols: Vec<Protocol>,
+    #[serde(default)]
+    pub cert_path: Option<String>,
+    #[serde(default)]
+    pub key_path: Option<String>,
+    #[serde(default = "default_doh_path")]
+    pub doh_path: String,
+}
+
+fn default_protocols() -> Vec<Protocol> {
+    vec![Protocol::Udp, Protocol::Tcp]
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+//none means any client is allowed, except update, which is refused
+//unless clients are listed explicitly
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AclConfig {
+    #[serde(default)]
+    pub allow_query: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_recursion: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_notify: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_update: Option<Vec<String>>,
+}
+
+//answers of chaos class txt queries version.bind, hostname.bind and
+//id.server, query for identity not configured is refused. nsid is only
+//sent to clients asking for it
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct IdentityConfig {
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub server_id: Option<String>,
+    #[serde(default)]
+    pub nsid: Option<String>,
+}
+
+//views are matched in order, auth and forwarder config outside views
+//belong to the default view, which matches any query not matched by others
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ViewConfig {
+    pub name: String,
+    #[serde(default)]
+    pub match_clients: Option<Vec<String>>,
+    //can't be used with udp listener bound to wildcard address
+    #[serde(default)]
+    pub match_destinations: Option<Vec<String>>,
+    #[serde(default)]
+    pub auth: AuthorityConfig,
+    #[serde(default)]
+    pub forwarder: ForwarderConfig,
+    #[serde(default)]
+    pub cache_size: Option<usize>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthorityConfig {
     #[serde(default)]
     pub zones: Vec<AuthZoneConfig>,
+
+    //changes kept in each zone journal, older ones are dropped
+    #[serde(default = "default_max_journal_len")]
+    pub max_journal_len: usize,
 }
 
 impl Default for AuthorityConfig {
     fn default() -> Self {
-        AuthorityConfig { zones: Vec::new() }
+        AuthorityConfig {
+            zones: Vec::new(),
+            max_journal_len: default_max_journal_len(),
+        }
     }
 }
 
+fn default_max_journal_len() -> usize {
+    100
+}
+
+//zone transfer is refused unless clients are listed explicitly, zone
+//changes are only kept in memory without journal file. secondary zone
+//is transferred from primaries and saved to file_path
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthZoneConfig {
     pub name: String,
     pub file_path: String,
+    #[serde(default)]
+    pub allow_transfer: Option<Vec<String>>,
+    #[serde(default)]
+    pub journal_file: Option<String>,
+    #[serde(default)]
+    pub zone_type: ZoneType,
+    #[serde(default)]
+    pub primaries: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoneType {
+    Primary,
+    Secondary,
+}
+
+impl Default for ZoneType {
+    fn default() -> Self {
+        ZoneType::Primary
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RecursorConfig {
-    #[serde(default)]
+    #[serde(default = "default_true")]
     pub enable: bool,
 
-    #[serde(default)]
+    #[serde(default = "default_cache_size")]
     pub cache_size: usize,
+
+    #[serde(default)]
+    pub tcp_only_servers: Vec<String>,
+
+    //negative answer from local zones is resolved again by recursion
+    #[serde(default)]
+    pub zone_fallthrough: bool,
 }
 
 impl Default for RecursorConfig {
     fn default() -> Self {
         RecursorConfig {
-            enable: true,
-            cache_size: DEFAULT_MESSAGE_CACHE_SIZE,
+            enable: default_true(),
+            cache_size: default_cache_size(),
+            tcp_only_servers: Vec::new(),
+            zone_fallthrough: false,
         }
     }
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_cache_size() -> usize {
+    DEFAULT_MESSAGE_CACHE_SIZE
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ForwarderConfig {
     #[serde(default)]
@@ -128,3 +599,92 @@ impl Default for MetricsConfig {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryLogFormat {
+    Json,
+    Text,
+}
+
+//log file is rotated when it grows larger than max_size in MB, at most
+//max_files rotated files are kept. sample_rate in [0, 1] is the
+//proportion of queries logged
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QueryLogConfig {
+    pub file_path: String,
+    #[serde(default = "default_query_log_format")]
+    pub format: QueryLogFormat,
+    #[serde(default = "default_query_log_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_query_log_max_files")]
+    pub max_files: usize,
+    #[serde(default = "default_query_log_sample_rate")]
+    pub sample_rate: f64,
+}
+
+fn default_query_log_format() -> QueryLogFormat {
+    QueryLogFormat::Json
+}
+
+fn default_query_log_max_size() -> u64 {
+    100
+}
+
+fn default_query_log_max_files() -> usize {
+    5
+}
+
+fn default_query_log_sample_rate() -> f64 {
+    1.0
+}
+
+//frame stream is written to either a unix socket or a file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnstapConfig {
+    #[serde(default)]
+    pub socket_path: Option<String>,
+    #[serde(default)]
+    pub file_path: Option<String>,
+    #[serde(default)]
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default = "default_dnstap_log")]
+    pub log_client: bool,
+    #[serde(default = "default_dnstap_log")]
+    pub log_resolver: bool,
+}
+
+fn default_dnstap_log() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_address() {
+        let config = VanguardConfig::from_yaml("server:\n  address: 0.0.0.0:5555\n").unwrap();
+        assert!(config.server.address.is_none());
+        assert_eq!(config.server.listeners.len(), 1);
+        assert_eq!(config.server.listeners[0].address, "0.0.0.0:5555");
+        assert_eq!(
+            config.server.listeners[0].protocols,
+            vec![Protocol::Udp, Protocol::Tcp]
+        );
+
+        let config = VanguardConfig::from_yaml(
+            "server:\n  listeners:\n  - address: 127.0.0.1:5555\n    protocols: [udp]\n",
+        )
+        .unwrap();
+        assert_eq!(config.server.listeners[0].address, "127.0.0.1:5555");
+        assert_eq!(config.server.listeners[0].protocols, vec![Protocol::Udp]);
+
+        assert!(VanguardConfig::from_yaml(
+            "server:\n  address: 0.0.0.0:5555\n  listeners:\n  - address: 127.0.0.1:5555\n",
+        )
+        .is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::auth::{AuthZone, ZoneUpdater};
+use crate::auth::{AuthZone, MemoryZone, ZoneUpdater};
 use anyhow::{self, bail};
 use r53::{Name, RData, RRClass, RRTtl, RRType, RRset};
 use std::sync::{Arc, RwLock};
@@ -12,7 +12,8 @@ use dynamic_dns::{
     dynamic_update_interface_server::DynamicUpdateInterface, AddRRsetRequest, AddRRsetResponse,
     AddZoneRequest, AddZoneResponse, DeleteDomainRequest, DeleteDomainResponse, DeleteRRsetRequest,
     DeleteRRsetResponse, DeleteRdataRequest, DeleteRdataResponse, DeleteZoneRequest,
-    DeleteZoneResponse, UpdateRdataRequest, UpdateRdataResponse,
+    DeleteZoneResponse, ExportZoneRequest, ExportZoneResponse, UpdateRdataRequest,
+    UpdateRdataResponse,
 };
 
 #[derive(Clone)]
@@ -27,16 +28,35 @@ impl DynamicUpdateHandler {
 }
 
 impl DynamicUpdateHandler {
+    //journal is written after zones are unlocked
+    fn change_zone<F>(&self, zone: &Name, names: &[Name], f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut MemoryZone) -> anyhow::Result<()>,
+    {
+        let (result, journal) = {
+            let mut zones = self.zones.write().unwrap();
+            if let Some(zone) = zones.get_exact_zone(zone) {
+                (zone.apply_change(names, f), zone.journal_file())
+            } else {
+                bail!("unknown zone {}", zone.to_string());
+            }
+        };
+        if let Some(journal) = journal {
+            if let Err(e) = journal.flush() {
+                warn!("write journal of zone {} failed: {:?}", zone, e);
+            }
+        }
+        result
+    }
+
     fn do_add_rrsets(&self, zone: &Name, rrsets: Vec<RRset>) -> anyhow::Result<()> {
-        let mut zones = self.zones.write().unwrap();
-        if let Some(zone) = zones.get_exact_zone(zone) {
+        let names: Vec<Name> = rrsets.iter().map(|rrset| rrset.name.clone()).collect();
+        self.change_zone(zone, &names, |zone| {
             for rrset in rrsets {
                 zone.add_rrset(rrset)?;
             }
             Ok(())
-        } else {
-            bail!("unknown zone {}", zone.to_string());
-        }
+        })
     }
 
     fn do_delete_domains(&self, zone: &Name, names: Vec<Name>) -> anyhow::Result<()> {
@@ -56,27 +76,23 @@ impl DynamicUpdateHandler {
         zone: &Name,
         rrset_headers: Vec<(Name, RRType)>,
     ) -> anyhow::Result<()> {
-        let mut zones = self.zones.write().unwrap();
-        if let Some(zone) = zones.get_exact_zone(zone) {
+        let names: Vec<Name> = rrset_headers.iter().map(|(name, _)| name.clone()).collect();
+        self.change_zone(zone, &names, |zone| {
             for rrset_header in rrset_headers {
                 zone.delete_rrset(&rrset_header.0, rrset_header.1)?;
             }
             Ok(())
-        } else {
-            bail!("unknown zone {}", zone.to_string());
-        }
+        })
     }
 
     fn do_delete_rdatas(&self, zone: &Name, rrsets: Vec<RRset>) -> anyhow::Result<()> {
-        let mut zones = self.zones.write().unwrap();
-        if let Some(zone) = zones.get_exact_zone(zone) {
+        let names: Vec<Name> = rrsets.iter().map(|rrset| rrset.name.clone()).collect();
+        self.change_zone(zone, &names, |zone| {
             for rrset in rrsets {
                 zone.delete_rdata(&rrset)?;
             }
             Ok(())
-        } else {
-            bail!("unknown zone {}", zone.to_string());
-        }
+        })
     }
 
     fn do_update_rdata(
@@ -85,12 +101,10 @@ impl DynamicUpdateHandler {
         old_rrset: RRset,
         new_rrset: RRset,
     ) -> anyhow::Result<()> {
-        let mut zones = self.zones.write().unwrap();
-        if let Some(zone) = zones.get_exact_zone(zone) {
+        let names = vec![old_rrset.name.clone()];
+        self.change_zone(zone, &names, |zone| {
             zone.update_rdata(&old_rrset, new_rrset)
-        } else {
-            bail!("unknown zone {}", zone.to_string());
-        }
+        })
     }
 }
 
@@ -279,6 +293,23 @@ impl DynamicUpdateInterface for DynamicUpdateHandler {
             Err(e) => Err(Status::new(Code::Internal, e.to_string())),
         }
     }
+
+    async fn export_zone(
+        &self,
+        request: tonic::Request<ExportZoneRequest>,
+    ) -> Result<tonic::Response<ExportZoneResponse>, tonic::Status> {
+        let ExportZoneRequest { zone } = request.into_inner();
+        let zone = match r53::Name::new(&zone) {
+            Ok(name) => name,
+            Err(e) => {
+                return Err(Status::new(Code::InvalidArgument, e.to_string()));
+            }
+        };
+        match self.zones.read().unwrap().export_zone(&zone) {
+            Ok(zone_content) => Ok(Response::new(ExportZoneResponse { zone_content })),
+            Err(e) => Err(Status::new(Code::NotFound, e.to_string())),
+        }
+    }
 }
 
 fn proto_typ_to_r53(typ: i32) -> RRType {
@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+//frame streams protocol used by dnstap, data frame is prefixed by its
+//length, control frame is escaped by a zero length
+pub const DNSTAP_CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+const MAX_CONTROL_FRAME_LEN: usize = 512;
+
+pub fn write_data<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+//file is unidirectional, only start is written
+pub fn start<W: Write>(writer: &mut W) -> io::Result<()> {
+    write_control(writer, CONTROL_START, true)?;
+    writer.flush()
+}
+
+//socket reader has to accept the content type before start
+pub fn handshake<S: Read + Write>(stream: &mut S) -> io::Result<()> {
+    write_control(stream, CONTROL_READY, true)?;
+    stream.flush()?;
+    expect_control(stream, CONTROL_ACCEPT)?;
+    start(stream)
+}
+
+//file written before is continued as the same stream, the stop frame
+//at its end is removed, so data frames follow the original start
+pub fn resume(file: &mut File) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return start(file);
+    }
+    let mut stop_frame = Vec::new();
+    write_control(&mut stop_frame, CONTROL_STOP, false)?;
+    let stop_len = stop_frame.len() as u64;
+    if len >= stop_len {
+        file.seek(SeekFrom::Start(len - stop_len))?;
+        let mut tail = vec![0u8; stop_frame.len()];
+        file.read_exact(&mut tail)?;
+        if tail == stop_frame {
+            file.set_len(len - stop_len)?;
+        }
+    }
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+pub fn stop<W: Write>(writer: &mut W) -> io::Result<()> {
+    write_control(writer, CONTROL_STOP, false)?;
+    writer.flush()
+}
+
+pub fn finish<R: Read>(reader: &mut R) -> io::Result<()> {
+    expect_control(reader, CONTROL_FINISH)
+}
+
+fn write_control<W: Write>(
+    writer: &mut W,
+    control: u32,
+    with_content_type: bool,
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(MAX_CONTROL_FRAME_LEN);
+    frame.extend_from_slice(&control.to_be_bytes());
+    if with_content_type {
+        frame.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(DNSTAP_CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend_from_slice(DNSTAP_CONTENT_TYPE);
+    }
+    writer.write_all(&0u32.to_be_bytes())?;
+    writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+    writer.write_all(&frame)
+}
+
+fn read_control<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    if u32::from_be_bytes(buf) != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expect control frame",
+        ));
+    }
+    reader.read_exact(&mut buf)?;
+    let len = u32::from_be_bytes(buf) as usize;
+    if len < 4 || len > MAX_CONTROL_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid control frame length",
+        ));
+    }
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame)?;
+    buf.copy_from_slice(&frame[..4]);
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn expect_control<R: Read>(reader: &mut R, control: u32) -> io::Result<()> {
+    let received = read_control(reader)?;
+    if received != control {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expect control {} but get {}", control, received),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Cursor;
+    use std::process;
+
+    #[test]
+    fn test_frames() {
+        let mut buf = Vec::new();
+        start(&mut buf).unwrap();
+        write_data(&mut buf, &[1, 2, 3]).unwrap();
+        stop(&mut buf).unwrap();
+
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 22];
+        expected.extend_from_slice(DNSTAP_CONTENT_TYPE);
+        expected.extend_from_slice(&[0, 0, 0, 3, 1, 2, 3]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+        assert_eq!(buf, expected);
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_control(&mut reader).unwrap(), CONTROL_START);
+        assert!(read_control(&mut reader).is_err());
+    }
+
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut accept = Vec::new();
+        write_control(&mut accept, CONTROL_ACCEPT, true).unwrap();
+        let mut stream = MockStream {
+            input: Cursor::new(accept),
+            output: Vec::new(),
+        };
+        handshake(&mut stream).unwrap();
+        let mut written = Cursor::new(stream.output);
+        assert_eq!(read_control(&mut written).unwrap(), CONTROL_READY);
+        assert_eq!(read_control(&mut written).unwrap(), CONTROL_START);
+
+        let mut finish_frame = Vec::new();
+        write_control(&mut finish_frame, CONTROL_FINISH, false).unwrap();
+        let mut stream = MockStream {
+            input: Cursor::new(finish_frame.clone()),
+            output: Vec::new(),
+        };
+        assert!(handshake(&mut stream).is_err());
+        assert!(finish(&mut Cursor::new(finish_frame)).is_ok());
+    }
+
+    #[test]
+    fn test_resume() {
+        let path = env::temp_dir().join(format!("vanguard2-dnstap-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(&path)
+                .unwrap()
+        };
+        for data in [[1u8], [2u8]].iter() {
+            let mut file = open();
+            resume(&mut file).unwrap();
+            write_data(&mut file, data).unwrap();
+            stop(&mut file).unwrap();
+        }
+
+        let mut expected = Vec::new();
+        start(&mut expected).unwrap();
+        write_data(&mut expected, &[1]).unwrap();
+        write_data(&mut expected, &[2]).unwrap();
+        stop(&mut expected).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+mod framestream;
+
+mod proto {
+    tonic::include_proto!("dnstap");
+}
+
+use crate::config::{DnstapConfig, Protocol};
+use anyhow::{bail, Context};
+use prometheus::IntCounter;
+use prost::Message as _;
+use proto::{dnstap, message, Dnstap, Message, SocketFamily, SocketProtocol};
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::RwLock;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref DNSTAP: RwLock<Option<DnstapLogger>> = RwLock::new(None);
+    static ref DNSTAP_DROP_INT_COUNT: IntCounter =
+        register_int_counter!("dnstap_drop", "dnstap message dropped").unwrap();
+}
+
+const DNSTAP_QUEUE_LEN: usize = 10000;
+const SOCKET_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct DnstapLogger {
+    sender: SyncSender<Vec<u8>>,
+    writer: JoinHandle<()>,
+    identity: Option<Vec<u8>>,
+    version: Vec<u8>,
+    log_client: bool,
+    log_resolver: bool,
+}
+
+//messages are encoded in place and written to the socket or file by a
+//dedicated thread, they are dropped if the writer falls behind
+pub fn init(conf: &DnstapConfig) -> anyhow::Result<()> {
+    let output = match (&conf.socket_path, &conf.file_path) {
+        (Some(path), None) => FrameOutput::socket(path.clone()),
+        (None, Some(path)) => {
+            FrameOutput::file(path).with_context(|| format!("open dnstap file {} failed", path))?
+        }
+        _ => bail!("one and only one of dnstap socket and file should be specified"),
+    };
+    let (sender, receiver) = sync_channel(DNSTAP_QUEUE_LEN);
+    let writer = thread::Builder::new()
+        .name("dnstap".to_string())
+        .spawn(move || write_frames(receiver, output))
+        .context("start dnstap writer failed")?;
+    *DNSTAP.write().unwrap() = Some(DnstapLogger {
+        sender,
+        writer,
+        identity: conf
+            .identity
+            .as_ref()
+            .map(|identity| identity.clone().into_bytes()),
+        version: conf
+            .version
+            .clone()
+            .unwrap_or_else(|| format!("vanguard2 {}", env!("CARGO_PKG_VERSION")))
+            .into_bytes(),
+        log_client: conf.log_client,
+        log_resolver: conf.log_resolver,
+    });
+    Ok(())
+}
+
+//write out queued messages and close the frame stream
+pub fn shutdown() {
+    let logger = DNSTAP.write().unwrap().take();
+    if let Some(logger) = logger {
+        drop(logger.sender);
+        let _ = logger.writer.join();
+    }
+}
+
+pub fn client_enabled() -> bool {
+    DNSTAP
+        .read()
+        .unwrap()
+        .as_ref()
+        .map_or(false, |logger| logger.log_client)
+}
+
+pub fn resolver_enabled() -> bool {
+    DNSTAP
+        .read()
+        .unwrap()
+        .as_ref()
+        .map_or(false, |logger| logger.log_resolver)
+}
+
+//query is logged as it's received, the returned tap goes with the
+//response and logs it once it's encoded, so what client gets is logged
+pub fn log_client_query(
+    client: SocketAddr,
+    server: SocketAddr,
+    transport: Protocol,
+    query: &[u8],
+) -> Option<ClientTap> {
+    if !client_enabled() {
+        return None;
+    }
+    let query_time = SystemTime::now();
+    let mut message = new_message(message::Type::ClientQuery, transport, Some(client), server);
+    set_query(&mut message, query, query_time);
+    log(message, |logger| logger.log_client);
+    Some(ClientTap {
+        client,
+        server,
+        transport,
+        query_time,
+    })
+}
+
+#[derive(Clone)]
+pub struct ClientTap {
+    client: SocketAddr,
+    server: SocketAddr,
+    transport: Protocol,
+    query_time: SystemTime,
+}
+
+impl ClientTap {
+    pub fn log_response(&self, response: &[u8]) {
+        let mut message = new_message(
+            message::Type::ClientResponse,
+            self.transport,
+            Some(self.client),
+            self.server,
+        );
+        set_query_time(&mut message, self.query_time);
+        set_response(&mut message, response, SystemTime::now());
+        log(message, |logger| logger.log_client);
+    }
+}
+
+//local is the address upstream query is sent from, query to forwarder
+//is logged with forwarder type
+pub fn log_resolver_query(
+    forwarded: bool,
+    local: Option<SocketAddr>,
+    server: SocketAddr,
+    transport: Protocol,
+    query: &[u8],
+    time: SystemTime,
+) {
+    let typ = if forwarded {
+        message::Type::ForwarderQuery
+    } else {
+        message::Type::ResolverQuery
+    };
+    let mut message = new_message(typ, transport, local, server);
+    set_query(&mut message, query, time);
+    log(message, |logger| logger.log_resolver);
+}
+
+pub fn log_resolver_response(
+    forwarded: bool,
+    local: Option<SocketAddr>,
+    server: SocketAddr,
+    transport: Protocol,
+    response: &[u8],
+    query_time: SystemTime,
+    response_time: SystemTime,
+) {
+    let typ = if forwarded {
+        message::Type::ForwarderResponse
+    } else {
+        message::Type::ResolverResponse
+    };
+    let mut message = new_message(typ, transport, local, server);
+    set_query_time(&mut message, query_time);
+    set_response(&mut message, response, response_time);
+    log(message, |logger| logger.log_resolver);
+}
+
+fn log<F: Fn(&DnstapLogger) -> bool>(message: Message, enabled: F) {
+    let dnstap = DNSTAP.read().unwrap();
+    let logger = match *dnstap {
+        Some(ref logger) if enabled(logger) => logger,
+        _ => return,
+    };
+    let frame = Dnstap {
+        identity: logger.identity.clone(),
+        version: Some(logger.version.clone()),
+        extra: None,
+        r#type: dnstap::Type::Message as i32,
+        message: Some(message),
+    };
+    let mut buf = Vec::with_capacity(frame.encoded_len());
+    if frame.encode(&mut buf).is_err() || logger.sender.try_send(buf).is_err() {
+        DNSTAP_DROP_INT_COUNT.inc();
+    }
+}
+
+fn new_message(
+    typ: message::Type,
+    transport: Protocol,
+    query_address: Option<SocketAddr>,
+    response_address: SocketAddr,
+) -> Message {
+    let family = match response_address.ip() {
+        IpAddr::V4(_) => SocketFamily::Inet,
+        IpAddr::V6(_) => SocketFamily::Inet6,
+    };
+    let protocol = match transport {
+        Protocol::Udp => SocketProtocol::Udp,
+        Protocol::Tcp => SocketProtocol::Tcp,
+        Protocol::Dot => SocketProtocol::Dot,
+        Protocol::Doh => SocketProtocol::Doh,
+    };
+    Message {
+        r#type: typ as i32,
+        socket_family: Some(family as i32),
+        socket_protocol: Some(protocol as i32),
+        query_address: query_address.map(|addr| ip_bytes(addr.ip())),
+        query_port: query_address.map(|addr| addr.port() as u32),
+        response_address: Some(ip_bytes(response_address.ip())),
+        response_port: Some(response_address.port() as u32),
+        ..Default::default()
+    }
+}
+
+fn set_query(message: &mut Message, query: &[u8], time: SystemTime) {
+    set_query_time(message, time);
+    message.query_message = Some(query.to_vec());
+}
+
+fn set_query_time(message: &mut Message, time: SystemTime) {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    message.query_time_sec = Some(time.as_secs());
+    message.query_time_nsec = Some(time.subsec_nanos());
+}
+
+fn set_response(message: &mut Message, response: &[u8], time: SystemTime) {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    message.response_time_sec = Some(time.as_secs());
+    message.response_time_nsec = Some(time.subsec_nanos());
+    message.response_message = Some(response.to_vec());
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+//flush after the queue is drained, stop is written once all the
+//senders are gone
+fn write_frames(receiver: Receiver<Vec<u8>>, mut output: FrameOutput) {
+    while let Ok(frame) = receiver.recv() {
+        let mut frame = Some(frame);
+        while let Some(data) = frame {
+            output.write(&data);
+            frame = receiver.try_recv().ok();
+        }
+        output.flush();
+    }
+    output.close();
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+//socket is reconnected after failure, since the reader may be restarted,
+//while file is only opened once, messages of last run are kept
+struct FrameOutput {
+    socket_path: Option<String>,
+    writer: Option<BufWriter<Box<dyn Stream>>>,
+    retry_at: Instant,
+}
+
+impl FrameOutput {
+    fn socket(path: String) -> Self {
+        FrameOutput {
+            socket_path: Some(path),
+            writer: None,
+            retry_at: Instant::now(),
+        }
+    }
+
+    fn file(path: &str) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        framestream::resume(&mut file)?;
+        Ok(FrameOutput {
+            socket_path: None,
+            writer: Some(BufWriter::new(Box::new(file))),
+            retry_at: Instant::now(),
+        })
+    }
+
+    fn connect(&mut self) -> bool {
+        let path = match self.socket_path {
+            Some(ref path) => path,
+            None => return false,
+        };
+        let now = Instant::now();
+        if now < self.retry_at {
+            return false;
+        }
+        let stream = UnixStream::connect(path).and_then(|mut stream| {
+            stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+            stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+            framestream::handshake(&mut stream)?;
+            Ok(stream)
+        });
+        match stream {
+            Ok(stream) => {
+                self.writer = Some(BufWriter::new(Box::new(stream)));
+                true
+            }
+            Err(e) => {
+                warn!("connect to dnstap socket {} failed: {}", path, e);
+                self.retry_at = now + SOCKET_RECONNECT_INTERVAL;
+                false
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if self.writer.is_none() && !self.connect() {
+            DNSTAP_DROP_INT_COUNT.inc();
+            return;
+        }
+        if let Err(e) = framestream::write_data(self.writer.as_mut().unwrap(), data) {
+            warn!("write dnstap failed: {}", e);
+            self.writer = None;
+            DNSTAP_DROP_INT_COUNT.inc();
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(ref mut writer) = self.writer {
+            if let Err(e) = writer.flush() {
+                warn!("flush dnstap failed: {}", e);
+                self.writer = None;
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            if framestream::stop(&mut writer).is_ok() && self.socket_path.is_some() {
+                let _ = framestream::finish(writer.get_mut());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_message() {
+        let client: SocketAddr = "[2001:db8::1]:5353".parse().unwrap();
+        let server: SocketAddr = "[2001:db8::53]:53".parse().unwrap();
+        let mut message = new_message(
+            message::Type::ClientQuery,
+            Protocol::Tcp,
+            Some(client),
+            server,
+        );
+        set_query(&mut message, &[1, 2, 3], UNIX_EPOCH + Duration::new(10, 20));
+        assert_eq!(message.r#type, message::Type::ClientQuery as i32);
+        assert_eq!(message.socket_family, Some(SocketFamily::Inet6 as i32));
+        assert_eq!(message.socket_protocol, Some(SocketProtocol::Tcp as i32));
+        assert_eq!(message.query_address, Some(ip_bytes(client.ip())));
+        assert_eq!(message.query_port, Some(5353));
+        assert_eq!(
+            message.response_address.as_ref().map(|ip| ip.len()),
+            Some(16)
+        );
+        assert_eq!(message.response_port, Some(53));
+        assert_eq!(message.query_time_sec, Some(10));
+        assert_eq!(message.query_time_nsec, Some(20));
+        assert_eq!(message.query_message, Some(vec![1, 2, 3]));
+        assert!(message.response_message.is_none());
+
+        let message = new_message(
+            message::Type::ResolverQuery,
+            Protocol::Udp,
+            None,
+            "192.0.2.1:53".parse().unwrap(),
+        );
+        assert_eq!(message.socket_family, Some(SocketFamily::Inet as i32));
+        assert!(message.query_address.is_none());
+        assert_eq!(message.response_address, Some(vec![192, 0, 2, 1]));
+    }
+}
@@ -74,7 +74,12 @@ impl<C: NameServerClient> AggregateClient<C> {
 
 #[async_trait]
 impl<C: NameServerClient> NameServerClient for AggregateClient<C> {
-    async fn query(&self, request: &Message, target: Host) -> anyhow::Result<Message> {
+    async fn query(
+        &self,
+        request: &Message,
+        target: Host,
+        forwarded: bool,
+    ) -> anyhow::Result<Message> {
         let mut rx_for_same_query = None;
         let mut tx_after_new_query = None;
         {
@@ -118,7 +123,7 @@ impl<C: NameServerClient> NameServerClient for AggregateClient<C> {
             }
         }
 
-        let resp = self.client.query(request, target).await;
+        let resp = self.client.query(request, target, forwarded).await;
         {
             let mut inflight_queries = self.inflight_queries.lock().unwrap();
             let question = request.question.as_ref().unwrap();
@@ -175,7 +180,12 @@ mod tests {
 
     #[async_trait]
     impl NameServerClient for DumbClient {
-        async fn query(&self, request: &Message, _target: Host) -> anyhow::Result<Message> {
+        async fn query(
+            &self,
+            request: &Message,
+            _target: Host,
+            _forwarded: bool,
+        ) -> anyhow::Result<Message> {
             self.query_count.fetch_add(1 as u8, Ordering::Relaxed);
             let mut receiver = {
                 let receiver = self.receiver.lock().unwrap();
@@ -205,7 +215,7 @@ mod tests {
                 rt.spawn(async move {
                     let request = Message::with_query(Name::new("zdns.cn").unwrap(), RRType::A);
                     let resp = client_clone
-                        .query(&request, IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)))
+                        .query(&request, IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)), false)
                         .await;
                     assert!(resp.is_ok());
                 })
@@ -217,7 +227,7 @@ mod tests {
             rt.spawn(async move {
                 let request = Message::with_query(Name::new("zdns.com").unwrap(), RRType::A);
                 let resp = client_clone
-                    .query(&request, IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)))
+                    .query(&request, IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)), false)
                     .await;
                 assert!(resp.is_ok());
             })
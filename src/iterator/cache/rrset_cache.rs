@@ -4,6 +4,15 @@ use r53::{
     header_flag::HeaderFlag, Message, MessageBuilder, Name, RData, RRType, RRset, SectionType,
 };
 
+const REPRESENTATIVE_TYPES: [RRType; 6] = [
+    RRType::A,
+    RRType::AAAA,
+    RRType::MX,
+    RRType::TXT,
+    RRType::NS,
+    RRType::SOA,
+];
+
 pub struct RRsetLruCache {
     rrsets: LruCache<EntryKey, RRsetEntry>,
 }
@@ -31,7 +40,12 @@ impl RRsetLruCache {
     pub fn gen_response(&mut self, request: &Message) -> Option<Message> {
         let question = request.question.as_ref().unwrap();
         let key = &EntryKey(&question.name as *const Name, question.typ);
-        match self.get_rrset_with_key(key) {
+        let rrset = if key.1 == RRType::ANY {
+            self.get_representative_rrset(&question.name)
+        } else {
+            self.get_rrset_with_key(key)
+        };
+        match rrset {
             Some(rrset) => {
                 let mut response = request.clone();
                 let mut builder = MessageBuilder::new(&mut response);
@@ -61,6 +75,16 @@ impl RRsetLruCache {
         }
     }
 
+    //any is answered with one cached rrset as rfc8482 suggests
+    fn get_representative_rrset(&mut self, name: &Name) -> Option<RRset> {
+        for typ in REPRESENTATIVE_TYPES.iter() {
+            if let Some(rrset) = self.get_rrset(name, *typ) {
+                return Some(rrset);
+            }
+        }
+        None
+    }
+
     pub fn gen_cname_response(&mut self, request: &Message) -> Option<Message> {
         let name = &request.question.as_ref().unwrap().name;
         let mut answers = Vec::new();
@@ -152,6 +176,24 @@ mod tests {
             .is_some());
     }
 
+    #[test]
+    fn test_any_response() {
+        let mut cache = RRsetLruCache::new(10);
+        let name = Name::new("www.zdns.cn").unwrap();
+        let request = Message::with_query(name.clone(), RRType::ANY);
+        assert!(cache.gen_response(&request).is_none());
+
+        let mx = RRset::from_str("www.zdns.cn 300 IN MX 10 mail.zdns.cn").unwrap();
+        cache.add_rrset(mx.clone(), RRsetTrustLevel::AnswerWithoutAA);
+        let aaaa = RRset::from_str("www.zdns.cn 300 IN AAAA 2001:db8::1").unwrap();
+        cache.add_rrset(aaaa.clone(), RRsetTrustLevel::AnswerWithoutAA);
+        let response = cache.gen_response(&request).unwrap();
+        let answers = response.section(SectionType::Answer).unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].typ, RRType::AAAA);
+        assert_eq!(answers[0].rdatas, aaaa.rdatas);
+    }
+
     #[test]
     fn test_rrset_cache_bench() {
         let mut cache = RRsetLruCache::new(10);
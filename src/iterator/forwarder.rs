@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::RwLock;
 
 use crate::config::ForwarderConfig;
 use domaintree::{DomainTree, NodeChain};
@@ -9,31 +10,40 @@ use r53::Name;
 use super::delegation_point::DelegationPoint;
 use super::host_selector::Host;
 
-#[derive(Clone)]
 pub struct ForwarderManager {
-    forwarders: DomainTree<Vec<Host>>,
+    forwarders: RwLock<DomainTree<Vec<Host>>>,
 }
 
 impl ForwarderManager {
     pub fn new(conf: &ForwarderConfig) -> Self {
+        Self::try_new(conf).unwrap()
+    }
+
+    pub fn try_new(conf: &ForwarderConfig) -> anyhow::Result<Self> {
         let mut forwarders = DomainTree::new();
         for conf in &conf.forwarders {
-            let name = Name::new(conf.zone_name.as_ref()).unwrap();
+            let name = Name::new(conf.zone_name.as_ref())?;
             let hosts = conf
                 .addresses
                 .iter()
-                .map(|address| IpAddr::from_str(address).unwrap())
-                .collect();
+                .map(|address| IpAddr::from_str(address))
+                .collect::<Result<Vec<Host>, _>>()?;
             forwarders.insert(name, Some(hosts));
         }
-        ForwarderManager {
-            forwarders: (forwarders),
-        }
+        Ok(ForwarderManager {
+            forwarders: RwLock::new(forwarders),
+        })
+    }
+
+    //swap in forwarders of other manager, queries in process aren't affected
+    pub fn replace(&self, other: ForwarderManager) {
+        *self.forwarders.write().unwrap() = other.forwarders.into_inner().unwrap();
     }
 
     pub fn get_delegation_point(&self, name: &Name) -> Option<DelegationPoint> {
-        let mut node_chain = NodeChain::new(&self.forwarders);
-        let result = self.forwarders.find_node(&name, &mut node_chain);
+        let forwarders = self.forwarders.read().unwrap();
+        let mut node_chain = NodeChain::new(&forwarders);
+        let result = forwarders.find_node(&name, &mut node_chain);
         if let Some(hosts) = result.get_value() {
             let top = node_chain.pop();
             let zone = node_chain.get_absolute_name(top.get_name());
@@ -85,5 +95,18 @@ mod tests {
         let dp = dp.unwrap();
         assert!(dp.get_missing_server().is_none());
         assert_eq!(dp.zone(), &Name::new("zdns.cn").unwrap());
+
+        conf.forwarders.remove(0);
+        forwarder_manager.replace(ForwarderManager::try_new(&conf).unwrap());
+        let dp = forwarder_manager.get_delegation_point(&Name::new("a.cn.").unwrap());
+        assert!(dp.is_none());
+        let dp = forwarder_manager.get_delegation_point(&Name::new("a.zdns.cn.").unwrap());
+        assert_eq!(dp.unwrap().zone(), &Name::new("zdns.cn").unwrap());
+
+        conf.forwarders.push(ZoneForwarderConfig {
+            zone_name: "com.".to_string(),
+            addresses: vec!["not an address".to_string()],
+        });
+        assert!(ForwarderManager::try_new(&conf).is_err());
     }
 }
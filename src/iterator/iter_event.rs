@@ -2,7 +2,7 @@ use std::time::Instant;
 
 use super::delegation_point::DelegationPoint;
 use super::util::ResponseCategory;
-use crate::types::Response;
+use crate::types::{Response, ServedBy};
 use r53::{HeaderFlag, Message, MessageBuilder, RRset, SectionType};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -31,6 +31,8 @@ pub struct IterEvent {
     delegation_point: Option<DelegationPoint>,
 
     pub cache_hit: bool,
+    //query is sent to forwarders instead of authoritative servers
+    pub forwarded: bool,
     pub error_count: u8,
     pub query_restart_count: u8,
     pub referral_count: u8,
@@ -50,6 +52,7 @@ impl IterEvent {
             prepend_rrsets: Vec::new(),
             delegation_point: None,
             cache_hit: false,
+            forwarded: false,
             error_count: 0,
             query_restart_count: 0,
             referral_count: 0,
@@ -148,7 +151,14 @@ impl IterEvent {
             .clear_flag(HeaderFlag::AuthAnswer)
             .id(self.orignal_request.header.id)
             .done();
-        let mut resp = Response::new(response);
+        let served_by = if self.cache_hit {
+            ServedBy::Cache
+        } else if self.forwarded {
+            ServedBy::Forwarder
+        } else {
+            ServedBy::Recursion
+        };
+        let mut resp = Response::new(response).with_served_by(served_by);
         resp.cache_hit = self.cache_hit;
         resp
     }
@@ -1,5 +1,7 @@
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -15,8 +17,8 @@ use super::iter_event::{IterEvent, QueryState};
 use super::nsclient::{NSClient, NameServerClient};
 use super::roothint::RootHint;
 use super::util::{sanitize_and_classify_response, ResponseCategory};
-use crate::config::VanguardConfig;
-use crate::types::{Request, Response};
+use crate::config::{ForwarderConfig, VanguardConfig};
+use crate::types::{Request, Response, ServedBy};
 
 const MAX_CNAME_REDIRECT_COUNT: u8 = 8;
 const MAX_DEPENDENT_QUERY_COUNT: u8 = 4;
@@ -27,7 +29,14 @@ const ITERATOR_TIMEOUT: Duration = Duration::from_secs(10);
 pub fn new_iterator(conf: &VanguardConfig) -> Iterator<AggregateClient<NSClient>> {
     let host_selector = Arc::new(Mutex::new(RTTBasedHostSelector::new(10000)));
     let cache = Arc::new(Mutex::new(MessageCache::new(conf.recursor.cache_size)));
-    let client = NSClient::new(host_selector.clone());
+    //addresses are validated when config is loaded
+    let tcp_only_hosts = conf
+        .recursor
+        .tcp_only_servers
+        .iter()
+        .filter_map(|address| IpAddr::from_str(address).ok())
+        .collect();
+    let client = NSClient::new(host_selector.clone(), tcp_only_hosts);
     let forwarder = Arc::new(ForwarderManager::new(&conf.forwarder));
     Iterator::new(
         cache,
@@ -62,6 +71,18 @@ impl<C: NameServerClient + 'static> Iterator<C> {
         }
     }
 
+    //iterator for another view, which shares upstream servers info
+    //but has its own cache and forwarders
+    pub fn new_view(&self, forwarder: &ForwarderConfig, cache_size: usize) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(MessageCache::new(cache_size))),
+            roothint: self.roothint.clone(),
+            host_selector: self.host_selector.clone(),
+            forwarder: Arc::new(ForwarderManager::new(forwarder)),
+            client: self.client.clone(),
+        }
+    }
+
     pub fn resolve(
         &mut self,
         req: Request,
@@ -69,6 +90,19 @@ impl<C: NameServerClient + 'static> Iterator<C> {
         Box::pin(self.clone().do_resolve(req))
     }
 
+    pub fn replace_forwarder(&self, forwarder: ForwarderManager) {
+        self.forwarder.replace(forwarder);
+    }
+
+    pub fn resolve_from_cache(&mut self, req: &Request) -> Option<Response> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.gen_response(&req.request).map(|response| {
+            let mut response = Response::new(response).with_served_by(ServedBy::Cache);
+            response.cache_hit = true;
+            response
+        })
+    }
+
     async fn do_resolve(mut self, req: Request) -> anyhow::Result<Response> {
         let mut event = IterEvent::new(req.request, QueryState::InitQuery, QueryState::Finished);
         loop {
@@ -139,10 +173,12 @@ impl<C: NameServerClient + 'static> Iterator<C> {
     }
 
     fn find_delegation_point(&mut self, event: &mut IterEvent) -> bool {
-        let qname = &event.get_request().question.as_ref().unwrap().name;
-        if let Some(dp) = self.forwarder.get_delegation_point(qname).or_else(|| {
+        let qname = event.get_request().question.as_ref().unwrap().name.clone();
+        let forwarder = self.forwarder.get_delegation_point(&qname);
+        event.forwarded = forwarder.is_some();
+        if let Some(dp) = forwarder.or_else(|| {
             let mut cache = self.cache.lock().unwrap();
-            DelegationPoint::from_cache(qname, &mut cache)
+            DelegationPoint::from_cache(&qname, &mut cache)
         }) {
             event.set_delegation_point(dp);
             event.next_state(QueryState::QueryTarget);
@@ -172,7 +208,11 @@ impl<C: NameServerClient + 'static> Iterator<C> {
             .expect("no dp set in query target state");
         let host = self.select_host(dp);
         match host {
-            Some(host) => match self.client.query(event.get_request(), host).await {
+            Some(host) => match self
+                .client
+                .query(event.get_request(), host, event.forwarded)
+                .await
+            {
                 Ok(mut response) => {
                     let question = event.get_request().question.as_ref().unwrap();
                     let response_category = match sanitize_and_classify_response(
@@ -64,7 +64,12 @@ impl DumbClient {
 
 #[async_trait]
 impl NameServerClient for DumbClient {
-    async fn query(&self, request: &Message, target: Host) -> anyhow::Result<Message> {
+    async fn query(
+        &self,
+        request: &Message,
+        target: Host,
+        _forwarded: bool,
+    ) -> anyhow::Result<Message> {
         let question = request.question.as_ref().unwrap();
         match self.responses.get(&ClientRequest {
             target,
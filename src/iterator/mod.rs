@@ -9,6 +9,7 @@ mod nsclient;
 mod roothint;
 mod util;
 
+pub use forwarder::ForwarderManager;
 pub use iterator::{new_iterator, Iterator};
 
 #[cfg(test)]
@@ -1,94 +1,307 @@
 use std::{
+    collections::HashSet,
     net::SocketAddr,
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{self, bail};
 use async_trait::async_trait;
-use r53::{Message, MessageRender, Rcode};
-use tokio::net::UdpSocket;
+use r53::{HeaderFlag, Message, MessageRender, Rcode};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::time::timeout;
 
 use super::host_selector::{Host, HostSelector, RTTBasedHostSelector};
+use crate::config::Protocol;
+use crate::dnstap;
 
 const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_secs(3); //3 secs
-const DEFAULT_RECV_BUF_SIZE: usize = 1024;
+const DEFAULT_RECV_BUF_SIZE: usize = 65535;
+const DEFAULT_SERVER_PORT: u16 = 53;
 
 #[async_trait]
 pub trait NameServerClient: Clone + Sync + Send {
-    async fn query(&self, request: &Message, target: Host) -> anyhow::Result<Message>;
+    //forwarded is set when target is a forwarder instead of an
+    //authoritative server
+    async fn query(
+        &self,
+        request: &Message,
+        target: Host,
+        forwarded: bool,
+    ) -> anyhow::Result<Message>;
 }
 
 #[derive(Clone)]
 pub struct NSClient {
     host_selector: Arc<Mutex<RTTBasedHostSelector>>,
+    tcp_only_hosts: Arc<HashSet<Host>>,
+    port: u16,
 }
 
 impl NSClient {
-    pub fn new(selector: Arc<Mutex<RTTBasedHostSelector>>) -> Self {
+    pub fn new(selector: Arc<Mutex<RTTBasedHostSelector>>, tcp_only_hosts: Vec<Host>) -> Self {
         Self {
             host_selector: selector,
+            tcp_only_hosts: Arc::new(tcp_only_hosts.into_iter().collect()),
+            port: DEFAULT_SERVER_PORT,
         }
     }
 
-    pub async fn do_query(&self, request: &Message, target: Host) -> anyhow::Result<Message> {
+    #[cfg(test)]
+    fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub async fn do_query(
+        &self,
+        request: &Message,
+        target: Host,
+        forwarded: bool,
+    ) -> anyhow::Result<Message> {
+        if self.tcp_only_hosts.contains(&target) {
+            return self.do_tcp_query(request, target, forwarded).await;
+        }
+
+        let response = self.do_udp_query(request, target, forwarded).await?;
+        if response.header.is_flag_set(HeaderFlag::Truncation) {
+            debug!(
+                "response from {} is truncated, retry with tcp",
+                target.to_string()
+            );
+            self.do_tcp_query(request, target, forwarded).await
+        } else {
+            Ok(response)
+        }
+    }
+
+    async fn do_udp_query(
+        &self,
+        request: &Message,
+        target: Host,
+        forwarded: bool,
+    ) -> anyhow::Result<Message> {
         let mut render = MessageRender::new();
         request.to_wire(&mut render);
+        let data = render.take_data();
+        let server = SocketAddr::new(target, self.port);
         let mut socket = UdpSocket::bind(&("0.0.0.0:0".parse::<SocketAddr>().unwrap())).await?;
-        socket.connect(SocketAddr::new(target, 53)).await?;
+        socket.connect(server).await?;
         let send_time = Instant::now();
-        if let Err(e) = socket.send(&render.take_data()).await {
-            self.host_selector
-                .lock()
-                .unwrap()
-                .set_timeout(target, DEFAULT_RECV_TIMEOUT);
+        let query_time = SystemTime::now();
+        if let Err(e) = socket.send(&data).await {
+            self.set_timeout(target);
             bail!(e);
         }
+        let local = socket.local_addr().ok();
+        if dnstap::resolver_enabled() {
+            dnstap::log_resolver_query(forwarded, local, server, Protocol::Udp, &data, query_time);
+        }
 
         let mut buf = vec![0; DEFAULT_RECV_BUF_SIZE];
         match timeout(DEFAULT_RECV_TIMEOUT, socket.recv(&mut buf)).await {
             Ok(result) => match result {
                 Ok(size) => {
-                    self.host_selector
-                        .lock()
-                        .unwrap()
-                        .set_rtt(target, send_time.elapsed());
+                    self.set_rtt(target, send_time.elapsed());
+                    if dnstap::resolver_enabled() {
+                        dnstap::log_resolver_response(
+                            forwarded,
+                            local,
+                            server,
+                            Protocol::Udp,
+                            &buf[..size],
+                            query_time,
+                            SystemTime::now(),
+                        );
+                    }
                     return Message::from_wire(&buf[..size]);
                 }
                 Err(e) => {
-                    self.host_selector
-                        .lock()
-                        .unwrap()
-                        .set_timeout(target, DEFAULT_RECV_TIMEOUT);
+                    self.set_timeout(target);
                     bail!(e);
                 }
             },
             Err(e) => {
-                self.host_selector
-                    .lock()
-                    .unwrap()
-                    .set_timeout(target, DEFAULT_RECV_TIMEOUT);
+                self.set_timeout(target);
                 bail!(e);
             }
         }
     }
+
+    async fn do_tcp_query(
+        &self,
+        request: &Message,
+        target: Host,
+        forwarded: bool,
+    ) -> anyhow::Result<Message> {
+        let mut render = MessageRender::new();
+        request.to_wire(&mut render);
+        let data = render.take_data();
+        let server = SocketAddr::new(target, self.port);
+        let send_time = Instant::now();
+        let query_time = SystemTime::now();
+        let exchange = async {
+            let mut stream = TcpStream::connect(server).await?;
+            let local = stream.local_addr().ok();
+            stream.write_u16(data.len() as u16).await?;
+            stream.write_all(&data).await?;
+            if dnstap::resolver_enabled() {
+                dnstap::log_resolver_query(
+                    forwarded,
+                    local,
+                    server,
+                    Protocol::Tcp,
+                    &data,
+                    query_time,
+                );
+            }
+            let len = stream.read_u16().await?;
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await?;
+            Ok::<_, anyhow::Error>((local, buf))
+        };
+
+        match timeout(DEFAULT_RECV_TIMEOUT, exchange).await {
+            Ok(Ok((local, buf))) => {
+                self.set_rtt(target, send_time.elapsed());
+                if dnstap::resolver_enabled() {
+                    dnstap::log_resolver_response(
+                        forwarded,
+                        local,
+                        server,
+                        Protocol::Tcp,
+                        &buf,
+                        query_time,
+                        SystemTime::now(),
+                    );
+                }
+                Message::from_wire(&buf)
+            }
+            Ok(Err(e)) => {
+                self.set_timeout(target);
+                bail!(e);
+            }
+            Err(e) => {
+                self.set_timeout(target);
+                bail!(e);
+            }
+        }
+    }
+
+    fn set_rtt(&self, target: Host, rtt: Duration) {
+        self.host_selector.lock().unwrap().set_rtt(target, rtt);
+    }
+
+    fn set_timeout(&self, target: Host) {
+        self.host_selector
+            .lock()
+            .unwrap()
+            .set_timeout(target, DEFAULT_RECV_TIMEOUT);
+    }
 }
 
 #[async_trait]
 impl NameServerClient for NSClient {
-    async fn query(&self, request: &Message, target: Host) -> anyhow::Result<Message> {
+    async fn query(
+        &self,
+        request: &Message,
+        target: Host,
+        forwarded: bool,
+    ) -> anyhow::Result<Message> {
         let mut request = request.clone();
         request.header.id = rand::random::<u16>();
-        let result = self.do_query(&request, target).await;
+        let result = self.do_query(&request, target, forwarded).await;
         if let Ok(ref response) = result {
             if response.header.rcode == Rcode::FormErr {
                 request.header.id = rand::random::<u16>();
                 request.edns = None;
                 request.recalculate_header();
-                return self.do_query(&request, target).await;
+                return self.do_query(&request, target, forwarded).await;
             }
         }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::{MessageBuilder, Name, RRType};
+    use tokio::net::TcpListener;
+
+    fn query() -> Message {
+        Message::with_query(Name::new("example.org.").unwrap(), RRType::A)
+    }
+
+    fn to_wire(message: &Message) -> Vec<u8> {
+        let mut render = MessageRender::new();
+        message.to_wire(&mut render).unwrap();
+        render.take_data()
+    }
+
+    fn response(request: &[u8], truncated: bool) -> Vec<u8> {
+        let mut response = Message::from_wire(request).unwrap();
+        let mut builder = MessageBuilder::new(&mut response);
+        builder.make_response();
+        if truncated {
+            builder.set_flag(HeaderFlag::Truncation);
+        }
+        builder.done();
+        to_wire(&response)
+    }
+
+    //answer every query over tcp with the tc bit cleared
+    async fn run_tcp_server(mut listener: TcpListener) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            let data = response(&buf, false);
+            stream.write_u16(data.len() as u16).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+        }
+    }
+
+    fn new_client(port: u16, tcp_only_hosts: Vec<Host>) -> NSClient {
+        let selector = Arc::new(Mutex::new(RTTBasedHostSelector::new(100)));
+        NSClient::new(selector, tcp_only_hosts).with_port(port)
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_tcp_on_truncation() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut socket = UdpSocket::bind(&addr).await.unwrap();
+        tokio::spawn(run_tcp_server(listener));
+        tokio::spawn(async move {
+            let mut buf = vec![0; DEFAULT_RECV_BUF_SIZE];
+            while let Ok((size, src)) = socket.recv_from(&mut buf).await {
+                let data = response(&buf[..size], true);
+                socket.send_to(&data, &src).await.unwrap();
+            }
+        });
+
+        let client = new_client(addr.port(), Vec::new());
+        let response = client.do_query(&query(), addr.ip(), false).await.unwrap();
+        assert!(response.header.is_flag_set(HeaderFlag::QueryRespone));
+        assert!(!response.header.is_flag_set(HeaderFlag::Truncation));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_only_host() {
+        //nothing listens on udp, query fails unless tcp is used directly
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_tcp_server(listener));
+
+        let client = new_client(addr.port(), vec![addr.ip()]);
+        let response = client.do_query(&query(), addr.ip(), false).await.unwrap();
+        assert!(response.header.is_flag_set(HeaderFlag::QueryRespone));
+        assert!(!response.header.is_flag_set(HeaderFlag::Truncation));
+    }
+}
@@ -49,13 +49,19 @@ pub fn sanitize_and_classify_response(
     if let Some(mut rrsets) = resp.section_mut(SectionType::Answer) {
         rrsets.retain(|rrset| rrset.name.is_subdomain(zone));
         if !rrsets.is_empty() {
-            if &rrsets[0].name != name || (rrsets[0].typ != typ && rrsets[0].typ != RRType::CNAME) {
+            if &rrsets[0].name != name
+                || (rrsets[0].typ != typ && rrsets[0].typ != RRType::CNAME && typ != RRType::ANY)
+            {
                 bail!("answer doesn't match query");
             }
 
             has_answer = true;
-            //should be cname chain
-            if sanitize_cname_chain(typ, &mut rrsets) {
+            if typ == RRType::ANY {
+                //only keep one rrset as rfc8482 suggests
+                rrsets.truncate(1);
+                response_category = ResponseCategory::Answer;
+            } else if sanitize_cname_chain(typ, &mut rrsets) {
+                //should be cname chain
                 response_category = ResponseCategory::Answer;
             } else {
                 response_category = ResponseCategory::CName;
@@ -166,6 +172,7 @@ fn sanitize_cname_chain(qtype: RRType, rrsets: &mut Vec<RRset>) -> bool {
 mod test {
     use super::*;
     use r53::util::hex::from_hex;
+    use r53::MessageBuilder;
     use std::str::FromStr;
 
     struct TestCase {
@@ -263,4 +270,29 @@ mod test {
         assert!(has_answer);
         assert_eq!(rrsets.len(), 1);
     }
+
+    #[test]
+    fn test_sanitize_any_response() {
+        let name = Name::new("www.zdns.cn.").unwrap();
+        let mut response = Message::with_query(name.clone(), RRType::ANY);
+        MessageBuilder::new(&mut response)
+            .make_response()
+            .add_rrset(
+                SectionType::Answer,
+                RRset::from_str("www.zdns.cn. 300 IN MX 10 mail.zdns.cn.").unwrap(),
+            )
+            .add_rrset(
+                SectionType::Answer,
+                RRset::from_str("www.zdns.cn. 300 IN A 1.1.1.1").unwrap(),
+            )
+            .done();
+        let zone = Name::new("zdns.cn.").unwrap();
+        assert_eq!(
+            sanitize_and_classify_response(&zone, &name, RRType::ANY, &mut response).unwrap(),
+            ResponseCategory::Answer
+        );
+        let answers = response.section(SectionType::Answer).unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].typ, RRType::MX);
+    }
 }
@@ -10,6 +10,7 @@ extern crate slog_scope;
 mod auth;
 pub mod config;
 pub mod controller;
+pub mod dnstap;
 mod iterator;
 pub mod logger;
 pub mod metrics;
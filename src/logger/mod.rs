@@ -1,3 +1,6 @@
+mod query_log;
+
+pub use self::query_log::{init_query_log, sampled_query_logger, QueryLogEntry, QueryLogger};
 use slog::Drain;
 use slog_scope::GlobalLoggerGuard;
 
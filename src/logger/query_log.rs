@@ -0,0 +1,316 @@
+use crate::config::{Protocol, QueryLogConfig, QueryLogFormat};
+use crate::types::ServedBy;
+use anyhow::{bail, Context};
+use prometheus::IntCounter;
+use r53::{question::Question, Header, HeaderFlag};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref QUERY_LOG: RwLock<Option<QueryLogger>> = RwLock::new(None);
+    static ref QUERY_LOG_DROP_INT_COUNT: IntCounter = register_int_counter!(
+        "query_log_drop",
+        "query log dropped since log queue is full"
+    )
+    .unwrap();
+}
+
+const QUERY_LOG_QUEUE_LEN: usize = 10000;
+const MEGA_BYTES: u64 = 1024 * 1024;
+
+//question is missing in formerr response to malformed query, dropped
+//means the response isn't sent because of rate limiting
+pub struct QueryLogEntry {
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    pub question: Option<Question>,
+    pub header: Header,
+    pub latency: Duration,
+    pub cache_hit: bool,
+    pub transport: Protocol,
+    pub served_by: Option<ServedBy>,
+    pub dropped: bool,
+}
+
+#[derive(Serialize)]
+struct QueryLogRecord {
+    time: String,
+    client: String,
+    qname: String,
+    qtype: String,
+    rcode: String,
+    flags: String,
+    latency_us: u128,
+    cache_hit: bool,
+    transport: Protocol,
+    served_by: Option<ServedBy>,
+    dropped: bool,
+}
+
+//entries are formatted and written by a dedicated thread, when the
+//writer falls behind, new entries are dropped instead of blocking
+//query resolving
+#[derive(Clone)]
+pub struct QueryLogger {
+    sender: SyncSender<QueryLogEntry>,
+    sample_rate: f64,
+}
+
+impl QueryLogger {
+    pub fn new(conf: &QueryLogConfig) -> anyhow::Result<Self> {
+        if !(0.0..=1.0).contains(&conf.sample_rate) {
+            bail!("query log sample rate {} isn't in [0, 1]", conf.sample_rate);
+        }
+        let file = RotatingFile::open(
+            PathBuf::from(&conf.file_path),
+            conf.max_size * MEGA_BYTES,
+            conf.max_files,
+        )
+        .with_context(|| format!("open query log {} failed", conf.file_path))?;
+        let (sender, receiver) = sync_channel(QUERY_LOG_QUEUE_LEN);
+        let format = conf.format;
+        thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || write_logs(receiver, file, format))
+            .context("start query log writer failed")?;
+        Ok(QueryLogger {
+            sender,
+            sample_rate: conf.sample_rate,
+        })
+    }
+
+    fn is_sampled(&self) -> bool {
+        self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate
+    }
+
+    pub fn log(&self, entry: QueryLogEntry) {
+        if self.sender.try_send(entry).is_err() {
+            QUERY_LOG_DROP_INT_COUNT.inc();
+        }
+    }
+}
+
+//logger is shared by all the servers, queries are logged when their
+//responses are sent or dropped
+pub fn init_query_log(conf: &QueryLogConfig) -> anyhow::Result<()> {
+    *QUERY_LOG.write().unwrap() = Some(QueryLogger::new(conf)?);
+    Ok(())
+}
+
+//checked before the entry is built, so queries not sampled cost nothing
+pub fn sampled_query_logger() -> Option<QueryLogger> {
+    QUERY_LOG
+        .read()
+        .unwrap()
+        .as_ref()
+        .filter(|logger| logger.is_sampled())
+        .cloned()
+}
+
+//flush after the queue is drained
+fn write_logs(receiver: Receiver<QueryLogEntry>, mut file: RotatingFile, format: QueryLogFormat) {
+    while let Ok(entry) = receiver.recv() {
+        let mut entry = Some(entry);
+        while let Some(e) = entry {
+            if let Err(e) = file.write_line(format_entry(e, format).as_bytes()) {
+                warn!("write query log failed: {}", e);
+            }
+            entry = receiver.try_recv().ok();
+        }
+        if let Err(e) = file.flush() {
+            warn!("flush query log failed: {}", e);
+        }
+    }
+}
+
+fn format_entry(entry: QueryLogEntry, format: QueryLogFormat) -> String {
+    let time = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let record = QueryLogRecord {
+        time: format!("{}.{:03}", time.as_secs(), time.subsec_millis()),
+        client: entry.client.to_string(),
+        qname: entry
+            .question
+            .as_ref()
+            .map_or_else(|| "-".to_string(), |q| q.name.to_string()),
+        qtype: entry
+            .question
+            .as_ref()
+            .map_or_else(|| "-".to_string(), |q| q.typ.to_string()),
+        rcode: entry.header.rcode.to_string(),
+        flags: format_flags(&entry.header),
+        latency_us: entry.latency.as_micros(),
+        cache_hit: entry.cache_hit,
+        transport: entry.transport,
+        served_by: entry.served_by,
+        dropped: entry.dropped,
+    };
+    match format {
+        QueryLogFormat::Json => serde_json::to_string(&record).unwrap(),
+        QueryLogFormat::Text => format!(
+            "{} {} {} {} {} {} {}us {} {} cache_hit={} dropped={}",
+            record.time,
+            record.client,
+            record.qname,
+            record.qtype,
+            record.rcode,
+            record.flags,
+            record.latency_us,
+            record.transport,
+            record
+                .served_by
+                .map_or_else(|| "-".to_string(), |s| s.to_string()),
+            record.cache_hit,
+            record.dropped,
+        ),
+    }
+}
+
+fn format_flags(header: &Header) -> String {
+    let flags = [
+        (HeaderFlag::QueryRespone, "qr"),
+        (HeaderFlag::AuthAnswer, "aa"),
+        (HeaderFlag::Truncation, "tc"),
+        (HeaderFlag::RecursionDesired, "rd"),
+        (HeaderFlag::RecursionAvailable, "ra"),
+        (HeaderFlag::AuthenticData, "ad"),
+        (HeaderFlag::CheckingDisabled, "cd"),
+    ];
+    let names: Vec<&str> = flags
+        .iter()
+        .filter(|(flag, _)| header.is_flag_set(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() {
+        "-".to_string()
+    } else {
+        names.join(",")
+    }
+}
+
+//query.log is renamed to query.log.1, query.log.1 to query.log.2 and
+//so on, file beyond max_files is overwritten
+struct RotatingFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            writer: BufWriter::new(file),
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.writer.write_all(line)?;
+        self.writer.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::{Message, Name, RRType};
+
+    #[test]
+    fn test_format_entry() {
+        let message = Message::with_query(Name::new("example.org.").unwrap(), RRType::A);
+        let entry = |question: Option<Question>, dropped: bool| QueryLogEntry {
+            time: UNIX_EPOCH,
+            client: "127.0.0.1:5353".parse().unwrap(),
+            question,
+            header: message.header.clone(),
+            latency: Duration::from_micros(10),
+            cache_hit: false,
+            transport: Protocol::Udp,
+            served_by: None,
+            dropped,
+        };
+        let line = format_entry(entry(message.question.clone(), true), QueryLogFormat::Text);
+        assert!(line.starts_with("0.000 127.0.0.1:5353 example.org. A "));
+        assert!(line.ends_with("cache_hit=false dropped=true"));
+        let line = format_entry(entry(None, false), QueryLogFormat::Text);
+        assert!(line.starts_with("0.000 127.0.0.1:5353 - - "));
+        let line = format_entry(entry(None, false), QueryLogFormat::Json);
+        assert!(line.contains("\"qname\":\"-\""));
+        assert!(line.contains("\"dropped\":false"));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("vanguard2-query-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("query.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in &["aaaa", "bbbb", "cccc", "dddd", "eeee", "ffff", "gggg"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "gggg\n");
+        assert_eq!(
+            fs::read_to_string(file.rotated_path(1)).unwrap(),
+            "eeee\nffff\n"
+        );
+        assert_eq!(
+            fs::read_to_string(file.rotated_path(2)).unwrap(),
+            "cccc\ndddd\n"
+        );
+        assert!(!file.rotated_path(3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
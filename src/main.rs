@@ -1,12 +1,15 @@
 use vanguard2::config::VanguardConfig;
 use vanguard2::controller::Controller;
+use vanguard2::dnstap;
 use vanguard2::logger;
 use vanguard2::metrics::run_metric_server;
 use vanguard2::resolver::Resolver;
 use vanguard2::server::Server;
 
 use clap::{App, Arg};
+use slog_scope::{info, warn};
 use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
 
 fn main() {
     let matches = App::new("auth")
@@ -24,7 +27,13 @@ fn main() {
 
     let config_file = matches.value_of("config").unwrap_or("vanguard.conf");
     let config = VanguardConfig::load_config(config_file).expect("config load failed");
-    let resolver = Resolver::new(&config);
+    if let Some(ref conf) = config.dnstap {
+        dnstap::init(conf).expect("dnstap init failed");
+    }
+    if let Some(ref conf) = config.query_log {
+        logger::init_query_log(conf).expect("query log init failed");
+    }
+    let resolver = Resolver::new(&config).expect("resolver init failed");
     let server = Server::new(&config.server);
     let controller = Controller::new(&config.controller, resolver.zone_data());
     let mut rt = Runtime::new().unwrap();
@@ -36,5 +45,30 @@ fn main() {
             .parse()
             .expect("metric server failed"),
     ));
-    rt.block_on(server.run(resolver));
+    rt.spawn(resolver.run_secondary_zones());
+    rt.spawn(reload_on_hangup(config_file.to_string(), resolver.clone()));
+    rt.block_on(server.run(resolver, wait_for_terminate()))
+        .expect("server failed");
+    dnstap::shutdown();
+}
+
+async fn wait_for_terminate() {
+    let mut terminate = signal(SignalKind::terminate()).expect("listen to sigterm failed");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("listen to sigint failed");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
+    info!("server begin to shutdown");
+}
+
+async fn reload_on_hangup(config_file: String, resolver: Resolver) {
+    let mut hangup = signal(SignalKind::hangup()).expect("listen to sighup failed");
+    while hangup.recv().await.is_some() {
+        match VanguardConfig::load_config(&config_file).and_then(|config| resolver.reload(&config))
+        {
+            Ok(_) => info!("reload config {} succeed", config_file),
+            Err(e) => warn!("reload config {} failed: {:?}", config_file, e),
+        }
+    }
 }
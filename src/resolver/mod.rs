@@ -0,0 +1,625 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use crate::auth::{AuthServer, AuthZone, NotifyHook};
+use crate::config::{Protocol, VanguardConfig};
+use crate::iterator::{new_iterator, ForwarderManager, Iterator};
+use crate::types::{
+    error_response, Acl, Handler, Middleware, Next, Request, Response, ServedBy, View,
+};
+use anyhow::{self, bail};
+use futures::future::join_all;
+use r53::{HeaderFlag, Message, Opcode, RRType, RRset, Rcode, SectionType};
+
+mod stage;
+
+const DEFAULT_VIEW: &str = "default";
+
+//zones and cache of one view, they live through reloads
+#[derive(Clone)]
+struct ViewResolver {
+    auth_server: AuthServer,
+    iterator: Iterator,
+    cache_size: usize,
+}
+
+//settings could be replaced as a whole during reload
+struct Policy {
+    //matching rules of the views in the same order as resolver views
+    views: Vec<View>,
+    allow_query: Option<Acl>,
+    allow_recursion: Option<Acl>,
+    allow_notify: Option<Acl>,
+    allow_update: Option<Acl>,
+    recursion_enabled: bool,
+    zone_fallthrough: bool,
+    identities: Vec<RRset>,
+    nsid: Option<Vec<u8>>,
+    pipeline: Vec<Box<dyn Middleware<QueryContext>>>,
+}
+
+impl Policy {
+    fn new(config: &VanguardConfig) -> anyhow::Result<Self> {
+        let mut pipeline = Vec::with_capacity(config.pipeline.len());
+        for (i, stage) in config.pipeline.iter().enumerate() {
+            if config.pipeline[..i].contains(stage) {
+                bail!("stage {:?} is duplicated in pipeline", stage);
+            }
+            pipeline.push(stage::new_stage(*stage));
+        }
+        let mut views = Vec::with_capacity(config.views.len() + 1);
+        for conf in &config.views {
+            views.push(new_view(
+                &conf.name,
+                &conf.match_clients,
+                &conf.match_destinations,
+            )?);
+        }
+        views.push(new_view(DEFAULT_VIEW, &None, &None)?);
+        Ok(Policy {
+            views,
+            allow_query: new_acl(&config.acl.allow_query)?,
+            allow_recursion: new_acl(&config.acl.allow_recursion)?,
+            allow_notify: new_acl(&config.acl.allow_notify)?,
+            allow_update: new_acl(&config.acl.allow_update)?,
+            recursion_enabled: config.recursor.enable,
+            zone_fallthrough: config.recursor.zone_fallthrough,
+            identities: stage::new_identities(&config.identity)?,
+            nsid: config
+                .identity
+                .nsid
+                .as_ref()
+                .map(|nsid| nsid.clone().into_bytes()),
+            pipeline,
+        })
+    }
+}
+
+//state shared by the stages of pipeline
+struct QueryContext {
+    request: Request,
+    view: ViewResolver,
+    policy: Arc<Policy>,
+}
+
+#[derive(Clone)]
+pub struct Resolver {
+    //default view is always the last one
+    views: Arc<Vec<ViewResolver>>,
+    policy: Arc<RwLock<Arc<Policy>>>,
+}
+
+impl Resolver {
+    pub fn new(config: &VanguardConfig) -> anyhow::Result<Self> {
+        let policy = Policy::new(config)?;
+        let default_iterator = new_iterator(config);
+        let mut views = Vec::with_capacity(config.views.len() + 1);
+        for conf in &config.views {
+            let cache_size = conf.cache_size.unwrap_or(config.recursor.cache_size);
+            views.push(ViewResolver {
+                auth_server: AuthServer::new(&conf.auth),
+                iterator: default_iterator.new_view(&conf.forwarder, cache_size),
+                cache_size,
+            });
+        }
+        views.push(ViewResolver {
+            auth_server: AuthServer::new(&config.auth),
+            iterator: default_iterator,
+            cache_size: config.recursor.cache_size,
+        });
+
+        Ok(Resolver {
+            views: Arc::new(views),
+            policy: Arc::new(RwLock::new(Arc::new(policy))),
+        })
+    }
+
+    //zone data of default view
+    pub fn zone_data(&self) -> Arc<RwLock<AuthZone>> {
+        self.views.last().unwrap().auth_server.zone_data()
+    }
+
+    //hook is shared by all the views
+    pub fn add_notify_hook(&self, hook: Arc<dyn NotifyHook>) {
+        for view in self.views.iter() {
+            view.auth_server.add_notify_hook(hook.clone());
+        }
+    }
+
+    //secondary zones of all the views are refreshed in background
+    pub fn run_secondary_zones(&self) -> impl Future<Output = ()> {
+        let runs: Vec<_> = self
+            .views
+            .iter()
+            .map(|view| view.auth_server.run_secondary_zones())
+            .collect();
+        async move {
+            join_all(runs).await;
+        }
+    }
+
+    //everything is loaded and checked before any change is applied, so
+    //an invalid config leaves the resolver untouched. caches are kept,
+    //views can't be added, removed or resized without restart, but
+    //their matching rules are replaced together with other settings
+    pub fn reload(&self, config: &VanguardConfig) -> anyhow::Result<()> {
+        let policy = Policy::new(config)?;
+        let old_policy = self.policy.read().unwrap().clone();
+        let view_names: Vec<&str> = old_policy.views.iter().map(|v| v.name()).collect();
+        let new_view_names: Vec<&str> = policy.views.iter().map(|v| v.name()).collect();
+        if view_names != new_view_names {
+            bail!("views are changed, restart is needed");
+        }
+        let mut cache_sizes: Vec<usize> = config
+            .views
+            .iter()
+            .map(|conf| conf.cache_size.unwrap_or(config.recursor.cache_size))
+            .collect();
+        cache_sizes.push(config.recursor.cache_size);
+        if self
+            .views
+            .iter()
+            .zip(cache_sizes)
+            .any(|(view, cache_size)| view.cache_size != cache_size)
+        {
+            bail!("cache size is changed, restart is needed");
+        }
+
+        let mut changes = Vec::with_capacity(self.views.len());
+        for (view, conf) in self.views.iter().zip(config.views.iter()) {
+            changes.push((
+                view.auth_server.prepare_reload(&conf.auth)?,
+                ForwarderManager::try_new(&conf.forwarder)?,
+            ));
+        }
+        let default_view = self.views.last().unwrap();
+        changes.push((
+            default_view.auth_server.prepare_reload(&config.auth)?,
+            ForwarderManager::try_new(&config.forwarder)?,
+        ));
+
+        //zones of all the views are locked, so queries never see part
+        //of the changes
+        let zone_data: Vec<_> = self
+            .views
+            .iter()
+            .map(|view| view.auth_server.zone_data())
+            .collect();
+        let mut zones: Vec<_> = zone_data
+            .iter()
+            .map(|zones| zones.write().unwrap())
+            .collect();
+        for ((view, zones), (zone_changes, forwarder)) in self
+            .views
+            .iter()
+            .zip(zones.iter_mut())
+            .zip(changes.into_iter())
+        {
+            view.auth_server.apply_reload(zones, zone_changes);
+            view.iterator.replace_forwarder(forwarder);
+        }
+        *self.policy.write().unwrap() = Arc::new(policy);
+        Ok(())
+    }
+
+    fn select_view(&self, policy: &Policy, req: &Request) -> &ViewResolver {
+        let client = req.client.ip();
+        let server = req.server.map(|addr| addr.ip());
+        policy
+            .views
+            .iter()
+            .position(|view| view.is_match(client, server))
+            .and_then(|i| self.views.get(i))
+            .unwrap_or_else(|| self.views.last().unwrap())
+    }
+
+    //only query goes through the pipeline, notify and update are
+    //handled by local zones of the view
+    async fn do_resolve(&mut self, req: Request) -> anyhow::Result<Response> {
+        let nsid = if req.nsid {
+            self.policy.read().unwrap().nsid.clone()
+        } else {
+            None
+        };
+        let mut response = match req.request.header.opcode {
+            Opcode::Query => self.do_query(req).await?,
+            Opcode::Notify => self.do_notify(&req),
+            Opcode::Update => self.do_update(&req).await,
+            _ => Response::new(error_response(&req.request, Rcode::NotImp)),
+        };
+        response.nsid = nsid;
+        Ok(response)
+    }
+
+    fn do_notify(&self, req: &Request) -> Response {
+        let policy = self.policy.read().unwrap().clone();
+        if !is_allowed(&policy.allow_notify, req.client.ip()) {
+            return Response::new(error_response(&req.request, Rcode::Refused));
+        }
+        let response = self
+            .select_view(&policy, req)
+            .auth_server
+            .handle_notify(req);
+        Response::new(response).with_served_by(ServedBy::Auth)
+    }
+
+    //update isn't allowed without acl
+    async fn do_update(&self, req: &Request) -> Response {
+        let policy = self.policy.read().unwrap().clone();
+        let allowed = policy
+            .allow_update
+            .as_ref()
+            .map_or(false, |acl| acl.contains(req.client.ip()));
+        if !allowed {
+            return Response::new(error_response(&req.request, Rcode::Refused));
+        }
+        let response = self
+            .select_view(&policy, req)
+            .auth_server
+            .handle_update(req)
+            .await;
+        Response::new(response).with_served_by(ServedBy::Auth)
+    }
+
+    fn do_transfer(&self, req: &Request) -> Response {
+        let policy = self.policy.read().unwrap().clone();
+        let mut messages = self
+            .select_view(&policy, req)
+            .auth_server
+            .handle_transfer(req);
+        let response = messages
+            .next()
+            .unwrap_or_else(|| error_response(&req.request, Rcode::ServFail));
+        Response::new(response)
+            .with_served_by(ServedBy::Auth)
+            .with_extra_messages(messages)
+    }
+
+    //query unanswered by any stage is refused
+    async fn do_query(&mut self, req: Request) -> anyhow::Result<Response> {
+        if let Some(rcode) = check_query_type(&req) {
+            return Ok(Response::new(error_response(&req.request, rcode)));
+        }
+        let typ = req.question().typ;
+        if typ == RRType::AXFR || typ == RRType::IXFR {
+            return Ok(self.do_transfer(&req));
+        }
+        let policy = self.policy.read().unwrap().clone();
+        let mut ctx = QueryContext {
+            view: self.select_view(&policy, &req).clone(),
+            request: req,
+            policy: policy.clone(),
+        };
+        let response = Next::new(&policy.pipeline).run(&mut ctx).await?;
+        Ok(response
+            .unwrap_or_else(|| Response::new(error_response(&ctx.request.request, Rcode::Refused))))
+    }
+}
+
+//none means any address
+fn new_acl(addrs: &Option<Vec<String>>) -> anyhow::Result<Option<Acl>> {
+    match addrs {
+        Some(addrs) => Ok(Some(Acl::new(addrs.iter().map(|s| s.as_ref()).collect())?)),
+        None => Ok(None),
+    }
+}
+
+//view without match clients matches any client
+fn new_view(
+    name: &str,
+    clients: &Option<Vec<String>>,
+    destinations: &Option<Vec<String>>,
+) -> anyhow::Result<View> {
+    let mut view = View::new(name.to_string());
+    let acl = match new_acl(clients)? {
+        Some(acl) => acl,
+        None => Acl::new(vec!["0.0.0.0/0", "::/0"])?,
+    };
+    for addr in acl.addrs {
+        view.add_addr(addr);
+    }
+    if let Some(destinations) = new_acl(destinations)? {
+        view.set_destinations(destinations);
+    }
+    Ok(view)
+}
+
+//referral isn't authoritative, so it's never treated as negative
+fn is_negative_response(response: &Message) -> bool {
+    if !response.header.is_flag_set(HeaderFlag::AuthAnswer) {
+        return false;
+    }
+    match response.header.rcode {
+        Rcode::NXDomain => true,
+        Rcode::NoError => response
+            .section(SectionType::Answer)
+            .map_or(true, |answers| answers.is_empty()),
+        _ => false,
+    }
+}
+
+fn is_allowed(acl: &Option<Acl>, client: IpAddr) -> bool {
+    acl.as_ref().map_or(true, |acl| acl.contains(client))
+}
+
+impl Handler for Resolver {
+    fn resolve(
+        &mut self,
+        req: Request,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Response>> + Send + '_>> {
+        Box::pin(self.do_resolve(req))
+    }
+}
+
+//among meta types only any and zone transfer are answered, axfr needs
+//a transport which could carry several messages, ixfr over udp is
+//answered with soa only
+fn check_query_type(req: &Request) -> Option<Rcode> {
+    let typ = req.question().typ;
+    let is_stream = req.transport == Protocol::Tcp || req.transport == Protocol::Dot;
+    match typ {
+        RRType::ANY => None,
+        RRType::AXFR if is_stream => None,
+        RRType::AXFR => Some(Rcode::Refused),
+        RRType::IXFR if is_stream || req.transport == Protocol::Udp => None,
+        RRType::IXFR => Some(Rcode::Refused),
+        _ => match typ.to_u16() {
+            //type 0 is reserved, opt is only valid as edns pseudo rr
+            0 | 41 => Some(Rcode::FormErr),
+            128..=254 => Some(Rcode::NotImp),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RecursorConfig;
+    use r53::{MessageBuilder, Name, RRClass};
+
+    fn request(opcode: Opcode) -> Request {
+        let mut message = Message::with_query(Name::new("example.org.").unwrap(), RRType::SOA);
+        MessageBuilder::new(&mut message).opcode(opcode).done();
+        Request::new(message, "127.0.0.1:5353".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_opcode_dispatch() {
+        let mut resolver = Resolver::new(&VanguardConfig::default()).unwrap();
+        let response = resolver.resolve(request(Opcode::Status)).await.unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NotImp);
+        assert_eq!(response.response.header.opcode, Opcode::Status);
+
+        //example.org isn't a local zone
+        let response = resolver.resolve(request(Opcode::Notify)).await.unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NotAuth);
+        assert_eq!(response.response.header.opcode, Opcode::Notify);
+
+        let response = resolver.resolve(request(Opcode::Update)).await.unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+
+        let mut config = VanguardConfig::default();
+        config.acl.allow_update = Some(vec!["127.0.0.1".to_string()]);
+        config.acl.allow_notify = Some(vec!["192.0.2.1".to_string()]);
+        let mut resolver = Resolver::new(&config).unwrap();
+        let response = resolver.resolve(request(Opcode::Update)).await.unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NotAuth);
+        assert_eq!(response.response.header.opcode, Opcode::Update);
+        let response = resolver.resolve(request(Opcode::Notify)).await.unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+    }
+
+    fn chaos_request(name: &str) -> Request {
+        let mut message = Message::with_query(Name::new(name).unwrap(), RRType::TXT);
+        message.question.as_mut().unwrap().class = RRClass::CH;
+        Request::new(message, "127.0.0.1:5353".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_identity() {
+        let mut config = VanguardConfig::default();
+        config.identity.version = Some("vanguard2 test".to_string());
+        config.identity.nsid = Some("ns1".to_string());
+        let mut resolver = Resolver::new(&config).unwrap();
+
+        let response = resolver
+            .resolve(chaos_request("version.bind."))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NoError);
+        let answers = response.response.section(SectionType::Answer).unwrap();
+        assert_eq!(answers[0].class, RRClass::CH);
+        assert_eq!(answers[0].rdatas[0].to_string(), "\"vanguard2 test\"");
+        assert!(response.nsid.is_none());
+
+        let response = resolver
+            .resolve(chaos_request("hostname.bind.").with_nsid(true))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+        assert_eq!(response.nsid, Some(b"ns1".to_vec()));
+    }
+
+    fn typed_request(typ: RRType, transport: Protocol) -> Request {
+        let message = Message::with_query(Name::new("example.org.").unwrap(), typ);
+        Request::new(message, "127.0.0.1:5353".parse().unwrap()).with_transport(transport)
+    }
+
+    #[test]
+    fn test_check_query_type() {
+        for (typ, transport, rcode) in vec![
+            (RRType::A, Protocol::Udp, None),
+            (RRType::ANY, Protocol::Udp, None),
+            (RRType::Unknown(0), Protocol::Udp, Some(Rcode::FormErr)),
+            (RRType::OPT, Protocol::Tcp, Some(Rcode::FormErr)),
+            (RRType::AXFR, Protocol::Udp, Some(Rcode::Refused)),
+            (RRType::AXFR, Protocol::Doh, Some(Rcode::Refused)),
+            (RRType::AXFR, Protocol::Tcp, None),
+            (RRType::IXFR, Protocol::Udp, None),
+            (RRType::IXFR, Protocol::Tcp, None),
+            (RRType::IXFR, Protocol::Doh, Some(Rcode::Refused)),
+            (RRType::Unknown(254), Protocol::Tcp, Some(Rcode::NotImp)),
+        ] {
+            assert_eq!(check_query_type(&typed_request(typ, transport)), rcode);
+        }
+    }
+
+    //default view serves example.org from testdata
+    fn local_zone_config(extra: &str) -> VanguardConfig {
+        serde_yaml::from_str(&format!(
+            "auth:\n  zones:\n  - name: example.org.\n    file_path: testdata/example.org.zone\n{}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn query(name: &str, rd: bool) -> Request {
+        let mut message = Message::with_query(Name::new(name).unwrap(), RRType::A);
+        let mut builder = MessageBuilder::new(&mut message);
+        if rd {
+            builder.set_flag(HeaderFlag::RecursionDesired);
+        } else {
+            builder.clear_flag(HeaderFlag::RecursionDesired);
+        }
+        builder.done();
+        Request::new(message, "127.0.0.1:5353".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_recursion_not_desired() {
+        let config = local_zone_config("");
+        assert!(config.recursor.enable);
+        let mut resolver = Resolver::new(&config).unwrap();
+
+        let response = resolver
+            .resolve(query("ns.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NoError);
+        assert_eq!(response.served_by, Some(ServedBy::Auth));
+
+        //nothing in cache, and query without rd never goes outside
+        let response = resolver
+            .resolve(query("www.example.com.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+        assert!(response.served_by.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recursion_disabled() {
+        let config = local_zone_config("recursor:\n  enable: false\n");
+        assert_eq!(
+            config.recursor.cache_size,
+            RecursorConfig::default().cache_size
+        );
+        let mut resolver = Resolver::new(&config).unwrap();
+
+        let response = resolver
+            .resolve(query("ns.example.org.", true))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NoError);
+        assert!(response.response.header.is_flag_set(HeaderFlag::AuthAnswer));
+        assert_eq!(response.served_by, Some(ServedBy::Auth));
+
+        let response = resolver
+            .resolve(query("www.example.com.", true))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+        assert!(response.served_by.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_zone_fallthrough() {
+        //acl after auth stage shows whether the query is handed over
+        let pipeline = "pipeline: [auth, acl]\nacl:\n  allow_query: [\"192.0.2.0/24\"]\n";
+        let mut resolver = Resolver::new(&local_zone_config(pipeline)).unwrap();
+        let response = resolver
+            .resolve(query("none.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NXDomain);
+
+        let config = local_zone_config(&format!(
+            "{}recursor:\n  zone_fallthrough: true\n",
+            pipeline
+        ));
+        assert!(config.recursor.enable);
+        let mut resolver = Resolver::new(&config).unwrap();
+        let response = resolver
+            .resolve(query("none.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+        //positive answer is never handed over
+        let response = resolver
+            .resolve(query("ns.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NoError);
+        assert_eq!(response.served_by, Some(ServedBy::Auth));
+
+        //negative answer is used if none of the following stages answers
+        let config = local_zone_config("recursor:\n  zone_fallthrough: true\n");
+        let mut resolver = Resolver::new(&config).unwrap();
+        let response = resolver
+            .resolve(query("none.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NXDomain);
+        assert_eq!(response.served_by, Some(ServedBy::Auth));
+    }
+
+    fn view_config(match_clients: &str, cache_size: usize) -> VanguardConfig {
+        serde_yaml::from_str(&format!(
+            "views:\n- name: internal\n  match_clients: [\"{}\"]\n  cache_size: {}\n  auth:\n    zones:\n    - name: example.org.\n      file_path: testdata/example.org.zone\n",
+            match_clients, cache_size
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reload_views() {
+        let mut resolver = Resolver::new(&view_config("192.0.2.0/24", 100)).unwrap();
+        let response = resolver
+            .resolve(query("ns.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+
+        resolver.reload(&view_config("127.0.0.0/8", 100)).unwrap();
+        let response = resolver
+            .resolve(query("ns.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NoError);
+        assert_eq!(response.served_by, Some(ServedBy::Auth));
+
+        assert!(Resolver::new(&view_config("192.0.2.0/33", 100)).is_err());
+        assert!(resolver.reload(&view_config("192.0.2.0/33", 100)).is_err());
+        assert!(resolver.reload(&view_config("192.0.2.0/24", 200)).is_err());
+        assert!(resolver.reload(&VanguardConfig::default()).is_err());
+        let response = resolver
+            .resolve(query("ns.example.org.", false))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::NoError);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_without_acl() {
+        let mut resolver = Resolver::new(&VanguardConfig::default()).unwrap();
+        let mut response = resolver
+            .resolve(typed_request(RRType::AXFR, Protocol::Tcp))
+            .await
+            .unwrap();
+        assert_eq!(response.response.header.rcode, Rcode::Refused);
+        assert!(response.extra_messages.next().is_none());
+    }
+}
@@ -0,0 +1,174 @@
+use super::{is_allowed, is_negative_response, QueryContext};
+use crate::config::{IdentityConfig, Stage};
+use crate::types::{error_response, Middleware, MiddlewareFuture, Next, Response, ServedBy};
+use anyhow::{self, ensure};
+use r53::{
+    HeaderFlag, MessageBuilder, Name, RData, RRClass, RRTtl, RRType, RRset, Rcode, SectionType,
+};
+
+//txt character string is at most 255 bytes
+const MAX_TXT_STRING_LEN: usize = 255;
+
+pub(super) fn new_stage(stage: Stage) -> Box<dyn Middleware<QueryContext>> {
+    match stage {
+        Stage::Acl => Box::new(AclStage),
+        Stage::Chaos => Box::new(ChaosStage),
+        Stage::Auth => Box::new(AuthStage),
+        Stage::Cache => Box::new(CacheStage),
+        Stage::Recursion => Box::new(RecursionStage),
+    }
+}
+
+fn answer<'a>(response: Response) -> MiddlewareFuture<'a> {
+    Box::pin(async { Ok(Some(response)) })
+}
+
+//cache is filled by recursion, so both of them are only for clients
+//allowed to recurse
+fn is_recursion_allowed(ctx: &QueryContext) -> bool {
+    ctx.policy.recursion_enabled && is_allowed(&ctx.policy.allow_recursion, ctx.request.client.ip())
+}
+
+struct AclStage;
+
+impl Middleware<QueryContext> for AclStage {
+    fn handle<'a>(
+        &'a self,
+        ctx: &'a mut QueryContext,
+        next: Next<'a, QueryContext>,
+    ) -> MiddlewareFuture<'a> {
+        if is_allowed(&ctx.policy.allow_query, ctx.request.client.ip()) {
+            next.run(ctx)
+        } else {
+            answer(Response::new(error_response(
+                &ctx.request.request,
+                Rcode::Refused,
+            )))
+        }
+    }
+}
+
+pub(super) fn new_identities(conf: &IdentityConfig) -> anyhow::Result<Vec<RRset>> {
+    let mut identities = Vec::new();
+    for (name, value) in &[
+        ("version.bind.", &conf.version),
+        ("hostname.bind.", &conf.hostname),
+        ("id.server.", &conf.server_id),
+    ] {
+        if let Some(value) = value {
+            ensure!(
+                value.len() <= MAX_TXT_STRING_LEN,
+                "identity {} is too long",
+                name
+            );
+            let txt = format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""));
+            identities.push(RRset {
+                name: Name::new(name)?,
+                typ: RRType::TXT,
+                class: RRClass::CH,
+                ttl: RRTtl(0),
+                rdatas: vec![RData::from_str(RRType::TXT, &txt)?],
+            });
+        }
+    }
+    Ok(identities)
+}
+
+//zones and recursion only serve internet class, so any chaos query
+//except the identity configured is refused
+struct ChaosStage;
+
+impl Middleware<QueryContext> for ChaosStage {
+    fn handle<'a>(
+        &'a self,
+        ctx: &'a mut QueryContext,
+        next: Next<'a, QueryContext>,
+    ) -> MiddlewareFuture<'a> {
+        let question = ctx.request.question();
+        if question.class != RRClass::CH {
+            return next.run(ctx);
+        }
+        let identity = ctx
+            .policy
+            .identities
+            .iter()
+            .find(|rrset| question.typ == RRType::TXT && rrset.name == question.name);
+        let response = match identity {
+            Some(rrset) => {
+                let mut response = ctx.request.request.clone();
+                MessageBuilder::new(&mut response)
+                    .make_response()
+                    .set_flag(HeaderFlag::AuthAnswer)
+                    .add_rrset(SectionType::Answer, rrset.clone())
+                    .done();
+                Response::new(response).with_served_by(ServedBy::Auth)
+            }
+            None => Response::new(error_response(&ctx.request.request, Rcode::Refused)),
+        };
+        answer(response)
+    }
+}
+
+//with zone fallthrough, negative answer is handed over to the
+//following stages, and only used if none of them answers
+struct AuthStage;
+
+impl Middleware<QueryContext> for AuthStage {
+    fn handle<'a>(
+        &'a self,
+        ctx: &'a mut QueryContext,
+        next: Next<'a, QueryContext>,
+    ) -> MiddlewareFuture<'a> {
+        match ctx.view.auth_server.resolve(&ctx.request) {
+            Some(response) if !ctx.policy.zone_fallthrough || !is_negative_response(&response) => {
+                answer(Response::new(response).with_served_by(ServedBy::Auth))
+            }
+            auth_response => Box::pin(async move {
+                let response = next.run(ctx).await?;
+                Ok(response.or_else(|| {
+                    auth_response.map(|r| Response::new(r).with_served_by(ServedBy::Auth))
+                }))
+            }),
+        }
+    }
+}
+
+struct CacheStage;
+
+impl Middleware<QueryContext> for CacheStage {
+    fn handle<'a>(
+        &'a self,
+        ctx: &'a mut QueryContext,
+        next: Next<'a, QueryContext>,
+    ) -> MiddlewareFuture<'a> {
+        if is_recursion_allowed(ctx) {
+            if let Some(response) = ctx.view.iterator.resolve_from_cache(&ctx.request) {
+                return answer(response);
+            }
+        }
+        next.run(ctx)
+    }
+}
+
+//without rd, query never goes outside
+struct RecursionStage;
+
+impl Middleware<QueryContext> for RecursionStage {
+    fn handle<'a>(
+        &'a self,
+        ctx: &'a mut QueryContext,
+        next: Next<'a, QueryContext>,
+    ) -> MiddlewareFuture<'a> {
+        if !is_recursion_allowed(ctx)
+            || !ctx
+                .request
+                .request
+                .header
+                .is_flag_set(HeaderFlag::RecursionDesired)
+        {
+            return next.run(ctx);
+        }
+        let resolve = ctx.view.iterator.resolve(ctx.request.clone());
+        Box::pin(async move { resolve.await.map(Some) })
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::channel::oneshot;
+use prometheus::{IntCounter, IntGauge};
+
+lazy_static! {
+    static ref TCP_CONNECTION_INT_GAUGE: IntGauge =
+        register_int_gauge!("tcp_connections", "open tcp connection count").unwrap();
+    static ref TCP_EVICT_INT_COUNT: IntCounter =
+        register_int_counter!("tcp_evict", "idle tcp connection evicted").unwrap();
+    static ref TCP_REJECT_INT_COUNT: IntCounter =
+        register_int_counter!("tcp_reject", "tcp connection rejected").unwrap();
+}
+
+struct Connection {
+    last_active: Instant,
+    inflight: usize,
+    evict: oneshot::Sender<()>,
+}
+
+struct Connections {
+    next_id: u64,
+    conns: HashMap<u64, Connection>,
+}
+
+//shared by tcp and tls listeners, when it's full, the connection idle
+//for the longest time is evicted to make room for the new one
+#[derive(Clone)]
+pub struct ConnectionTable {
+    max_connections: usize,
+    connections: Arc<Mutex<Connections>>,
+}
+
+pub struct ConnectionHandle {
+    id: u64,
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl ConnectionTable {
+    pub fn new(max_connections: usize) -> Self {
+        ConnectionTable {
+            max_connections,
+            connections: Arc::new(Mutex::new(Connections {
+                next_id: 0,
+                conns: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn register(&self) -> Option<(ConnectionHandle, oneshot::Receiver<()>)> {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.conns.len() >= self.max_connections {
+            let idlest = connections
+                .conns
+                .iter()
+                .filter(|(_, conn)| conn.inflight == 0)
+                .min_by_key(|(_, conn)| conn.last_active)
+                .map(|(id, _)| *id);
+            match idlest {
+                Some(id) => {
+                    let conn = connections.conns.remove(&id).unwrap();
+                    let _ = conn.evict.send(());
+                    TCP_EVICT_INT_COUNT.inc();
+                    TCP_CONNECTION_INT_GAUGE.dec();
+                }
+                None => {
+                    TCP_REJECT_INT_COUNT.inc();
+                    return None;
+                }
+            }
+        }
+
+        let id = connections.next_id;
+        connections.next_id += 1;
+        let (sender, receiver) = oneshot::channel();
+        connections.conns.insert(
+            id,
+            Connection {
+                last_active: Instant::now(),
+                inflight: 0,
+                evict: sender,
+            },
+        );
+        TCP_CONNECTION_INT_GAUGE.inc();
+        Some((
+            ConnectionHandle {
+                id,
+                connections: self.connections.clone(),
+            },
+            receiver,
+        ))
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().conns.len()
+    }
+}
+
+impl ConnectionHandle {
+    pub fn touch(&self, inflight: usize) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(conn) = connections.conns.get_mut(&self.id) {
+            conn.last_active = Instant::now();
+            conn.inflight = inflight;
+        }
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.conns.remove(&self.id).is_some() {
+            TCP_CONNECTION_INT_GAUGE.dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_idlest_connection() {
+        let table = ConnectionTable::new(2);
+        let (c1, mut e1) = table.register().unwrap();
+        let (c2, mut e2) = table.register().unwrap();
+        c1.touch(1);
+        c2.touch(0);
+
+        //c2 has no inflight query, so it's evicted
+        let (c3, mut e3) = table.register().unwrap();
+        assert_eq!(e2.try_recv(), Ok(Some(())));
+        assert_eq!(e1.try_recv(), Ok(None));
+        drop(c2);
+
+        //both are busy
+        c3.touch(1);
+        assert!(table.register().is_none());
+        assert_eq!(e3.try_recv(), Ok(None));
+
+        drop(c1);
+        assert!(table.register().is_some());
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use super::connection_table::ConnectionHandle;
+use super::edns_option::{append_edns_option, EDNS_NSID};
+use super::frame::{log_query, resolve_frame, QueryFrame};
+use super::tcp_server::TcpOptions;
+use super::tls_server::load_tls_config;
+use crate::config::Protocol;
+use crate::types::Handler;
+use anyhow::bail;
+use futures::channel::oneshot;
+use hyper::{
+    body::HttpBody,
+    header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request as HttpRequest, Response as HttpResponse, StatusCode,
+};
+use r53::{Message, MessageRender, SectionType};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3); //3 secs
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+const MAX_DNS_MESSAGE_LEN: usize = 65535;
+
+pub struct DohServer<H> {
+    handler: H,
+    path: Arc<String>,
+    acceptor: Option<TlsAcceptor>,
+    options: TcpOptions,
+}
+
+impl<H: Handler + Send + Sync> DohServer<H> {
+    pub fn new(
+        handler: H,
+        path: &str,
+        cert_path: Option<&str>,
+        key_path: Option<&str>,
+        options: TcpOptions,
+    ) -> anyhow::Result<Self> {
+        let acceptor = match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let config = load_tls_config(cert_path, key_path, &[b"h2", b"http/1.1"])?;
+                Some(TlsAcceptor::from(config))
+            }
+            (None, None) => None,
+            _ => bail!("doh over tls needs both cert and key"),
+        };
+        Ok(DohServer {
+            handler,
+            path: Arc::new(path.to_string()),
+            acceptor,
+            options: options.with_transport(Protocol::Doh),
+        })
+    }
+
+    pub async fn run(self, mut listener: TcpListener) {
+        let mut shutdown = self.options.shutdown();
+        loop {
+            let conn = tokio::select! {
+                conn = listener.accept() => conn,
+                _ = &mut shutdown => break,
+            };
+            let (stream, src) = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("doh accept failed: {}", e);
+                    continue;
+                }
+            };
+            let local = match stream.local_addr() {
+                Ok(local) => local,
+                Err(_) => continue,
+            };
+            let (conn, evicted) = match self.options.register_connection() {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let handler = self.handler.clone();
+            let path = self.path.clone();
+            let options = self.options.clone();
+            if let Some(acceptor) = self.acceptor.clone() {
+                tokio::spawn(async move {
+                    match timeout(DEFAULT_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            serve_connection(
+                                handler, path, stream, src, local, options, conn, evicted,
+                            )
+                            .await
+                        }
+                        Ok(Err(e)) => debug!("tls handshake with {} failed: {}", src, e),
+                        Err(_) => debug!("tls handshake with {} timeout", src),
+                    }
+                });
+            } else {
+                tokio::spawn(serve_connection(
+                    handler, path, stream, src, local, options, conn, evicted,
+                ));
+            }
+        }
+    }
+}
+
+//requests in process are recorded in connection table, so busy
+//connection isn't evicted
+struct Inflight {
+    conn: ConnectionHandle,
+    count: AtomicUsize,
+}
+
+impl Inflight {
+    fn begin(&self) {
+        self.conn
+            .touch(self.count.fetch_add(1, Ordering::Relaxed) + 1);
+    }
+
+    fn end(&self) {
+        self.conn
+            .touch(self.count.fetch_sub(1, Ordering::Relaxed) - 1);
+    }
+}
+
+//connection is registered like tcp and tls ones, on shutdown or
+//eviction it stops taking new requests and is closed once the ones
+//in process are answered
+async fn serve_connection<H, S>(
+    handler: H,
+    path: Arc<String>,
+    stream: S,
+    src: SocketAddr,
+    local: SocketAddr,
+    options: TcpOptions,
+    conn: ConnectionHandle,
+    mut evicted: oneshot::Receiver<()>,
+) where
+    H: Handler,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let inflight = Arc::new(Inflight {
+        conn,
+        count: AtomicUsize::new(0),
+    });
+    let service = service_fn(move |req| {
+        let handler = handler.clone();
+        let path = path.clone();
+        let inflight = inflight.clone();
+        async move {
+            inflight.begin();
+            let response = handle_request(handler, path, req, src, local).await;
+            inflight.end();
+            response
+        }
+    });
+    let connection = Http::new().serve_connection(stream, service);
+    tokio::pin!(connection);
+    let mut shutdown = options.shutdown();
+    let mut closing = false;
+    let result = loop {
+        tokio::select! {
+            result = connection.as_mut() => break result,
+            _ = &mut shutdown, if !closing => {
+                closing = true;
+                connection.as_mut().graceful_shutdown();
+            }
+            _ = &mut evicted, if !closing => {
+                closing = true;
+                connection.as_mut().graceful_shutdown();
+            }
+        }
+    };
+    if let Err(e) = result {
+        debug!("doh connection with {} failed: {}", src, e);
+    }
+}
+
+async fn handle_request<H: Handler>(
+    mut handler: H,
+    path: Arc<String>,
+    req: HttpRequest<Body>,
+    src: SocketAddr,
+    local: SocketAddr,
+) -> Result<HttpResponse<Body>, Infallible> {
+    if req.uri().path() != path.as_str() {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+    let time = SystemTime::now();
+
+    let wire = match *req.method() {
+        Method::GET => match req.uri().query().and_then(get_dns_param) {
+            Some(wire) if wire.len() > MAX_DNS_MESSAGE_LEN => {
+                return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE))
+            }
+            Some(wire) => wire,
+            None => return Ok(status_response(StatusCode::BAD_REQUEST)),
+        },
+        Method::POST => {
+            let content_type = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok());
+            if content_type != Some(DNS_MESSAGE_CONTENT_TYPE) {
+                return Ok(status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            let content_len = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<usize>().ok());
+            if content_len.map_or(false, |len| len > MAX_DNS_MESSAGE_LEN) {
+                return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            match read_body(req.into_body()).await {
+                Ok(wire) => wire,
+                Err(status) => return Ok(status_response(status)),
+            }
+        }
+        _ => return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    let frame = match Message::from_wire(&wire) {
+        Ok(request) => QueryFrame::query(request, &wire),
+        Err(_) => QueryFrame::Malformed(wire),
+    };
+
+    match resolve_frame(&mut handler, frame, src, local, Protocol::Doh).await {
+        Some(response) => {
+            log_query(&response, src, Protocol::Doh, time, false);
+            let mut render = MessageRender::new();
+            response.response.to_wire(&mut render);
+            let mut wire = render.take_data();
+            if let Some(ref nsid) = response.nsid {
+                append_edns_option(&mut wire, EDNS_NSID, nsid);
+            }
+            if let Some(ref tap) = response.tap {
+                tap.log_response(&wire);
+            }
+            let mut builder = HttpResponse::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE);
+            if let Some(ttl) = min_ttl(&response.response) {
+                builder = builder.header(CACHE_CONTROL, format!("max-age={}", ttl));
+            }
+            Ok(builder.body(Body::from(wire)).unwrap())
+        }
+        None => Ok(status_response(StatusCode::BAD_REQUEST)),
+    }
+}
+
+//body without content length is read in chunks, and it's rejected once
+//it's too long instead of being buffered as a whole
+async fn read_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut wire = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if wire.len() + chunk.len() > MAX_DNS_MESSAGE_LEN {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        wire.extend_from_slice(&chunk);
+    }
+    Ok(wire)
+}
+
+fn status_response(status: StatusCode) -> HttpResponse<Body> {
+    HttpResponse::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+//rfc8484 requires base64url without padding, but be tolerant with
+//clients which add padding
+fn get_dns_param(query: &str) -> Option<Vec<u8>> {
+    query
+        .split('&')
+        .find_map(|kv| kv.strip_prefix("dns="))
+        .and_then(|v| base64::decode_config(v.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok())
+}
+
+fn min_ttl(message: &Message) -> Option<u32> {
+    [
+        SectionType::Answer,
+        SectionType::Authority,
+        SectionType::Additional,
+    ]
+    .iter()
+    .filter_map(|section| message.section(*section))
+    .flatten()
+    .map(|rrset| rrset.ttl.0)
+    .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TcpConfig;
+    use crate::types::{Request, Response};
+    use futures::{future, stream, FutureExt};
+    use hyper::{body, Client};
+    use r53::{HeaderFlag, MessageBuilder, Name, RRType};
+    use std::{future::Future, pin::Pin};
+    use tokio::net::TcpStream;
+    use tokio::time::delay_for;
+
+    const DOH_PATH: &str = "/dns-query";
+
+    #[derive(Clone)]
+    struct EchoHandler;
+
+    impl Handler for EchoHandler {
+        fn resolve(
+            &mut self,
+            req: Request,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Response>> + Send + '_>> {
+            let mut response = req.request;
+            MessageBuilder::new(&mut response).make_response().done();
+            Box::pin(async move { Ok(Response::new(response)) })
+        }
+    }
+
+    //answer is delayed to keep the request in process
+    #[derive(Clone)]
+    struct SlowHandler;
+
+    impl Handler for SlowHandler {
+        fn resolve(
+            &mut self,
+            req: Request,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Response>> + Send + '_>> {
+            let mut response = req.request;
+            MessageBuilder::new(&mut response).make_response().done();
+            Box::pin(async move {
+                delay_for(Duration::from_millis(500)).await;
+                Ok(Response::new(response))
+            })
+        }
+    }
+
+    async fn run_server<H: Handler + Send + Sync>(
+        handler: H,
+        options: TcpOptions,
+    ) -> (SocketAddr, String) {
+        let server = DohServer::new(handler, DOH_PATH, None, None, options).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.run(listener));
+        (addr, format!("http://{}{}", addr, DOH_PATH))
+    }
+
+    fn default_options() -> TcpOptions {
+        TcpOptions::new(&TcpConfig::default(), future::pending().boxed().shared())
+    }
+
+    fn query_wire(id: u16) -> Vec<u8> {
+        let mut request = Message::with_query(Name::new("example.org.").unwrap(), RRType::A);
+        MessageBuilder::new(&mut request).id(id).done();
+        let mut render = MessageRender::new();
+        request.to_wire(&mut render).unwrap();
+        render.take_data()
+    }
+
+    fn post_request(url: &str, body: Body) -> HttpRequest<Body> {
+        HttpRequest::post(url)
+            .header(CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+            .body(body)
+            .unwrap()
+    }
+
+    async fn check_response(response: HttpResponse<Body>, id: u16) {
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            DNS_MESSAGE_CONTENT_TYPE
+        );
+        let wire = body::to_bytes(response.into_body()).await.unwrap();
+        let message = Message::from_wire(&wire).unwrap();
+        assert_eq!(message.header.id, id);
+        assert!(message.header.is_flag_set(HeaderFlag::QueryRespone));
+    }
+
+    #[tokio::test]
+    async fn test_query_over_https() {
+        let (_, url) = run_server(EchoHandler, default_options()).await;
+        let client = Client::new();
+
+        let encoded = base64::encode_config(&query_wire(1), base64::URL_SAFE_NO_PAD);
+        let uri = format!("{}?dns={}", url, encoded).parse().unwrap();
+        check_response(client.get(uri).await.unwrap(), 1).await;
+
+        let request = post_request(&url, Body::from(query_wire(2)));
+        check_response(client.request(request).await.unwrap(), 2).await;
+
+        let uri = format!("{}?name=example.org", url).parse().unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_post() {
+        let (_, url) = run_server(EchoHandler, default_options()).await;
+        let client = Client::new();
+
+        //rejected by content length
+        let request = post_request(&url, Body::from(vec![0; MAX_DNS_MESSAGE_LEN + 1]));
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        //chunked body is rejected once it's too long
+        let chunks = (0..5).map(|_| Ok::<_, std::io::Error>(vec![0u8; 16384]));
+        let request = post_request(&url, Body::wrap_stream(stream::iter(chunks)));
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_get_dns_param() {
+        let wire = vec![0xab, 0xcd, 0x01, 0x00, 0x00, 0x01];
+        let encoded = base64::encode_config(&wire, base64::URL_SAFE_NO_PAD);
+        assert_eq!(
+            get_dns_param(&format!("ct=1&dns={}", encoded)),
+            Some(wire.clone())
+        );
+        assert_eq!(get_dns_param(&format!("dns={}==", encoded)), Some(wire));
+        assert_eq!(get_dns_param("name=example.org"), None);
+        assert_eq!(get_dns_param("dns=!!!"), None);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let options = TcpOptions::new(&TcpConfig::default(), stopped.map(|_| ()).boxed().shared());
+        let (addr, url) = run_server(SlowHandler, options.clone()).await;
+
+        let request = post_request(&url, Body::from(query_wire(1)));
+        let pending = tokio::spawn(Client::new().request(request));
+        delay_for(Duration::from_millis(100)).await;
+        assert_eq!(options.connection_count(), 1);
+        stop.send(()).unwrap();
+
+        //request in process is still answered
+        check_response(pending.await.unwrap().unwrap(), 1).await;
+        for _ in 0..10 {
+            if options.connection_count() == 0 {
+                break;
+            }
+            delay_for(Duration::from_millis(100)).await;
+        }
+        assert_eq!(options.connection_count(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//r53 doesn't expose edns options, so they are handled on wire format
+
+pub const EDNS_NSID: u16 = 3;
+pub const EDNS_TCP_KEEPALIVE: u16 = 11;
+
+const HEADER_LEN: usize = 12;
+const OPT_TYPE: u16 = 41;
+
+fn read_u16(wire: &[u8], pos: usize) -> Option<u16> {
+    Some(((*wire.get(pos)? as u16) << 8) | (*wire.get(pos + 1)? as u16))
+}
+
+fn skip_name(wire: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *wire.get(pos)? as usize;
+        match len & 0xc0 {
+            0xc0 => {
+                wire.get(pos + 1)?;
+                return Some(pos + 2);
+            }
+            0 => {
+                pos += 1;
+                if len == 0 {
+                    return Some(pos);
+                }
+                pos += len;
+            }
+            _ => return None,
+        }
+    }
+}
+
+//return the offset of rdlength and the end of rdata of the OPT RR
+fn find_opt_rdata(wire: &[u8]) -> Option<(usize, usize)> {
+    if wire.len() < HEADER_LEN {
+        return None;
+    }
+    let qd_count = read_u16(wire, 4)?;
+    let rr_count =
+        read_u16(wire, 6)? as usize + read_u16(wire, 8)? as usize + read_u16(wire, 10)? as usize;
+    let mut pos = HEADER_LEN;
+    for _ in 0..qd_count {
+        pos = skip_name(wire, pos)? + 4;
+    }
+
+    let mut opt = None;
+    for _ in 0..rr_count {
+        pos = skip_name(wire, pos)?;
+        let typ = read_u16(wire, pos)?;
+        let rdlen_pos = pos + 8;
+        let rdata_end = rdlen_pos + 2 + read_u16(wire, rdlen_pos)? as usize;
+        if rdata_end > wire.len() {
+            return None;
+        }
+        if typ == OPT_TYPE {
+            opt = Some((rdlen_pos, rdata_end));
+        }
+        pos = rdata_end;
+    }
+    opt
+}
+
+pub fn find_edns_option(wire: &[u8], code: u16) -> Option<Vec<u8>> {
+    let (rdlen_pos, rdata_end) = find_opt_rdata(wire)?;
+    let mut pos = rdlen_pos + 2;
+    while pos + 4 <= rdata_end {
+        let option_code = read_u16(wire, pos)?;
+        let option_end = pos + 4 + read_u16(wire, pos + 2)? as usize;
+        if option_end > rdata_end {
+            return None;
+        }
+        if option_code == code {
+            return Some(wire[pos + 4..option_end].to_vec());
+        }
+        pos = option_end;
+    }
+    None
+}
+
+//only works when OPT is the last RR, otherwise moving RRs after it
+//may break name compression
+pub fn append_edns_option(wire: &mut Vec<u8>, code: u16, data: &[u8]) -> bool {
+    let (rdlen_pos, rdata_end) = match find_opt_rdata(wire) {
+        Some(opt) => opt,
+        None => return false,
+    };
+    let rdlen = (rdata_end - rdlen_pos - 2) + 4 + data.len();
+    if rdata_end != wire.len() || rdlen > u16::max_value() as usize {
+        return false;
+    }
+
+    wire.extend_from_slice(&code.to_be_bytes());
+    wire.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    wire.extend_from_slice(data);
+    wire[rdlen_pos..rdlen_pos + 2].copy_from_slice(&(rdlen as u16).to_be_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //query example.org. A with an OPT RR in additional section
+    fn query_with_opt() -> Vec<u8> {
+        let mut wire = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        wire.extend_from_slice(b"\x07example\x03org\x00");
+        wire.extend_from_slice(&[0, 1, 0, 1]);
+        wire.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        wire
+    }
+
+    #[test]
+    fn test_append_and_find_option() {
+        let mut wire = query_with_opt();
+        assert_eq!(find_edns_option(&wire, EDNS_TCP_KEEPALIVE), None);
+        assert!(append_edns_option(&mut wire, EDNS_TCP_KEEPALIVE, &[0, 100]));
+        assert_eq!(
+            find_edns_option(&wire, EDNS_TCP_KEEPALIVE),
+            Some(vec![0, 100])
+        );
+        assert!(append_edns_option(&mut wire, EDNS_NSID, b"ns1"));
+        assert_eq!(find_edns_option(&wire, EDNS_NSID), Some(b"ns1".to_vec()));
+        assert_eq!(
+            find_edns_option(&wire, EDNS_TCP_KEEPALIVE),
+            Some(vec![0, 100])
+        );
+        //rdlength of OPT
+        assert_eq!(&wire[38..40], &[0, 13]);
+    }
+
+    #[test]
+    fn test_without_opt() {
+        let mut wire = query_with_opt();
+        wire.truncate(wire.len() - 11);
+        wire[11] = 0;
+        assert!(!append_edns_option(&mut wire, EDNS_TCP_KEEPALIVE, &[]));
+        assert_eq!(find_edns_option(&wire, EDNS_TCP_KEEPALIVE), None);
+
+        //truncated message
+        let wire = query_with_opt();
+        assert_eq!(find_opt_rdata(&wire[..wire.len() - 1]), None);
+    }
+}
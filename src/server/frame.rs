@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use super::edns_option::{find_edns_option, EDNS_NSID};
+use crate::config::Protocol;
+use crate::dnstap::{self, ClientTap};
+use crate::logger::{sampled_query_logger, QueryLogEntry};
+use crate::types::{error_response, Handler, Request, Response};
+use prometheus::IntCounter;
+use r53::{HeaderFlag, Message, Rcode};
+
+lazy_static! {
+    static ref FORMERR_INT_COUNT: IntCounter =
+        register_int_counter!("formerr_count", "formerr response count").unwrap();
+    static ref SERVFAIL_INT_COUNT: IntCounter =
+        register_int_counter!("servfail_count", "servfail response count").unwrap();
+    static ref DROP_INT_COUNT: IntCounter =
+        register_int_counter!("drop_count", "dropped query count").unwrap();
+}
+
+const HEADER_LEN: usize = 12;
+
+pub enum QueryFrame {
+    //edns options aren't kept by message, so the ones we care about
+    //are picked up from wire format. raw is only kept for dnstap
+    Query {
+        request: Message,
+        nsid: bool,
+        raw: Option<Vec<u8>>,
+    },
+    Malformed(Vec<u8>),
+}
+
+impl QueryFrame {
+    pub fn query(request: Message, wire: &[u8]) -> Self {
+        QueryFrame::Query {
+            request,
+            nsid: find_edns_option(wire, EDNS_NSID).is_some(),
+            raw: if dnstap::client_enabled() {
+                Some(wire.to_vec())
+            } else {
+                None
+            },
+        }
+    }
+
+    //query is logged with the bytes received
+    pub fn tap(
+        &self,
+        client: SocketAddr,
+        server: SocketAddr,
+        transport: Protocol,
+    ) -> Option<ClientTap> {
+        let raw = match self {
+            QueryFrame::Query { raw: Some(raw), .. } => raw,
+            QueryFrame::Query { raw: None, .. } => return None,
+            QueryFrame::Malformed(raw) => raw,
+        };
+        dnstap::log_client_query(client, server, transport, raw)
+    }
+}
+
+pub async fn resolve_frame<H: Handler>(
+    handler: &mut H,
+    frame: QueryFrame,
+    client: SocketAddr,
+    server: SocketAddr,
+    transport: Protocol,
+) -> Option<Response> {
+    let tap = frame.tap(client, server, transport);
+    let mut response = answer_frame(handler, frame, client, server, transport).await?;
+    response.tap = tap;
+    Some(response)
+}
+
+//queries are logged when their responses are sent or dropped by the
+//servers, so the log shows what clients actually get, including the
+//responses made by server itself. time is when the query is received
+pub fn log_query(
+    response: &Response,
+    client: SocketAddr,
+    transport: Protocol,
+    time: SystemTime,
+    dropped: bool,
+) {
+    if let Some(query_log) = sampled_query_logger() {
+        query_log.log(QueryLogEntry {
+            time,
+            client,
+            question: response.response.question.clone(),
+            header: response.response.header.clone(),
+            latency: time.elapsed().unwrap_or_default(),
+            cache_hit: response.cache_hit,
+            transport,
+            served_by: response.served_by,
+            dropped,
+        });
+    }
+}
+
+//every query is answered except the ones too short to have a header
+//or with qr bit set, replying to them may cause a loop
+async fn answer_frame<H: Handler>(
+    handler: &mut H,
+    frame: QueryFrame,
+    client: SocketAddr,
+    server: SocketAddr,
+    transport: Protocol,
+) -> Option<Response> {
+    let (request, nsid) = match frame {
+        QueryFrame::Query { request, nsid, .. } => (request, nsid),
+        QueryFrame::Malformed(raw) => {
+            return match formerr_response(&raw) {
+                Some(response) => {
+                    FORMERR_INT_COUNT.inc();
+                    Some(Response::new(response))
+                }
+                None => {
+                    DROP_INT_COUNT.inc();
+                    None
+                }
+            };
+        }
+    };
+
+    if request.header.is_flag_set(HeaderFlag::QueryRespone) {
+        DROP_INT_COUNT.inc();
+        return None;
+    }
+
+    if request.header.qd_count != 1 || request.question.is_none() {
+        FORMERR_INT_COUNT.inc();
+        return Some(Response::new(error_response(&request, Rcode::FormErr)));
+    }
+
+    let query = Request::new(request, client)
+        .with_server(server)
+        .with_transport(transport)
+        .with_nsid(nsid);
+    let request = query.request.clone();
+    match handler.resolve(query).await {
+        Ok(response) => Some(response),
+        Err(e) => {
+            debug!("resolve query from {} failed: {}", client, e);
+            SERVFAIL_INT_COUNT.inc();
+            Some(Response::new(error_response(&request, Rcode::ServFail)))
+        }
+    }
+}
+
+//build a header only response, keep id, opcode and rd of the query
+fn formerr_response(raw: &[u8]) -> Option<Message> {
+    if raw.len() < HEADER_LEN || raw[2] & 0x80 != 0 {
+        return None;
+    }
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = raw[0];
+    header[1] = raw[1];
+    header[2] = 0x80 | (raw[2] & 0x79);
+    header[3] = 0x01;
+    Message::from_wire(&header).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::Opcode;
+
+    #[test]
+    fn test_formerr_response() {
+        assert!(formerr_response(&[0x12, 0x34, 0x01]).is_none());
+        //qr bit is set
+        assert!(formerr_response(&[0x12, 0x34, 0x81, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xff]).is_none());
+
+        let response =
+            formerr_response(&[0x12, 0x34, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 0, 0xff]).unwrap();
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(response.header.rcode, Rcode::FormErr);
+        assert_eq!(response.header.opcode, Opcode::Query);
+        assert!(response.header.is_flag_set(HeaderFlag::QueryRespone));
+        assert!(response.header.is_flag_set(HeaderFlag::RecursionDesired));
+        assert_eq!(response.header.qd_count, 0);
+        assert!(response.question.is_none());
+    }
+}
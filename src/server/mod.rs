@@ -1,6 +1,16 @@
+mod connection_table;
+mod doh_server;
+mod edns_option;
+mod frame;
+mod query_limiter;
+mod rrl;
 mod server;
+mod socket;
 mod tcp_server;
 mod tcp_stream_coder;
+mod tls_server;
+#[cfg(target_os = "linux")]
+mod udp_batch;
 mod udp_server;
 mod udp_stream_coder;
 
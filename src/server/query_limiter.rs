@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::QueryLimitConfig;
+use prometheus::{IntCounter, IntGauge};
+
+lazy_static! {
+    static ref INFLIGHT_INT_GAUGE: IntGauge =
+        register_int_gauge!("inflight", "query in process").unwrap();
+    static ref INFLIGHT_LIMIT_INT_COUNT: IntCounter =
+        register_int_counter!("inflight_limit_count", "query rejected by inflight limit").unwrap();
+    static ref PREFIX_LIMIT_INT_COUNT: IntCounter =
+        register_int_counter!("prefix_limit_count", "query rejected by per prefix limit").unwrap();
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimitExceeded {
+    Inflight,
+    Prefix,
+}
+
+struct Limits {
+    max_inflight: usize,
+    max_inflight_per_prefix: usize,
+    v4_prefix_len: u8,
+    v6_prefix_len: u8,
+    inflight: AtomicUsize,
+    prefixes: Mutex<HashMap<IpAddr, usize>>,
+}
+
+//shared by all the udp listeners, every accepted query holds a permit
+//until it's answered
+#[derive(Clone)]
+pub struct QueryLimiter {
+    limits: Arc<Limits>,
+}
+
+pub struct QueryPermit {
+    limits: Arc<Limits>,
+    prefix: IpAddr,
+}
+
+impl QueryLimiter {
+    pub fn new(conf: &QueryLimitConfig) -> Self {
+        QueryLimiter {
+            limits: Arc::new(Limits {
+                max_inflight: conf.max_inflight,
+                max_inflight_per_prefix: conf.max_inflight_per_prefix,
+                v4_prefix_len: conf.v4_prefix_len.min(32),
+                v6_prefix_len: conf.v6_prefix_len.min(128),
+                inflight: AtomicUsize::new(0),
+                prefixes: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn acquire(&self, client: IpAddr) -> Result<QueryPermit, LimitExceeded> {
+        let limits = &self.limits;
+        if limits.inflight.fetch_add(1, Ordering::SeqCst) >= limits.max_inflight {
+            limits.inflight.fetch_sub(1, Ordering::SeqCst);
+            INFLIGHT_LIMIT_INT_COUNT.inc();
+            return Err(LimitExceeded::Inflight);
+        }
+
+        let prefix = mask_addr(client, limits.v4_prefix_len, limits.v6_prefix_len);
+        {
+            let mut prefixes = limits.prefixes.lock().unwrap();
+            let count = prefixes.entry(prefix).or_insert(0);
+            if *count >= limits.max_inflight_per_prefix {
+                drop(prefixes);
+                limits.inflight.fetch_sub(1, Ordering::SeqCst);
+                PREFIX_LIMIT_INT_COUNT.inc();
+                return Err(LimitExceeded::Prefix);
+            }
+            *count += 1;
+        }
+
+        INFLIGHT_INT_GAUGE.inc();
+        Ok(QueryPermit {
+            limits: limits.clone(),
+            prefix,
+        })
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.limits.inflight.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for QueryPermit {
+    fn drop(&mut self) {
+        {
+            let mut prefixes = self.limits.prefixes.lock().unwrap();
+            if let Some(count) = prefixes.get_mut(&self.prefix) {
+                *count -= 1;
+                if *count == 0 {
+                    prefixes.remove(&self.prefix);
+                }
+            }
+        }
+        self.limits.inflight.fetch_sub(1, Ordering::SeqCst);
+        INFLIGHT_INT_GAUGE.dec();
+    }
+}
+
+pub fn mask_addr(addr: IpAddr, v4_prefix_len: u8, v6_prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::max_value()
+                .checked_shl(32 - v4_prefix_len as u32)
+                .unwrap_or(0);
+            IpAddr::V4((u32::from(v4) & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::max_value()
+                .checked_shl(128 - v6_prefix_len as u32)
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(v6) & mask).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OverloadPolicy;
+    use std::str::FromStr;
+
+    fn new_limiter(max_inflight: usize, max_inflight_per_prefix: usize) -> QueryLimiter {
+        QueryLimiter::new(&QueryLimitConfig {
+            max_inflight,
+            max_inflight_per_prefix,
+            v4_prefix_len: 24,
+            v6_prefix_len: 56,
+            overload_policy: OverloadPolicy::Drop,
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_mask_addr() {
+        assert_eq!(mask_addr(ip("1.2.3.4"), 24, 56), ip("1.2.3.0"));
+        assert_eq!(mask_addr(ip("1.2.3.4"), 0, 56), ip("0.0.0.0"));
+        assert_eq!(mask_addr(ip("1.2.3.4"), 32, 56), ip("1.2.3.4"));
+        assert_eq!(
+            mask_addr(ip("2001:db8:1:2:3::1"), 24, 56),
+            ip("2001:db8:1::")
+        );
+    }
+
+    #[test]
+    fn test_inflight_limit() {
+        let limiter = new_limiter(2, 10);
+        let p1 = limiter.acquire(ip("1.1.1.1")).unwrap();
+        let _p2 = limiter.acquire(ip("2.2.2.2")).unwrap();
+        assert_eq!(
+            limiter.acquire(ip("3.3.3.3")).err(),
+            Some(LimitExceeded::Inflight)
+        );
+        assert_eq!(limiter.inflight(), 2);
+
+        drop(p1);
+        assert_eq!(limiter.inflight(), 1);
+        assert!(limiter.acquire(ip("3.3.3.3")).is_ok());
+    }
+
+    #[test]
+    fn test_prefix_limit() {
+        let limiter = new_limiter(10, 2);
+        let p1 = limiter.acquire(ip("1.1.1.1")).unwrap();
+        let _p2 = limiter.acquire(ip("1.1.1.2")).unwrap();
+        assert_eq!(
+            limiter.acquire(ip("1.1.1.3")).err(),
+            Some(LimitExceeded::Prefix)
+        );
+        assert!(limiter.acquire(ip("1.1.2.1")).is_ok());
+        assert_eq!(limiter.inflight(), 2);
+
+        drop(p1);
+        assert!(limiter.acquire(ip("1.1.1.3")).is_ok());
+    }
+}
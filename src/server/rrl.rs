@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::query_limiter::mask_addr;
+use crate::config::RrlConfig;
+use crate::types::{Acl, Response, ServedBy};
+use prometheus::IntCounter;
+use r53::{HeaderFlag, Message, MessageBuilder, Name, RRType, Rcode, SectionType};
+
+lazy_static! {
+    static ref RRL_DROP_INT_COUNT: IntCounter =
+        register_int_counter!("rrl_drop", "response dropped by rrl").unwrap();
+    static ref RRL_SLIP_INT_COUNT: IntCounter =
+        register_int_counter!("rrl_slip", "truncated response sent by rrl").unwrap();
+}
+
+//part of the table evicted when it's full
+const EVICT_RATIO: usize = 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RrlAction {
+    Send,
+    Slip,
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum ResponseKind {
+    Answer,
+    NXDomain,
+    NoData,
+    Referral,
+    Error,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+struct BucketKey {
+    prefix: IpAddr,
+    kind: ResponseKind,
+    name: Option<Name>,
+    typ: Option<RRType>,
+}
+
+struct Bucket {
+    balance: f64,
+    last_update: Instant,
+    penalty_count: u32,
+}
+
+struct Limiter {
+    responses_per_second: f64,
+    nxdomains_per_second: f64,
+    errors_per_second: f64,
+    window: f64,
+    slip: u32,
+    v4_prefix_len: u8,
+    v6_prefix_len: u8,
+    max_table_size: usize,
+    exempt_clients: Acl,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+//bind style response rate limiting, only authoritative answers,
+//referrals and errors are limited, since they are what reflection
+//attack abuses
+#[derive(Clone)]
+pub struct ResponseRateLimiter {
+    limiter: Arc<Limiter>,
+}
+
+impl ResponseRateLimiter {
+    pub fn new(conf: &RrlConfig) -> anyhow::Result<Self> {
+        let exempt_clients = Acl::new(conf.exempt_clients.iter().map(|s| s.as_ref()).collect())?;
+        Ok(ResponseRateLimiter {
+            limiter: Arc::new(Limiter {
+                responses_per_second: conf.responses_per_second as f64,
+                nxdomains_per_second: conf
+                    .nxdomains_per_second
+                    .unwrap_or(conf.responses_per_second)
+                    as f64,
+                errors_per_second: conf.errors_per_second.unwrap_or(conf.responses_per_second)
+                    as f64,
+                window: conf.window.max(1) as f64,
+                slip: conf.slip,
+                v4_prefix_len: conf.v4_prefix_len.min(32),
+                v6_prefix_len: conf.v6_prefix_len.min(128),
+                max_table_size: conf.max_table_size,
+                exempt_clients,
+                buckets: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    pub fn check(&self, client: IpAddr, response: &Response) -> RrlAction {
+        let action = self.check_at(client, response, Instant::now());
+        match action {
+            RrlAction::Drop => RRL_DROP_INT_COUNT.inc(),
+            RrlAction::Slip => RRL_SLIP_INT_COUNT.inc(),
+            RrlAction::Send => {}
+        }
+        action
+    }
+
+    fn check_at(&self, client: IpAddr, response: &Response, now: Instant) -> RrlAction {
+        let limiter = &self.limiter;
+        if limiter.exempt_clients.contains(client) {
+            return RrlAction::Send;
+        }
+
+        let (kind, name, typ) = match classify_response(response) {
+            Some(classified) => classified,
+            None => return RrlAction::Send,
+        };
+        let rate = match kind {
+            ResponseKind::NXDomain => limiter.nxdomains_per_second,
+            ResponseKind::Error => limiter.errors_per_second,
+            _ => limiter.responses_per_second,
+        };
+        if rate <= 0.0 {
+            return RrlAction::Send;
+        }
+
+        let key = BucketKey {
+            prefix: mask_addr(client, limiter.v4_prefix_len, limiter.v6_prefix_len),
+            kind,
+            name,
+            typ,
+        };
+        let mut buckets = limiter.buckets.lock().unwrap();
+        if buckets.len() >= limiter.max_table_size && !buckets.contains_key(&key) {
+            let window = limiter.window;
+            buckets
+                .retain(|_, bucket| now.duration_since(bucket.last_update).as_secs_f64() < window);
+            if buckets.len() >= limiter.max_table_size {
+                evict_buckets(&mut buckets, limiter.max_table_size / EVICT_RATIO + 1);
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            balance: rate,
+            last_update: now,
+            penalty_count: 0,
+        });
+        let elapsed = now.duration_since(bucket.last_update).as_secs_f64();
+        bucket.last_update = now;
+        //allow one second burst, and remember abuse for a whole window
+        bucket.balance = (bucket.balance + elapsed * rate).min(rate) - 1.0;
+        bucket.balance = bucket.balance.max(-rate * limiter.window);
+        if bucket.balance >= 0.0 {
+            bucket.penalty_count = 0;
+            return RrlAction::Send;
+        }
+
+        bucket.penalty_count += 1;
+        if limiter.slip != 0 && bucket.penalty_count % limiter.slip == 0 {
+            RrlAction::Slip
+        } else {
+            RrlAction::Drop
+        }
+    }
+}
+
+//clients being limited are kept as long as possible, since dropping
+//them resets their debt, the oldest among the rest go first. a batch
+//is evicted at a time to avoid scanning the table for every new key
+fn evict_buckets(buckets: &mut HashMap<BucketKey, Bucket>, count: usize) {
+    let mut keys: Vec<(u32, Instant, BucketKey)> = buckets
+        .iter()
+        .map(|(key, bucket)| (bucket.penalty_count, bucket.last_update, key.clone()))
+        .collect();
+    keys.sort_unstable_by_key(|(penalty_count, last_update, _)| (*penalty_count, *last_update));
+    for (_, _, key) in keys.into_iter().take(count) {
+        buckets.remove(&key);
+    }
+}
+
+//none means the response isn't limited
+fn classify_response(response: &Response) -> Option<(ResponseKind, Option<Name>, Option<RRType>)> {
+    //errors from auth and the ones made by server itself are limited, even
+    //without aa set. errors from recursion aren't, like other recursive answers
+    let served_by = response.served_by;
+    let response = &response.response;
+    match response.header.rcode {
+        Rcode::NoError | Rcode::NXDomain => {}
+        _ => {
+            return match served_by {
+                None | Some(ServedBy::Auth) => Some((ResponseKind::Error, None, None)),
+                _ => None,
+            }
+        }
+    }
+    let question = response.question.as_ref()?;
+    let authority_owner = |typ: RRType| {
+        response
+            .section(SectionType::Authority)
+            .and_then(|rrsets| rrsets.iter().find(|rrset| rrset.typ == typ))
+            .map(|rrset| rrset.name.clone())
+    };
+    let has_answer = response
+        .section(SectionType::Answer)
+        .map_or(false, |rrsets| !rrsets.is_empty());
+
+    if !response.header.is_flag_set(HeaderFlag::AuthAnswer) {
+        return match (
+            response.header.rcode,
+            has_answer,
+            authority_owner(RRType::NS),
+        ) {
+            (Rcode::NoError, false, Some(zone)) => Some((ResponseKind::Referral, Some(zone), None)),
+            _ => None,
+        };
+    }
+
+    match response.header.rcode {
+        Rcode::NoError if has_answer => Some((
+            ResponseKind::Answer,
+            Some(question.name.clone()),
+            Some(question.typ),
+        )),
+        Rcode::NoError => Some((
+            ResponseKind::NoData,
+            authority_owner(RRType::SOA).or_else(|| Some(question.name.clone())),
+            None,
+        )),
+        _ => Some((
+            ResponseKind::NXDomain,
+            authority_owner(RRType::SOA).or_else(|| Some(question.name.clone())),
+            None,
+        )),
+    }
+}
+
+//keep header and question, client will retry with tcp
+pub fn truncated_response(mut response: Message) -> Message {
+    for section in &[
+        SectionType::Answer,
+        SectionType::Authority,
+        SectionType::Additional,
+    ] {
+        response.take_section(*section);
+    }
+    MessageBuilder::new(&mut response)
+        .set_flag(HeaderFlag::Truncation)
+        .done();
+    response.recalculate_header();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::error_response;
+    use r53::build_response;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn new_limiter(slip: u32) -> ResponseRateLimiter {
+        new_limiter_with_size(slip, 1000)
+    }
+
+    fn new_limiter_with_size(slip: u32, max_table_size: usize) -> ResponseRateLimiter {
+        ResponseRateLimiter::new(&RrlConfig {
+            responses_per_second: 2,
+            nxdomains_per_second: None,
+            errors_per_second: None,
+            window: 5,
+            slip,
+            v4_prefix_len: 24,
+            v6_prefix_len: 56,
+            max_table_size,
+            exempt_clients: vec!["10.0.0.0/8".to_string()],
+        })
+        .unwrap()
+    }
+
+    fn auth_answer() -> Response {
+        let mut response = build_response(
+            "example.org.",
+            RRType::A,
+            vec![vec!["example.org. 300 IN A 192.0.2.1"]],
+            vec![vec!["example.org. 300 IN NS ns.example.org."]],
+            vec![],
+            None,
+        )
+        .unwrap();
+        MessageBuilder::new(&mut response)
+            .set_flag(HeaderFlag::AuthAnswer)
+            .done();
+        Response::new(response).with_served_by(ServedBy::Auth)
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = new_limiter(2);
+        let response = auth_answer();
+        let client = IpAddr::from_str("1.1.1.1").unwrap();
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Send);
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Send);
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Drop);
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Slip);
+        //same prefix shares the bucket
+        let neighbour = IpAddr::from_str("1.1.1.2").unwrap();
+        assert_eq!(limiter.check_at(neighbour, &response, now), RrlAction::Drop);
+        let other = IpAddr::from_str("1.1.2.1").unwrap();
+        assert_eq!(limiter.check_at(other, &response, now), RrlAction::Send);
+        let exempt = IpAddr::from_str("10.1.1.1").unwrap();
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at(exempt, &response, now), RrlAction::Send);
+        }
+
+        //debt has to be paid back before sending again
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(client, &response, later), RrlAction::Slip);
+        let later = now + Duration::from_secs(3);
+        assert_eq!(limiter.check_at(client, &response, later), RrlAction::Send);
+    }
+
+    #[test]
+    fn test_slip_disabled() {
+        let limiter = new_limiter(0);
+        let response = auth_answer();
+        let client = IpAddr::from_str("1.1.1.1").unwrap();
+        let now = Instant::now();
+        limiter.check_at(client, &response, now);
+        limiter.check_at(client, &response, now);
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at(client, &response, now), RrlAction::Drop);
+        }
+    }
+
+    #[test]
+    fn test_error_response() {
+        let limiter = new_limiter(0);
+        let request = Message::with_query(Name::new("example.org.").unwrap(), RRType::A);
+        let client = IpAddr::from_str("1.1.1.1").unwrap();
+        let now = Instant::now();
+        for _ in 0..10 {
+            let mut response = request.clone();
+            MessageBuilder::new(&mut response).make_response().done();
+            let response = Response::new(response);
+            assert_eq!(limiter.check_at(client, &response, now), RrlAction::Send);
+        }
+
+        //errors from recursion aren't limited
+        let response = Response::new(error_response(&request, Rcode::ServFail))
+            .with_served_by(ServedBy::Recursion);
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at(client, &response, now), RrlAction::Send);
+        }
+
+        //errors share one bucket
+        let response =
+            Response::new(error_response(&request, Rcode::Refused)).with_served_by(ServedBy::Auth);
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Send);
+        let response = Response::new(error_response(&request, Rcode::ServFail));
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Send);
+        let response = Response::new(error_response(&request, Rcode::FormErr));
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Drop);
+        let other = IpAddr::from_str("1.1.2.1").unwrap();
+        let response = Response::new(error_response(&request, Rcode::Refused));
+        assert_eq!(limiter.check_at(other, &response, now), RrlAction::Send);
+        assert_eq!(limiter.check_at(other, &response, now), RrlAction::Send);
+        assert_eq!(limiter.check_at(other, &response, now), RrlAction::Drop);
+    }
+
+    #[test]
+    fn test_table_full() {
+        let limiter = new_limiter_with_size(0, 4);
+        let response = auth_answer();
+        let client = IpAddr::from_str("1.1.1.1").unwrap();
+        let now = Instant::now();
+        limiter.check_at(client, &response, now);
+        limiter.check_at(client, &response, now);
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Drop);
+
+        //client being limited survives the new keys
+        for i in 2..10 {
+            let other = IpAddr::from_str(&format!("1.1.{}.1", i)).unwrap();
+            assert_eq!(limiter.check_at(other, &response, now), RrlAction::Send);
+            assert!(limiter.limiter.buckets.lock().unwrap().len() <= 4);
+        }
+        assert_eq!(limiter.check_at(client, &response, now), RrlAction::Drop);
+    }
+
+    #[test]
+    fn test_truncated_response() {
+        let response = truncated_response(auth_answer().response);
+        assert!(response.header.is_flag_set(HeaderFlag::Truncation));
+        assert_eq!(response.header.an_count, 0);
+        assert_eq!(response.header.ns_count, 0);
+        assert!(response.question.is_some());
+    }
+}
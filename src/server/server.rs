@@ -1,22 +1,151 @@
-use super::{tcp_server::TcpServer, udp_server::UdpServer};
-use crate::config::ServerConfig;
+use super::{
+    doh_server::DohServer,
+    query_limiter::QueryLimiter,
+    rrl::ResponseRateLimiter,
+    socket::{bind_tcp, bind_udp},
+    tcp_server::{TcpOptions, TcpServer},
+    tls_server::TlsServer,
+    udp_server::{calculate_qps, UdpServer},
+};
+use crate::config::{
+    ListenerConfig, Protocol, QueryLimitConfig, RrlConfig, ServerConfig, TcpConfig, UdpConfig,
+};
 use crate::types::Handler;
+use anyhow::{anyhow, Context};
+use futures::future::{self, BoxFuture, FutureExt, Shared};
+use std::future::Future;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+//resolved once server begins to shut down
+pub type Shutdown = Shared<BoxFuture<'static, ()>>;
+
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct Server {
-    addr: SocketAddr,
+    listeners: Vec<ListenerConfig>,
+    limit: QueryLimitConfig,
+    rrl: Option<RrlConfig>,
+    enable_tcp: bool,
+    tcp: TcpConfig,
+    udp: UdpConfig,
+    udp_workers: usize,
+    shutdown_timeout: Duration,
 }
 
 impl Server {
     pub fn new(conf: &ServerConfig) -> Self {
-        let addr = conf.address.parse().unwrap();
-        Server { addr }
+        Server {
+            listeners: conf.listeners.clone(),
+            limit: conf.limit.clone(),
+            rrl: conf.rrl.clone(),
+            enable_tcp: conf.enable_tcp,
+            tcp: conf.tcp.clone(),
+            udp: conf.udp.clone(),
+            udp_workers: if conf.udp.workers == 0 {
+                num_cpus::get()
+            } else {
+                conf.udp.workers
+            },
+            shutdown_timeout: Duration::from_secs(conf.shutdown_timeout),
+        }
     }
 
-    pub async fn run<H: Handler + Send + Sync>(&self, handler: H) {
-        let mut udp_server = UdpServer::new(handler.clone());
-        let tcp_server = TcpServer::new(handler);
-        tokio::spawn(tcp_server.run(self.addr));
-        udp_server.run(self.addr).await
+    //all the sockets are bound before serving, so any invalid listener
+    //is reported as an error instead of leaving a partially working server.
+    //after shutdown, listeners stop receiving new queries and queries in
+    //process are waited until shutdown timeout
+    pub async fn run<H, F>(&self, handler: H, shutdown: F) -> anyhow::Result<()>
+    where
+        H: Handler + Send + Sync,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown: Shutdown = shutdown.boxed().shared();
+        let limiter = QueryLimiter::new(&self.limit);
+        let rrl = match self.rrl {
+            Some(ref conf) => {
+                Some(ResponseRateLimiter::new(conf).context("invalid rrl exempt clients")?)
+            }
+            None => None,
+        };
+        let tcp_options = TcpOptions::new(&self.tcp, shutdown.clone());
+        let mut servers = Vec::new();
+        for conf in &self.listeners {
+            let addr: SocketAddr = conf
+                .address
+                .parse()
+                .with_context(|| format!("invalid listen address {}", conf.address))?;
+            for protocol in &conf.protocols {
+                let server = match protocol {
+                    Protocol::Udp => {
+                        for socket in bind_udp(addr, self.udp_workers)? {
+                            let udp_server = UdpServer::new(
+                                handler.clone(),
+                                limiter.clone(),
+                                self.limit.overload_policy,
+                                rrl.clone(),
+                            );
+                            let server = udp_server
+                                .spawn(socket, self.udp.batch_size, shutdown.clone())
+                                .with_context(|| format!("serve udp on {} failed", addr))?;
+                            servers.push(server);
+                        }
+                        continue;
+                    }
+                    Protocol::Tcp if !self.enable_tcp => continue,
+                    Protocol::Tcp => {
+                        let listener = bind_tcp(addr)?;
+                        let tcp_server = TcpServer::new(handler.clone(), tcp_options.clone());
+                        tokio::spawn(tcp_server.run(listener))
+                    }
+                    Protocol::Dot => {
+                        let (cert_path, key_path) = match (&conf.cert_path, &conf.key_path) {
+                            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+                            _ => {
+                                return Err(anyhow!(
+                                    "dot listener on {} has no cert or key",
+                                    conf.address
+                                ))
+                            }
+                        };
+                        let tls_server = TlsServer::new(
+                            handler.clone(),
+                            cert_path,
+                            key_path,
+                            tcp_options.clone(),
+                        )?;
+                        let listener = bind_tcp(addr)?;
+                        tokio::spawn(tls_server.run(listener))
+                    }
+                    Protocol::Doh => {
+                        let doh_server = DohServer::new(
+                            handler.clone(),
+                            &conf.doh_path,
+                            conf.cert_path.as_deref(),
+                            conf.key_path.as_deref(),
+                            tcp_options.clone(),
+                        )?;
+                        let listener = bind_tcp(addr)?;
+                        tokio::spawn(doh_server.run(listener))
+                    }
+                };
+                servers.push(server);
+            }
+        }
+
+        if servers.is_empty() {
+            return Err(anyhow!("no listener is configured"));
+        }
+        tokio::spawn(calculate_qps());
+        future::join_all(servers).await;
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while (limiter.inflight() > 0 || tcp_options.connection_count() > 0)
+            && Instant::now() < deadline
+        {
+            delay_for(DRAIN_CHECK_INTERVAL).await;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,55 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::net::TcpListener;
+
+const TCP_LISTEN_BACKLOG: i32 = 1024;
+
+//v6 sockets are created with IPV6_V6ONLY, so "0.0.0.0:53" and "[::]:53"
+//can be listened at the same time
+fn new_socket(addr: SocketAddr, typ: Type, protocol: Protocol) -> std::io::Result<Socket> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, typ, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+//with more than one socket, SO_REUSEPORT is set and kernel distributes
+//packets among them by flow hash
+pub fn bind_udp(addr: SocketAddr, count: usize) -> anyhow::Result<Vec<std::net::UdpSocket>> {
+    let count = count.max(1);
+    (0..count)
+        .map(|_| {
+            new_socket(addr, Type::dgram(), Protocol::udp())
+                .and_then(|socket| {
+                    if count > 1 {
+                        socket.set_reuse_port(true)?;
+                    }
+                    socket.bind(&SockAddr::from(addr))?;
+                    Ok(socket.into_udp_socket())
+                })
+                .with_context(|| format!("bind udp socket to {} failed", addr))
+        })
+        .collect()
+}
+
+pub fn bind_tcp(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let socket = new_socket(addr, Type::stream(), Protocol::tcp())
+        .and_then(|socket| {
+            //allow restart while old connections are still in TIME_WAIT
+            socket.set_reuse_address(true)?;
+            socket.bind(&SockAddr::from(addr))?;
+            socket.listen(TCP_LISTEN_BACKLOG)?;
+            Ok(socket)
+        })
+        .with_context(|| format!("listen tcp socket on {} failed", addr))?;
+    Ok(TcpListener::from_std(socket.into_tcp_listener())?)
+}
@@ -1,39 +1,283 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    io, iter, mem,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
 
+use super::connection_table::{ConnectionHandle, ConnectionTable};
+use super::frame::{log_query, resolve_frame};
+use super::server::Shutdown;
 use super::tcp_stream_coder::TcpStreamCoder;
-use crate::types::{Handler, Request};
-use futures::{SinkExt, StreamExt};
+use crate::config::{Protocol, TcpConfig};
+use crate::types::{Handler, Response};
+use futures::{channel::oneshot, stream::FuturesUnordered, Sink, SinkExt, StreamExt};
+use r53::RRType;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::time::timeout;
+use tokio::time::{delay_for, timeout};
 use tokio_util::codec::Framed;
 
-const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_secs(3); //3 secs
+#[derive(Clone)]
+pub struct TcpOptions {
+    idle_timeout: Duration,
+    send_timeout: Duration,
+    transfer_timeout: Duration,
+    max_pipelined_queries: usize,
+    connections: ConnectionTable,
+    shutdown: Shutdown,
+    transport: Protocol,
+}
+
+impl TcpOptions {
+    pub fn new(conf: &TcpConfig, shutdown: Shutdown) -> Self {
+        TcpOptions {
+            idle_timeout: Duration::from_secs(conf.idle_timeout),
+            send_timeout: Duration::from_secs(conf.send_timeout),
+            transfer_timeout: Duration::from_secs(conf.transfer_timeout),
+            max_pipelined_queries: conf.max_pipelined_queries.max(1),
+            connections: ConnectionTable::new(conf.max_connections),
+            shutdown,
+            transport: Protocol::Tcp,
+        }
+    }
+
+    //connections table is still shared with the original options
+    pub fn with_transport(mut self, transport: Protocol) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn register_connection(&self) -> Option<(ConnectionHandle, oneshot::Receiver<()>)> {
+        self.connections.register()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+}
 
 pub struct TcpServer<H> {
     handler: H,
+    options: TcpOptions,
 }
 
 impl<H: Handler + Send + Sync> TcpServer<H> {
-    pub fn new(handler: H) -> Self {
-        TcpServer { handler }
+    pub fn new(handler: H, options: TcpOptions) -> Self {
+        TcpServer { handler, options }
     }
 
-    pub async fn run(self, addr: SocketAddr) {
-        let mut listener = TcpListener::bind(&addr).await.unwrap();
+    pub async fn run(self, mut listener: TcpListener) {
+        let mut shutdown = self.options.shutdown();
         loop {
-            let (stream, src) = listener.accept().await.unwrap();
-            let handler = self.handler.clone();
-            let mut stream = Framed::new(stream, TcpStreamCoder::new());
-            tokio::spawn(async move {
-                while let Ok(Some(Ok(request))) = timeout(DEFAULT_RECV_TIMEOUT, stream.next()).await
-                {
-                    let query = Request::new(request, src);
-                    if let Ok(response) = handler.clone().resolve(query).await {
-                        //TODO, add send timeout and error check
-                        stream.send(response.response).await;
+            let conn = tokio::select! {
+                conn = listener.accept() => conn,
+                _ = &mut shutdown => break,
+            };
+            let (stream, src) = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("tcp accept failed: {}", e);
+                    continue;
+                }
+            };
+            let local = match stream.local_addr() {
+                Ok(local) => local,
+                Err(_) => continue,
+            };
+            if let Some((conn, evicted)) = self.options.register_connection() {
+                tokio::spawn(serve_connection(
+                    self.handler.clone(),
+                    stream,
+                    src,
+                    local,
+                    self.options.clone(),
+                    conn,
+                    evicted,
+                ));
+            }
+        }
+    }
+}
+
+//shared by plain tcp and tls, stream is any connection which carries
+//length prefixed dns messages, queries are resolved concurrently and
+//responses are sent in the order they are ready
+pub async fn serve_connection<H, S>(
+    handler: H,
+    stream: S,
+    src: SocketAddr,
+    local: SocketAddr,
+    options: TcpOptions,
+    conn: ConnectionHandle,
+    mut evicted: oneshot::Receiver<()>,
+) where
+    H: Handler,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let keepalive = (options.idle_timeout.as_millis() / 100).min(u16::max_value() as u128) as u16;
+    let (mut sink, mut frames) =
+        Framed::new(stream, TcpStreamCoder::with_keepalive(keepalive)).split();
+    let mut pending = FuturesUnordered::new();
+    let mut reading = true;
+    let mut shutdown = options.shutdown();
+    let transport = options.transport;
+    while reading || !pending.is_empty() {
+        tokio::select! {
+            frame = frames.next(), if reading && pending.len() < options.max_pipelined_queries => {
+                match frame {
+                    Some(Ok(frame)) => {
+                        let mut handler = handler.clone();
+                        pending.push(async move {
+                            let time = SystemTime::now();
+                            let response =
+                                resolve_frame(&mut handler, frame, src, local, transport).await;
+                            (response, time)
+                        });
+                        conn.touch(pending.len());
+                    }
+                    //client may half close the connection after sending queries
+                    _ => reading = false,
+                }
+            }
+            Some((response, time)) = pending.next(), if !pending.is_empty() => {
+                if let Some(response) = response {
+                    log_query(&response, src, transport, time, false);
+                    let send_timeout = if is_transfer(&response) {
+                        options.transfer_timeout
+                    } else {
+                        options.send_timeout
+                    };
+                    match timeout(send_timeout, send_response(&mut sink, response)).await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => {
+                            debug!("send response to {} failed: {}", src, e);
+                            break;
+                        }
+                        Err(_) => {
+                            debug!("send response to {} timeout", src);
+                            break;
+                        }
                     }
                 }
-            });
+                conn.touch(pending.len());
+            }
+            _ = delay_for(options.idle_timeout), if pending.is_empty() => break,
+            _ = &mut evicted => break,
+            //stop reading but finish queries already received
+            _ = &mut shutdown, if reading => reading = false,
         }
     }
 }
+
+fn is_transfer(response: &Response) -> bool {
+    response
+        .response
+        .question
+        .as_ref()
+        .map_or(false, |question| {
+            question.typ == RRType::AXFR || question.typ == RRType::IXFR
+        })
+}
+
+//messages of zone transfer are rendered and sent one at a time
+async fn send_response<S>(sink: &mut S, mut response: Response) -> io::Result<()>
+where
+    S: Sink<Response, Error = io::Error> + Unpin,
+{
+    let extra_messages = mem::replace(&mut response.extra_messages, Box::new(iter::empty()));
+    let tap = response.tap.clone();
+    sink.send(response).await?;
+    for message in extra_messages {
+        let mut response = Response::new(message);
+        response.tap = tap.clone();
+        sink.send(response).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TcpConfig;
+    use crate::types::Request;
+    use futures::FutureExt;
+    use r53::{Message, MessageBuilder, MessageRender, Name};
+    use std::{future::Future, pin::Pin};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const EXTRA_MESSAGE_COUNT: u16 = 3;
+
+    //zone transfer is answered with the response repeated, message ids
+    //tell their order
+    #[derive(Clone)]
+    struct TransferHandler;
+
+    impl Handler for TransferHandler {
+        fn resolve(
+            &mut self,
+            req: Request,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Response>> + Send + '_>> {
+            let mut response = req.request;
+            MessageBuilder::new(&mut response).make_response().done();
+            let mut result = Response::new(response.clone());
+            if is_transfer(&result) {
+                let messages = (1..=EXTRA_MESSAGE_COUNT).map(move |id| {
+                    let mut message = response.clone();
+                    MessageBuilder::new(&mut message).id(id).done();
+                    message
+                });
+                result = result.with_extra_messages(Box::new(messages));
+            }
+            Box::pin(async move { Ok(result) })
+        }
+    }
+
+    async fn send_query(stream: &mut TcpStream, typ: RRType) {
+        let mut request = Message::with_query(Name::new("example.org.").unwrap(), typ);
+        MessageBuilder::new(&mut request).id(0).done();
+        let mut render = MessageRender::new();
+        request.to_wire(&mut render).unwrap();
+        let data = render.take_data();
+        stream.write_u16(data.len() as u16).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+    }
+
+    async fn recv_response(stream: &mut TcpStream) -> Message {
+        let len = stream.read_u16().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        Message::from_wire(&buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let options = TcpOptions::new(
+            &TcpConfig::default(),
+            futures::future::pending().boxed().shared(),
+        );
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(TcpServer::new(TransferHandler, options).run(listener));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send_query(&mut stream, RRType::AXFR).await;
+        for id in 0..=EXTRA_MESSAGE_COUNT {
+            let response = recv_response(&mut stream).await;
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.question.as_ref().unwrap().typ, RRType::AXFR);
+        }
+
+        //connection is still usable after transfer
+        send_query(&mut stream, RRType::A).await;
+        let response = recv_response(&mut stream).await;
+        assert_eq!(response.header.id, 0);
+        assert_eq!(response.question.as_ref().unwrap().typ, RRType::A);
+    }
+}
@@ -1,3 +1,6 @@
+use super::edns_option::{append_edns_option, find_edns_option, EDNS_NSID, EDNS_TCP_KEEPALIVE};
+use super::frame::QueryFrame;
+use crate::types::Response;
 use bytes::{Buf, BufMut, BytesMut};
 use r53::{Message, MessageRender};
 use std::io::{self, Cursor};
@@ -6,24 +9,38 @@ use tokio_util::codec::{Decoder, Encoder};
 pub struct TcpStreamCoder {
     render: MessageRender,
     message_len: Option<u16>,
+    keepalive: Option<u16>,
 }
 
 impl TcpStreamCoder {
-    pub fn new() -> Self {
+    //idle timeout in units of 100 milliseconds, sent to client with
+    //edns-tcp-keepalive option in responses which has edns
+    pub fn with_keepalive(keepalive: u16) -> Self {
         TcpStreamCoder {
             render: MessageRender::new(),
             message_len: None,
+            keepalive: Some(keepalive),
         }
     }
 }
 
 impl Encoder for TcpStreamCoder {
-    type Item = Message;
+    type Item = Response;
     type Error = io::Error;
 
-    fn encode(&mut self, message: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode(&mut self, response: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let message = response.response;
         message.to_wire(&mut self.render);
-        let buffer = self.render.take_data();
+        let mut buffer = self.render.take_data();
+        if let (Some(keepalive), Some(_)) = (self.keepalive, message.edns.as_ref()) {
+            append_edns_option(&mut buffer, EDNS_TCP_KEEPALIVE, &keepalive.to_be_bytes());
+        }
+        if let Some(ref nsid) = response.nsid {
+            append_edns_option(&mut buffer, EDNS_NSID, nsid);
+        }
+        if let Some(ref tap) = response.tap {
+            tap.log_response(&buffer);
+        }
         dst.put_u16(buffer.len() as u16);
         dst.extend(buffer);
         self.render.clear();
@@ -32,7 +49,7 @@ impl Encoder for TcpStreamCoder {
 }
 
 impl Decoder for TcpStreamCoder {
-    type Item = Message;
+    type Item = QueryFrame;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
@@ -49,9 +66,13 @@ impl Decoder for TcpStreamCoder {
             return Ok(None);
         }
         self.message_len = None;
-        match Message::from_wire(&src.as_ref()[0..(message_len as usize)]) {
-            Ok(message) => Ok(Some(message)),
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+        let buf = src.split_to(message_len as usize);
+        //client must not send keepalive with timeout
+        let has_timeout = find_edns_option(buf.as_ref(), EDNS_TCP_KEEPALIVE)
+            .map_or(false, |timeout| !timeout.is_empty());
+        match Message::from_wire(buf.as_ref()) {
+            Ok(request) if !has_timeout => Ok(Some(QueryFrame::query(request, buf.as_ref()))),
+            _ => Ok(Some(QueryFrame::Malformed(buf.to_vec()))),
         }
     }
 }
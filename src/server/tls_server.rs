@@ -0,0 +1,191 @@
+use std::{fs::File, io::BufReader, sync::Arc, time::Duration};
+
+use super::tcp_server::{serve_connection, TcpOptions};
+use crate::config::Protocol;
+use crate::types::Handler;
+use anyhow::{anyhow, bail, Context};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        NoClientAuth, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3); //3 secs
+const DOT_ALPN_PROTOCOL: &[u8] = b"dot";
+
+pub fn load_tls_config(
+    cert_path: &str,
+    key_path: &str,
+    alpn_protocols: &[&[u8]],
+) -> anyhow::Result<Arc<ServerConfig>> {
+    let cert_file = File::open(cert_path)
+        .with_context(|| format!("open certificate file {} failed", cert_path))?;
+    let certs = certs(&mut BufReader::new(cert_file))
+        .map_err(|_| anyhow!("invalid certificate file {}", cert_path))?;
+    if certs.is_empty() {
+        bail!("no certificate found in {}", cert_path);
+    }
+
+    let key_file =
+        File::open(key_path).with_context(|| format!("open key file {} failed", key_path))?;
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(key_file))
+        .map_err(|_| anyhow!("invalid private key file {}", key_path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| anyhow!("invalid private key file {}", key_path))?;
+    }
+    let key = keys
+        .pop()
+        .ok_or_else(|| anyhow!("no private key found in {}", key_path))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)?;
+    config.set_protocols(
+        &alpn_protocols
+            .iter()
+            .map(|p| p.to_vec())
+            .collect::<Vec<Vec<u8>>>(),
+    );
+    Ok(Arc::new(config))
+}
+
+pub struct TlsServer<H> {
+    handler: H,
+    acceptor: TlsAcceptor,
+    options: TcpOptions,
+}
+
+impl<H: Handler + Send + Sync> TlsServer<H> {
+    pub fn new(
+        handler: H,
+        cert_path: &str,
+        key_path: &str,
+        options: TcpOptions,
+    ) -> anyhow::Result<Self> {
+        let config = load_tls_config(cert_path, key_path, &[DOT_ALPN_PROTOCOL])?;
+        Ok(TlsServer {
+            handler,
+            acceptor: TlsAcceptor::from(config),
+            options: options.with_transport(Protocol::Dot),
+        })
+    }
+
+    pub async fn run(self, mut listener: TcpListener) {
+        let mut shutdown = self.options.shutdown();
+        loop {
+            let conn = tokio::select! {
+                conn = listener.accept() => conn,
+                _ = &mut shutdown => break,
+            };
+            let (stream, src) = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("tls accept failed: {}", e);
+                    continue;
+                }
+            };
+            let local = match stream.local_addr() {
+                Ok(local) => local,
+                Err(_) => continue,
+            };
+            let (conn, evicted) = match self.options.register_connection() {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let handler = self.handler.clone();
+            let acceptor = self.acceptor.clone();
+            let options = self.options.clone();
+            tokio::spawn(async move {
+                match timeout(DEFAULT_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        serve_connection(handler, stream, src, local, options, conn, evicted).await
+                    }
+                    Ok(Err(e)) => debug!("tls handshake with {} failed: {}", src, e),
+                    Err(_) => debug!("tls handshake with {} timeout", src),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TcpConfig;
+    use crate::types::{Request, Response};
+    use futures::FutureExt;
+    use r53::{HeaderFlag, Message, MessageBuilder, MessageRender, Name, RRType};
+    use std::{future::Future, net::SocketAddr, pin::Pin};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+
+    const CERT_PATH: &str = "testdata/localhost.crt";
+    const KEY_PATH: &str = "testdata/localhost.key";
+
+    #[derive(Clone)]
+    struct EchoHandler;
+
+    impl Handler for EchoHandler {
+        fn resolve(
+            &mut self,
+            req: Request,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Response>> + Send + '_>> {
+            let mut response = req.request;
+            MessageBuilder::new(&mut response).make_response().done();
+            Box::pin(async move { Ok(Response::new(response)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_over_tls() {
+        let options = TcpOptions::new(
+            &TcpConfig::default(),
+            futures::future::pending().boxed().shared(),
+        );
+        let server = TlsServer::new(EchoHandler, CERT_PATH, KEY_PATH, options).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.run(listener));
+
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut BufReader::new(File::open(CERT_PATH).unwrap()))
+            .unwrap();
+        config.set_protocols(&[DOT_ALPN_PROTOCOL.to_vec()]);
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = connector.connect(domain, stream).await.unwrap();
+
+        let mut request = Message::with_query(Name::new("example.org.").unwrap(), RRType::A);
+        MessageBuilder::new(&mut request).id(1234).done();
+        let mut render = MessageRender::new();
+        request.to_wire(&mut render).unwrap();
+        let data = render.take_data();
+        stream.write_u16(data.len() as u16).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+
+        let len = stream.read_u16().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        let response = Message::from_wire(&buf).unwrap();
+        assert_eq!(response.header.id, 1234);
+        assert!(response.header.is_flag_set(HeaderFlag::QueryRespone));
+        assert_eq!(response.question, request.question);
+    }
+
+    #[test]
+    fn test_load_tls_config() {
+        assert!(load_tls_config(CERT_PATH, KEY_PATH, &[DOT_ALPN_PROTOCOL]).is_ok());
+        assert!(load_tls_config(KEY_PATH, KEY_PATH, &[DOT_ALPN_PROTOCOL]).is_err());
+        assert!(load_tls_config(CERT_PATH, CERT_PATH, &[DOT_ALPN_PROTOCOL]).is_err());
+    }
+}
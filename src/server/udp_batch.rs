@@ -0,0 +1,220 @@
+use futures::future::poll_fn;
+use futures::ready;
+use mio::Ready;
+use socket2::SockAddr;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::task::Poll;
+use tokio::io::PollEvented;
+
+//large enough for any udp message
+const RECV_BUFFER_LEN: usize = 65535;
+
+//udp socket which receives and sends several packets with one syscall
+pub struct BatchUdpSocket {
+    io: PollEvented<mio::net::UdpSocket>,
+    batch_size: usize,
+}
+
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    //buffer index, packet length and source address
+    received: Vec<(usize, usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> Self {
+        RecvBatch {
+            bufs: vec![vec![0; RECV_BUFFER_LEN]; batch_size],
+            received: Vec::with_capacity(batch_size),
+        }
+    }
+
+    pub fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .map(move |&(index, len, src)| (&self.bufs[index][..len], src))
+    }
+}
+
+impl BatchUdpSocket {
+    pub fn new(socket: std::net::UdpSocket, batch_size: usize) -> io::Result<Self> {
+        let socket = mio::net::UdpSocket::from_socket(socket)?;
+        Ok(BatchUdpSocket {
+            io: PollEvented::new(socket)?,
+            batch_size: batch_size.max(1),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        poll_fn(|cx| {
+            ready!(self.io.poll_read_ready(cx, Ready::readable()))?;
+            match recv_mmsg(self.io.get_ref().as_raw_fd(), batch) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.io.clear_read_ready(cx, Ready::readable())?;
+                    Poll::Pending
+                }
+                result => Poll::Ready(result),
+            }
+        })
+        .await
+    }
+
+    //return count of packets sent, which may be less than packets in
+    //the batch, error means the first packet isn't sent
+    pub async fn send_batch<T: AsRef<[u8]>>(
+        &self,
+        packets: &[(T, SocketAddr)],
+    ) -> io::Result<usize> {
+        poll_fn(|cx| {
+            ready!(self.io.poll_write_ready(cx))?;
+            match send_mmsg(self.io.get_ref().as_raw_fd(), packets) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.io.clear_write_ready(cx)?;
+                    Poll::Pending
+                }
+                result => Poll::Ready(result),
+            }
+        })
+        .await
+    }
+}
+
+fn recv_mmsg(fd: RawFd, batch: &mut RecvBatch) -> io::Result<usize> {
+    let count = batch.bufs.len();
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+    let mut iovecs: Vec<libc::iovec> = batch
+        .bufs
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iovec, addr)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    batch.received.clear();
+    for (index, msg) in msgs.iter().take(received as usize).enumerate() {
+        let src = unsafe {
+            SockAddr::from_raw_parts(
+                &addrs[index] as *const libc::sockaddr_storage as *const libc::sockaddr,
+                msg.msg_hdr.msg_namelen,
+            )
+        };
+        if let Some(src) = src.as_std() {
+            batch.received.push((index, msg.msg_len as usize, src));
+        }
+    }
+    Ok(batch.received.len())
+}
+
+fn send_mmsg<T: AsRef<[u8]>>(fd: RawFd, packets: &[(T, SocketAddr)]) -> io::Result<usize> {
+    let addrs: Vec<SockAddr> = packets
+        .iter()
+        .map(|(_, dst)| SockAddr::from(*dst))
+        .collect();
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|(data, _)| libc::iovec {
+            iov_base: data.as_ref().as_ptr() as *mut libc::c_void,
+            iov_len: data.as_ref().len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter())
+        .map(|(iovec, dst)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = dst.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = dst.len();
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batch_send_and_recv() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let server = BatchUdpSocket::new(server, 4).unwrap();
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let client = BatchUdpSocket::new(client, 4).unwrap();
+
+        let packets: Vec<(Vec<u8>, SocketAddr)> = (0..6u8)
+            .map(|i| (vec![i; i as usize + 1], server_addr))
+            .collect();
+        let mut sent = 0;
+        while sent < packets.len() {
+            sent += client.send_batch(&packets[sent..]).await.unwrap();
+        }
+
+        let mut batch = RecvBatch::new(4);
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            server.recv_batch(&mut batch).await.unwrap();
+            assert!(batch.packets().count() <= 4);
+            for (data, src) in batch.packets() {
+                assert_eq!(src, client_addr);
+                received.push(data.to_vec());
+            }
+        }
+        let expected: Vec<Vec<u8>> = packets.into_iter().map(|(data, _)| data).collect();
+        assert_eq!(received, expected);
+    }
+}
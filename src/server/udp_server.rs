@@ -1,13 +1,28 @@
+use super::frame::{log_query, resolve_frame, QueryFrame};
+use super::query_limiter::QueryLimiter;
+use super::rrl::{truncated_response, ResponseRateLimiter, RrlAction};
+use super::server::Shutdown;
+#[cfg(target_os = "linux")]
+use super::udp_batch::{BatchUdpSocket, RecvBatch};
 use super::udp_stream_coder::UdpStreamCoder;
-use crate::types::{Handler, Request};
-use futures::channel::mpsc::channel;
-use futures::{SinkExt, StreamExt};
+use crate::config::{OverloadPolicy, Protocol};
+use crate::types::{error_response, Handler, Response};
+#[cfg(target_os = "linux")]
+use bytes::BytesMut;
+use futures::channel::mpsc::{channel, Sender};
+use futures::{FutureExt, SinkExt, StreamExt};
 use prometheus::{IntCounter, IntGauge};
-use r53::Message;
+use r53::{HeaderFlag, Message, Rcode};
+use std::io;
 use std::net::SocketAddr;
-use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
 use tokio::time;
+#[cfg(target_os = "linux")]
+use tokio_util::codec::{Decoder, Encoder};
 use tokio_util::udp::UdpFramed;
 
 lazy_static! {
@@ -23,53 +38,225 @@ lazy_static! {
         register_int_counter!("rc", "response count until now").unwrap();
     static ref CHC_UDP_INT_COUNT: IntCounter =
         register_int_counter!("chc", "cache hit count").unwrap();
+    static ref OVERLOAD_DROP_UDP_INT_COUNT: IntCounter =
+        register_int_counter!("overload_drop", "query dropped when overloaded").unwrap();
+    static ref OVERLOAD_SERVFAIL_UDP_INT_COUNT: IntCounter = register_int_counter!(
+        "overload_servfail",
+        "query answered with servfail when overloaded"
+    )
+    .unwrap();
+    static ref SEND_DROP_UDP_INT_COUNT: IntCounter =
+        register_int_counter!("send_drop", "response dropped since send queue is full").unwrap();
 }
 
 const QUERY_BUFFER_LEN: usize = 1024;
 
 pub struct UdpServer<H: Handler> {
     handler: H,
+    limiter: QueryLimiter,
+    overload_policy: OverloadPolicy,
+    rrl: Option<ResponseRateLimiter>,
 }
 
 impl<H: Handler> UdpServer<H> {
-    pub fn new(handler: H) -> Self {
-        UdpServer { handler }
+    pub fn new(
+        handler: H,
+        limiter: QueryLimiter,
+        overload_policy: OverloadPolicy,
+        rrl: Option<ResponseRateLimiter>,
+    ) -> Self {
+        UdpServer {
+            handler,
+            limiter,
+            overload_policy,
+            rrl,
+        }
+    }
+
+    pub fn spawn(
+        self,
+        socket: std::net::UdpSocket,
+        batch_size: usize,
+        shutdown: Shutdown,
+    ) -> io::Result<JoinHandle<()>> {
+        #[cfg(target_os = "linux")]
+        {
+            if batch_size > 1 {
+                let socket = BatchUdpSocket::new(socket, batch_size)?;
+                return Ok(tokio::spawn(self.run_batched(socket, shutdown)));
+            }
+        }
+        let socket = UdpSocket::from_std(socket)?;
+        Ok(tokio::spawn(self.run(socket, shutdown)))
     }
 
-    pub async fn run(&mut self, addr: SocketAddr) {
-        let socket = UdpSocket::bind(addr).await.unwrap();
+    //for socket bound to wildcard address, server address of request
+    //is the wildcard address
+    async fn run(self, socket: UdpSocket, mut shutdown: Shutdown) {
+        let local = socket.local_addr().unwrap();
         let (mut send_stream, mut recv_stream) =
             UdpFramed::new(socket, UdpStreamCoder::new()).split();
-        let (sender, mut receiver) = channel::<(Message, SocketAddr)>(QUERY_BUFFER_LEN);
+        let (sender, mut receiver) = channel::<(Response, SocketAddr)>(QUERY_BUFFER_LEN);
+        //quit after all the senders held by queries in process are dropped
         tokio::spawn(async move {
-            loop {
-                let response = receiver.next().await.unwrap();
-                send_stream.send(response).await.unwrap();
+            while let Some((response, dst)) = receiver.next().await {
+                if let Err(e) = send_stream.send((response, dst)).await {
+                    debug!("send response to {} failed: {}", dst, e);
+                }
             }
         });
-        tokio::spawn(calculate_qps());
 
         loop {
-            if let Some(Ok((request, src))) = recv_stream.next().await {
-                QC_UDP_INT_COUNT.inc();
-                let mut sender_back = sender.clone();
-                let mut handler = self.handler.clone();
-                tokio::spawn(async move {
-                    let query = Request::new(request, src);
-                    if let Ok(response) = handler.resolve(query).await {
-                        RC_UDP_INT_COUNT.inc();
-                        if response.cache_hit {
-                            CHC_UDP_INT_COUNT.inc();
+            let frame = tokio::select! {
+                frame = recv_stream.next() => frame,
+                _ = &mut shutdown => break,
+            };
+            if let Some(Ok((frame, src))) = frame {
+                self.handle_frame(frame, src, local, &sender);
+            }
+        }
+    }
+
+    //responses already queued are sent together with the first one
+    #[cfg(target_os = "linux")]
+    async fn run_batched(self, socket: BatchUdpSocket, mut shutdown: Shutdown) {
+        let socket = Arc::new(socket);
+        let local = socket.local_addr().unwrap();
+        let batch_size = socket.batch_size();
+        let (sender, mut receiver) = channel::<(Response, SocketAddr)>(QUERY_BUFFER_LEN);
+        let send_socket = socket.clone();
+        tokio::spawn(async move {
+            let mut coder = UdpStreamCoder::new();
+            let mut packets = Vec::with_capacity(batch_size);
+            while let Some(queued) = receiver.next().await {
+                let mut queued = Some(queued);
+                while let Some((response, dst)) = queued {
+                    let mut buf = BytesMut::new();
+                    if coder.encode(response, &mut buf).is_ok() {
+                        packets.push((buf, dst));
+                    }
+                    queued = if packets.len() < batch_size {
+                        receiver.next().now_or_never().flatten()
+                    } else {
+                        None
+                    };
+                }
+
+                let mut sent = 0;
+                while sent < packets.len() {
+                    match send_socket.send_batch(&packets[sent..]).await {
+                        Ok(count) => sent += count,
+                        Err(e) => {
+                            debug!("send response to {} failed: {}", packets[sent].1, e);
+                            sent += 1;
                         }
-                        sender_back.try_send((response.response, src)).unwrap();
                     }
-                });
+                }
+                packets.clear();
+            }
+        });
+
+        let mut coder = UdpStreamCoder::new();
+        let mut batch = RecvBatch::new(batch_size);
+        loop {
+            let received = tokio::select! {
+                received = socket.recv_batch(&mut batch) => received,
+                _ = &mut shutdown => break,
+            };
+            if let Err(e) = received {
+                debug!("receive query on {} failed: {}", local, e);
+                continue;
+            }
+            for (packet, src) in batch.packets() {
+                if let Ok(Some(frame)) = coder.decode(&mut BytesMut::from(packet)) {
+                    self.handle_frame(frame, src, local, &sender);
+                }
             }
         }
     }
+
+    fn handle_frame(
+        &self,
+        frame: QueryFrame,
+        src: SocketAddr,
+        local: SocketAddr,
+        sender: &Sender<(Response, SocketAddr)>,
+    ) {
+        QC_UDP_INT_COUNT.inc();
+        let time = SystemTime::now();
+        let mut sender_back = sender.clone();
+        let permit = match self.limiter.acquire(src.ip()) {
+            Ok(permit) => permit,
+            Err(_) => {
+                let tap = frame.tap(src, local, Protocol::Udp);
+                if let Some(response) = self.overload_response(frame) {
+                    let mut response = Response::new(response);
+                    response.tap = tap;
+                    send_response(&self.rrl, &mut sender_back, response, src, time);
+                }
+                return;
+            }
+        };
+
+        let mut handler = self.handler.clone();
+        let rrl = self.rrl.clone();
+        tokio::spawn(async move {
+            if let Some(response) =
+                resolve_frame(&mut handler, frame, src, local, Protocol::Udp).await
+            {
+                RC_UDP_INT_COUNT.inc();
+                if response.cache_hit {
+                    CHC_UDP_INT_COUNT.inc();
+                }
+                send_response(&rrl, &mut sender_back, response, src, time);
+            }
+            drop(permit);
+        });
+    }
+
+    fn overload_response(&self, frame: QueryFrame) -> Option<Message> {
+        match (self.overload_policy, frame) {
+            (OverloadPolicy::ServFail, QueryFrame::Query { request, .. })
+                if !request.header.is_flag_set(HeaderFlag::QueryRespone) =>
+            {
+                OVERLOAD_SERVFAIL_UDP_INT_COUNT.inc();
+                Some(error_response(&request, Rcode::ServFail))
+            }
+            _ => {
+                OVERLOAD_DROP_UDP_INT_COUNT.inc();
+                None
+            }
+        }
+    }
+}
+
+//rate limiting is applied to every response, including the ones made
+//by server itself when it's overloaded
+fn send_response(
+    rrl: &Option<ResponseRateLimiter>,
+    sender: &mut Sender<(Response, SocketAddr)>,
+    mut response: Response,
+    src: SocketAddr,
+    time: SystemTime,
+) {
+    let action = rrl
+        .as_ref()
+        .map_or(RrlAction::Send, |rrl| rrl.check(src.ip(), &response));
+    match action {
+        RrlAction::Send => {}
+        RrlAction::Slip => response.response = truncated_response(response.response),
+        RrlAction::Drop => {
+            log_query(&response, src, Protocol::Udp, time, true);
+            return;
+        }
+    }
+    log_query(&response, src, Protocol::Udp, time, false);
+    if sender.try_send((response, src)).is_err() {
+        SEND_DROP_UDP_INT_COUNT.inc();
+    }
 }
 
-async fn calculate_qps() {
+pub async fn calculate_qps() {
     let mut interval = time::interval(Duration::from_secs(1));
     let mut last_qc = 0;
     let mut last_chc = 0;
@@ -1,8 +1,17 @@
+use super::edns_option::{append_edns_option, find_edns_option, EDNS_NSID, EDNS_TCP_KEEPALIVE};
+use super::frame::QueryFrame;
+use crate::types::Response;
 use bytes::BytesMut;
-use r53::{Message, MessageRender};
+use r53::{HeaderFlag, Message, MessageBuilder, MessageRender, SectionType};
 use std::io;
 use tokio_util::codec::{Decoder, Encoder};
 
+//payload size every dns client should accept, and the upper bound
+//of edns buffer size we trust to avoid ip fragmentation
+const MIN_UDP_PAYLOAD_SIZE: usize = 512;
+const MAX_UDP_PAYLOAD_SIZE: usize = 4096;
+const EDNS_OPTION_HEADER_LEN: usize = 4;
+
 pub struct UdpStreamCoder {
     render: MessageRender,
 }
@@ -13,28 +22,195 @@ impl UdpStreamCoder {
             render: MessageRender::new(),
         }
     }
+
+    //drop additional first since it's optional, then authority, which
+    //is only required by negative answer and referral. tc bit is set
+    //once required data is dropped as rfc2181 section 9 says, so client
+    //will retry with tcp. reserved is the space for edns options
+    //appended after rendering
+    fn render_with_limit(&mut self, mut message: Message, reserved: usize) {
+        let max_len = max_payload_size(&message).saturating_sub(reserved);
+        let is_positive = message
+            .section(SectionType::Answer)
+            .map_or(false, |answers| !answers.is_empty());
+        message.to_wire(&mut self.render);
+        for section in &[
+            SectionType::Additional,
+            SectionType::Authority,
+            SectionType::Answer,
+        ] {
+            if self.render.data().len() <= max_len {
+                break;
+            }
+
+            self.render.clear();
+            message.take_section(*section);
+            let is_required = match *section {
+                SectionType::Additional => false,
+                SectionType::Authority => !is_positive,
+                SectionType::Answer => true,
+            };
+            if is_required {
+                MessageBuilder::new(&mut message)
+                    .set_flag(HeaderFlag::Truncation)
+                    .done();
+            }
+            message.recalculate_header();
+            message.to_wire(&mut self.render);
+        }
+    }
+}
+
+fn max_payload_size(message: &Message) -> usize {
+    message.edns.as_ref().map_or(MIN_UDP_PAYLOAD_SIZE, |edns| {
+        (edns.udp_size as usize)
+            .max(MIN_UDP_PAYLOAD_SIZE)
+            .min(MAX_UDP_PAYLOAD_SIZE)
+    })
 }
 
 impl Encoder for UdpStreamCoder {
-    type Item = Message;
+    type Item = Response;
     type Error = io::Error;
 
-    fn encode(&mut self, message: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        message.to_wire(&mut self.render);
-        dst.extend(self.render.data());
+    fn encode(&mut self, response: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let tap = response.tap;
+        let has_edns = response.response.edns.is_some();
+        let nsid = response.nsid.filter(|_| has_edns);
+        let reserved = nsid
+            .as_ref()
+            .map_or(0, |nsid| EDNS_OPTION_HEADER_LEN + nsid.len());
+        self.render_with_limit(response.response, reserved);
+        let mut buffer = self.render.take_data();
+        if let Some(ref nsid) = nsid {
+            append_edns_option(&mut buffer, EDNS_NSID, nsid);
+        }
+        if let Some(ref tap) = tap {
+            tap.log_response(&buffer);
+        }
+        dst.extend(buffer);
         self.render.clear();
         Ok(())
     }
 }
 
 impl Decoder for UdpStreamCoder {
-    type Item = Message;
+    type Item = QueryFrame;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        //keepalive is only meaningful for tcp
+        if find_edns_option(src.as_ref(), EDNS_TCP_KEEPALIVE).is_some() {
+            return Ok(Some(QueryFrame::Malformed(src.to_vec())));
+        }
         match Message::from_wire(src.as_ref()) {
-            Ok(message) => Ok(Some(message)),
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+            Ok(request) => Ok(Some(QueryFrame::query(request, src.as_ref()))),
+            Err(_) => Ok(Some(QueryFrame::Malformed(src.to_vec()))),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r53::{build_response, RRType};
+
+    fn build_large_response(udp_size: Option<usize>) -> Message {
+        let answers: Vec<String> = (0..60)
+            .map(|i| format!("example.org. 300 IN A 192.0.2.{}", i))
+            .collect();
+        build_response(
+            "example.org.",
+            RRType::A,
+            vec![answers.iter().map(|s| s.as_ref()).collect()],
+            vec![vec!["example.org. 300 IN NS ns.example.org."]],
+            vec![vec!["ns.example.org. 300 IN A 192.0.2.200"]],
+            udp_size,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_truncate_oversized_response() {
+        let mut coder = UdpStreamCoder::new();
+        let mut buf = BytesMut::new();
+        coder
+            .encode(Response::new(build_large_response(None)), &mut buf)
+            .unwrap();
+        assert!(buf.len() <= MIN_UDP_PAYLOAD_SIZE);
+        let response = Message::from_wire(buf.as_ref()).unwrap();
+        assert!(response.header.is_flag_set(HeaderFlag::Truncation));
+        assert_eq!(response.header.an_count, 0);
+        assert_eq!(response.header.ns_count, 0);
+        assert_eq!(response.header.ar_count, 0);
+    }
+
+    fn build_large_authority_response(answer: Option<&str>) -> Message {
+        let authority: Vec<String> = (0..40)
+            .map(|i| format!("example.org. 300 IN NS ns{}.nameservers.example.org.", i))
+            .collect();
+        build_response(
+            "example.org.",
+            RRType::A,
+            answer.into_iter().map(|answer| vec![answer]).collect(),
+            vec![authority.iter().map(|s| s.as_ref()).collect()],
+            Vec::new(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_drop_authority() {
+        //authority of positive answer is optional
+        let mut coder = UdpStreamCoder::new();
+        let mut buf = BytesMut::new();
+        let response = build_large_authority_response(Some("example.org. 300 IN A 192.0.2.1"));
+        coder.encode(Response::new(response), &mut buf).unwrap();
+        let response = Message::from_wire(buf.as_ref()).unwrap();
+        assert!(!response.header.is_flag_set(HeaderFlag::Truncation));
+        assert_eq!(response.header.an_count, 1);
+        assert_eq!(response.header.ns_count, 0);
+
+        //referral can't be answered without authority
+        let mut buf = BytesMut::new();
+        let response = build_large_authority_response(None);
+        coder.encode(Response::new(response), &mut buf).unwrap();
+        let response = Message::from_wire(buf.as_ref()).unwrap();
+        assert!(response.header.is_flag_set(HeaderFlag::Truncation));
+        assert_eq!(response.header.ns_count, 0);
+    }
+
+    #[test]
+    fn test_honor_edns_payload_size() {
+        let mut coder = UdpStreamCoder::new();
+        let mut buf = BytesMut::new();
+        coder
+            .encode(Response::new(build_large_response(Some(4096))), &mut buf)
+            .unwrap();
+        assert!(buf.len() > MIN_UDP_PAYLOAD_SIZE);
+        let response = Message::from_wire(buf.as_ref()).unwrap();
+        assert!(!response.header.is_flag_set(HeaderFlag::Truncation));
+        assert_eq!(response.header.an_count, 60);
+    }
+
+    #[test]
+    fn test_append_nsid() {
+        let mut coder = UdpStreamCoder::new();
+        let mut response = Response::new(build_large_response(Some(4096)));
+        response.nsid = Some(b"ns1".to_vec());
+        let mut buf = BytesMut::new();
+        coder.encode(response, &mut buf).unwrap();
+        assert_eq!(
+            find_edns_option(buf.as_ref(), EDNS_NSID),
+            Some(b"ns1".to_vec())
+        );
+
+        //nsid needs edns
+        let mut response = Response::new(build_large_response(None));
+        response.nsid = Some(b"ns1".to_vec());
+        let mut buf = BytesMut::new();
+        coder.encode(response, &mut buf).unwrap();
+        assert_eq!(find_edns_option(buf.as_ref(), EDNS_NSID), None);
+    }
+}
@@ -1,28 +1,76 @@
+use std::fmt;
 use std::future::Future;
+use std::iter;
 use std::net::SocketAddr;
 use std::pin::Pin;
 
-use r53::{question::Question, Message};
+use crate::config::Protocol;
+use crate::dnstap::ClientTap;
+use r53::{question::Question, Message, MessageBuilder, Rcode};
+use serde::Serialize;
 
 #[derive(Debug, Clone)]
 pub struct Request {
     pub client: SocketAddr,
+    pub server: Option<SocketAddr>,
+    pub transport: Protocol,
+    //client asks for name server identifier
+    pub nsid: bool,
     pub request: Message,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServedBy {
+    Auth,
+    Cache,
+    Recursion,
+    Forwarder,
+}
+
+//messages built lazily, so they are rendered one by one when sent
+pub type MessageStream = Box<dyn Iterator<Item = Message> + Send>;
+
 pub struct Response {
     pub cache_hit: bool,
+    pub served_by: Option<ServedBy>,
+    //name server identifier sent back in edns option
+    pub nsid: Option<Vec<u8>>,
     pub response: Message,
+    //zone transfer is answered with several messages, the rest of them
+    //are sent right after response over stream transports
+    pub extra_messages: MessageStream,
+    //set when dnstap logs client messages
+    pub tap: Option<ClientTap>,
 }
 
 impl Request {
     pub fn new(request: Message, client: SocketAddr) -> Self {
         Self {
             client,
+            server: None,
+            transport: Protocol::Udp,
+            nsid: false,
             request: request,
         }
     }
 
+    pub fn with_transport(mut self, transport: Protocol) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn with_nsid(mut self, nsid: bool) -> Self {
+        self.nsid = nsid;
+        self
+    }
+
+    //local address the query is received on
+    pub fn with_server(mut self, server: SocketAddr) -> Self {
+        self.server = Some(server);
+        self
+    }
+
     pub fn question(&self) -> &Question {
         self.request.question.as_ref().unwrap()
     }
@@ -32,9 +80,44 @@ impl Response {
     pub fn new(response: Message) -> Self {
         Self {
             cache_hit: false,
+            served_by: None,
+            nsid: None,
             response: response,
+            extra_messages: Box::new(iter::empty()),
+            tap: None,
         }
     }
+
+    pub fn with_served_by(mut self, served_by: ServedBy) -> Self {
+        self.served_by = Some(served_by);
+        self
+    }
+
+    pub fn with_extra_messages(mut self, messages: MessageStream) -> Self {
+        self.extra_messages = messages;
+        self
+    }
+}
+
+impl fmt::Display for ServedBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ServedBy::Auth => "auth",
+            ServedBy::Cache => "cache",
+            ServedBy::Recursion => "recursion",
+            ServedBy::Forwarder => "forwarder",
+        };
+        f.write_str(name)
+    }
+}
+
+pub fn error_response(request: &Message, rcode: Rcode) -> Message {
+    let mut response = request.clone();
+    MessageBuilder::new(&mut response)
+        .make_response()
+        .rcode(rcode)
+        .done();
+    response
 }
 
 pub trait Handler: Send + Clone + 'static {
@@ -43,3 +126,75 @@ pub trait Handler: Send + Clone + 'static {
         req: Request,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<Response>> + Send + '_>>;
 }
+
+pub type MiddlewareFuture<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<Option<Response>>> + Send + 'a>>;
+
+//one stage of query processing, it either answers the query, or hands
+//it over to the rest of the chain, it may modify the query before and
+//the response after. none means no stage answers the query
+pub trait Middleware<C>: Send + Sync {
+    fn handle<'a>(&'a self, ctx: &'a mut C, next: Next<'a, C>) -> MiddlewareFuture<'a>;
+}
+
+pub struct Next<'a, C> {
+    stages: &'a [Box<dyn Middleware<C>>],
+}
+
+impl<'a, C: Send> Next<'a, C> {
+    pub fn new(stages: &'a [Box<dyn Middleware<C>>]) -> Self {
+        Next { stages }
+    }
+
+    pub fn run(self, ctx: &'a mut C) -> MiddlewareFuture<'a> {
+        match self.stages.split_first() {
+            Some((stage, rest)) => stage.handle(ctx, Next { stages: rest }),
+            None => Box::pin(async { Ok(None) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Record(&'static str);
+
+    impl Middleware<Vec<&'static str>> for Record {
+        fn handle<'a>(
+            &'a self,
+            ctx: &'a mut Vec<&'static str>,
+            next: Next<'a, Vec<&'static str>>,
+        ) -> MiddlewareFuture<'a> {
+            ctx.push(self.0);
+            next.run(ctx)
+        }
+    }
+
+    struct Stop;
+
+    impl Middleware<Vec<&'static str>> for Stop {
+        fn handle<'a>(
+            &'a self,
+            _ctx: &'a mut Vec<&'static str>,
+            _next: Next<'a, Vec<&'static str>>,
+        ) -> MiddlewareFuture<'a> {
+            Box::pin(async { Err(anyhow::anyhow!("stop")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_chain() {
+        let stages: Vec<Box<dyn Middleware<Vec<&'static str>>>> =
+            vec![Box::new(Record("a")), Box::new(Record("b"))];
+        let mut ctx = Vec::new();
+        assert!(Next::new(&stages).run(&mut ctx).await.unwrap().is_none());
+        assert_eq!(ctx, vec!["a", "b"]);
+
+        let stages: Vec<Box<dyn Middleware<Vec<&'static str>>>> =
+            vec![Box::new(Record("a")), Box::new(Stop), Box::new(Record("b"))];
+        let mut ctx = Vec::new();
+        assert!(Next::new(&stages).run(&mut ctx).await.is_err());
+        assert_eq!(ctx, vec!["a"]);
+    }
+}
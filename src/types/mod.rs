@@ -1,4 +1,8 @@
 mod handler;
 mod view;
 
-pub use self::handler::{Handler, Request, Response};
+pub use self::handler::{
+    error_response, Handler, MessageStream, Middleware, MiddlewareFuture, Next, Request, Response,
+    ServedBy,
+};
+pub use self::view::{Acl, View};
@@ -17,11 +17,17 @@ impl FromStr for Address {
         match segs.len() {
             1 => {
                 let ip = IpAddr::from_str(segs[0])?;
-                Ok(Address { ip, mask_len: 32 })
+                Ok(Address {
+                    ip,
+                    mask_len: max_mask_len(ip),
+                })
             }
             2 => {
                 let ip = IpAddr::from_str(segs[0])?;
                 let mask_len = u32::from_str(segs[1])?;
+                if mask_len > max_mask_len(ip) {
+                    bail!("invalid mask length {}", mask_len);
+                }
                 Ok(Address { ip, mask_len })
             }
             _ => {
@@ -31,30 +37,31 @@ impl FromStr for Address {
     }
 }
 
+fn max_mask_len(ip: IpAddr) -> u32 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
 pub struct Acl {
     pub addrs: Vec<Address>,
+    v4_trie: IpLookupTable<Ipv4Addr, ()>,
+    v6_trie: IpLookupTable<Ipv6Addr, ()>,
 }
 
 impl Acl {
     pub fn new(addreses: Vec<&str>) -> Result<Self> {
-        let addrs = addreses
-            .iter()
-            .map(|&s| Address::from_str(s))
-            .collect::<Result<Vec<Address>, _>>()?;
-        Ok(Self { addrs })
+        let mut acl = Self::empty();
+        for s in addreses {
+            acl.add_addr(Address::from_str(s)?);
+        }
+        Ok(acl)
     }
-}
 
-pub struct View {
-    name: String,
-    v4_trie: IpLookupTable<Ipv4Addr, ()>,
-    v6_trie: IpLookupTable<Ipv6Addr, ()>,
-}
-
-impl View {
-    pub fn new(name: String) -> Self {
+    pub fn empty() -> Self {
         Self {
-            name,
+            addrs: Vec::new(),
             v4_trie: IpLookupTable::new(),
             v6_trie: IpLookupTable::new(),
         }
@@ -65,9 +72,10 @@ impl View {
             IpAddr::V4(v4) => self.v4_trie.insert(v4, addr.mask_len, ()),
             IpAddr::V6(v6) => self.v6_trie.insert(v6, addr.mask_len, ()),
         };
+        self.addrs.push(addr);
     }
 
-    pub fn has_addr(&self, addr: IpAddr) -> bool {
+    pub fn contains(&self, addr: IpAddr) -> bool {
         match addr {
             IpAddr::V4(v4) => self.v4_trie.longest_match(v4).is_some(),
             IpAddr::V6(v6) => self.v6_trie.longest_match(v6).is_some(),
@@ -75,6 +83,50 @@ impl View {
     }
 }
 
+pub struct View {
+    name: String,
+    acl: Acl,
+    destinations: Option<Acl>,
+}
+
+impl View {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            acl: Acl::empty(),
+            destinations: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
+    }
+
+    pub fn add_addr(&mut self, addr: Address) {
+        self.acl.add_addr(addr);
+    }
+
+    pub fn has_addr(&self, addr: IpAddr) -> bool {
+        self.acl.contains(addr)
+    }
+
+    pub fn set_destinations(&mut self, destinations: Acl) {
+        self.destinations = Some(destinations);
+    }
+
+    //view without destinations matches queries to any local address
+    pub fn is_match(&self, client: IpAddr, server: Option<IpAddr>) -> bool {
+        if !self.has_addr(client) {
+            return false;
+        }
+        match (&self.destinations, server) {
+            (None, _) => true,
+            (Some(destinations), Some(server)) => destinations.contains(server),
+            (Some(_), None) => false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{Acl, Address, View};
@@ -96,4 +148,34 @@ mod tests {
         assert!(view.has_addr(IpAddr::from_str("2.1.3.1").unwrap()));
         assert!(!view.has_addr(IpAddr::from_str("3.1.3.1").unwrap()));
     }
+
+    #[test]
+    fn test_view_match() {
+        let mut view = View::new("internal".to_string());
+        for addr in Acl::new(vec!["10.0.0.0/8"]).unwrap().addrs {
+            view.add_addr(addr);
+        }
+        let client = IpAddr::from_str("10.0.0.1").unwrap();
+        assert!(view.is_match(client, None));
+        assert!(!view.is_match(IpAddr::from_str("11.0.0.1").unwrap(), None));
+
+        view.set_destinations(Acl::new(vec!["192.168.1.1"]).unwrap());
+        assert!(view.is_match(client, Some(IpAddr::from_str("192.168.1.1").unwrap())));
+        assert!(!view.is_match(client, Some(IpAddr::from_str("192.168.1.2").unwrap())));
+        assert!(!view.is_match(client, None));
+    }
+
+    #[test]
+    fn test_acl_contains() {
+        let acl = Acl::new(vec!["10.0.0.0/8", "2001:db8::/32", "2001:db9::1"]).unwrap();
+        assert!(acl.contains(IpAddr::from_str("10.1.2.3").unwrap()));
+        assert!(!acl.contains(IpAddr::from_str("11.1.2.3").unwrap()));
+        assert!(acl.contains(IpAddr::from_str("2001:db8::53").unwrap()));
+        assert!(acl.contains(IpAddr::from_str("2001:db9::1").unwrap()));
+        assert!(!acl.contains(IpAddr::from_str("2001:db9::2").unwrap()));
+
+        assert_eq!(Address::from_str("2001:db9::1").unwrap().mask_len, 128);
+        assert!(Address::from_str("10.0.0.0/33").is_err());
+        assert!(Acl::new(vec!["10.0.0.0/8", "localhost"]).is_err());
+    }
 }